@@ -0,0 +1,222 @@
+use sink::s3_sink::{S3OutputFormat, S3Sink};
+use sink::elastic_search_sink::ElasticSearchSink;
+use dynamo_db::dynamo_db_library::DynamoDbLibrary;
+use dynamo_db::table_config::TableCapacity;
+use kinesis_stream::kcl_error::KclError;
+use config::env_config::{get_env_var_or, get_parsed_env_var_or};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+use std::thread;
+use std::time;
+
+const BACKFILL_HOUR_FORMAT: &str = "%Y-%m-%dT%H";
+
+/// Re-indexes the S3 archive of a time range into Elasticsearch.
+/// The progress is checkpointed per object, so a stopped backfill resumes where it left off.
+/// Every document gets the id of its object key and line, so the documents of an object that was
+/// indexed again after a stop overwrite themselves instead of being duplicated.
+pub struct BackfillJob {
+    s3_sink: S3Sink,
+    elastic_search_sink: ElasticSearchSink,
+    checkpoints: DynamoDbLibrary,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    throttle_millis: u64,
+}
+
+impl BackfillJob {
+    /// The range is [from, to) in whole hours, formatted as 2018-10-01T13.
+    /// KCL_BACKFILL_CHECKPOINT_TABLE (hash key backfill_id) and KCL_BACKFILL_THROTTLE_MILLIS,
+    /// the pause between two bulk requests.
+    pub fn new(s3_sink: S3Sink, elastic_search_sink: ElasticSearchSink,
+               from: &String, to: &String) -> Option<BackfillJob> {
+        let from_date = BackfillJob::parse_hour(from);
+        let to_date = BackfillJob::parse_hour(to);
+        if from_date.is_none() || to_date.is_none() {
+            eprintln!("The backfill range should be formatted as {}.", BACKFILL_HOUR_FORMAT);
+            return None;
+        }
+
+        if s3_sink.config.output_format != S3OutputFormat::Json {
+            eprintln!("Only the json S3 output format can be backfilled.");
+            return None;
+        }
+
+        let checkpoint_table_name =
+            get_env_var_or("KCL_BACKFILL_CHECKPOINT_TABLE", "kcl_backfill_checkpoints");
+
+        return Some(BackfillJob {
+            s3_sink,
+            elastic_search_sink,
+            checkpoints: DynamoDbLibrary::new(checkpoint_table_name),
+            from: from_date.unwrap(),
+            to: to_date.unwrap(),
+            throttle_millis: get_parsed_env_var_or("KCL_BACKFILL_THROTTLE_MILLIS", 1000)
+        });
+    }
+
+    /// The checkpoint table is created like the lease table, with the same capacity.
+    pub fn ensure_table(&self, capacity: &TableCapacity, active_timeout_seconds: u64) -> Result<(), KclError> {
+        return self.checkpoints.ensure_table("backfill_id", capacity, active_timeout_seconds);
+    }
+
+    fn parse_hour(hour: &String) -> Option<DateTime<Utc>> {
+        let naive_date_time =
+            NaiveDateTime::parse_from_str(&format!("{}:00:00", hour), "%Y-%m-%dT%H:%M:%S");
+        if naive_date_time.is_err() {
+            return None;
+        }
+
+        return Some(DateTime::<Utc>::from_utc(naive_date_time.unwrap(), Utc));
+    }
+
+    pub fn run(&self) -> bool {
+        let backfill_id = format!(
+            "{}/{}_{}", self.s3_sink.config.bucket_name,
+            self.from.format(BACKFILL_HOUR_FORMAT), self.to.format(BACKFILL_HOUR_FORMAT)
+        );
+
+        let checkpoint = self.checkpoints.get_backfill_checkpoint(&backfill_id);
+        if checkpoint.is_err() {
+            eprintln!("Can't read the checkpoint of backfill {}. {}", backfill_id, checkpoint.unwrap_err());
+            return false;
+        }
+
+        let checkpoint = checkpoint.unwrap();
+        let mut last_key = None;
+        let mut number_of_objects: u64 = 0;
+        if checkpoint.is_some() {
+            let checkpoint = checkpoint.unwrap();
+            eprintln!("Resuming backfill {} after {}, {} objects were backfilled.",
+                     backfill_id, checkpoint.last_key, checkpoint.number_of_objects);
+            last_key = Some(checkpoint.last_key);
+            number_of_objects = checkpoint.number_of_objects;
+        }
+
+        let mut hour = self.from;
+
+        while hour < self.to {
+            let prefix = self.s3_sink.config.get_hour_prefix(&hour);
+            let keys = self.s3_sink.list_object_keys(&prefix);
+            if keys.is_none() {
+                return false;
+            }
+
+            for key in keys.unwrap() {
+                // Keys are listed in order, so everything up to the checkpoint is already indexed.
+                if last_key.is_some() && &key <= last_key.as_ref().unwrap() {
+                    continue;
+                }
+
+                let object = self.s3_sink.get_object(&key);
+                if object.is_none() {
+                    return false;
+                }
+
+                let bulk = String::from_utf8(object.unwrap());
+                if bulk.is_err() {
+                    eprintln!("Skipping {} - it's not a valid UTF-8 bulk body.", key);
+                    continue;
+                }
+
+                // Only a bulk response accounting for every document moves the checkpoint past the object.
+                let indexed = self.elastic_search_sink.index_bulk(&get_bulk_with_ids(&key, &bulk.unwrap()));
+                if indexed.is_err() {
+                    eprintln!("Backfill {} stopped at {}. - {}", backfill_id, key, indexed.unwrap_err());
+                    return false;
+                }
+
+                let number_of_rejected_documents = indexed.unwrap();
+                if number_of_rejected_documents > 0 {
+                    eprintln!("{} documents of {} were rejected by Elastic search.", number_of_rejected_documents, key);
+                }
+
+                number_of_objects = number_of_objects + 1;
+                let checkpointed = self.checkpoints.update_backfill_checkpoint(&backfill_id, &key, number_of_objects);
+                if checkpointed.is_err() {
+                    eprintln!("Can't checkpoint backfill {} at {}. {}", backfill_id, key, checkpointed.unwrap_err());
+                }
+
+                let sleep_time = time::Duration::from_millis(self.throttle_millis);
+                thread::sleep(sleep_time);
+            }
+
+            eprintln!("Backfilled {} - {} objects so far.", prefix, number_of_objects);
+            hour = hour + Duration::hours(1);
+        }
+
+        eprintln!("Backfill {} is done.", backfill_id);
+        return true;
+    }
+}
+
+/// Adds an `_id` of the object key and the line of the document to every action without one.
+/// The lines of a bulk alternate between an action and its document.
+fn get_bulk_with_ids(key: &String, bulk: &String) -> String {
+    let mut lines: Vec<String> = vec![];
+    for (index, line) in bulk.lines().filter(|line| !line.trim().is_empty()).enumerate() {
+        if index % 2 == 1 {
+            lines.push(line.to_string());
+            continue;
+        }
+
+        let parsed: Result<Value, _> = ::serde_json::from_str(line);
+        let mut action = match parsed {
+            Ok(Value::Object(action)) => action,
+            _ => {
+                lines.push(line.to_string());
+                continue;
+            }
+        };
+
+        for (_, metadata) in action.iter_mut() {
+            if let Value::Object(ref mut metadata) = *metadata {
+                if !metadata.contains_key("_id") {
+                    metadata.insert("_id".to_string(), Value::String(format!("{}:{}", key, index + 2)));
+                }
+            }
+        }
+
+        lines.push(Value::Object(action).to_string());
+    }
+
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    return lines.join("\n") + "\n";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_key_and_line_as_id_of_every_document() {
+        let bulk = "{\"index\": {\"_index\": \"logs\", \"_type\": \"_doc\"} }\n{\"id\":1}\n\
+                    {\"index\": {\"_index\": \"logs\", \"_type\": \"_doc\", \"_id\": \"kept\"} }\n{\"id\":2}\n\
+                    {\"index\": {\"_index\": \"logs\", \"_type\": \"_doc\"} }\n{\"id\":3}\n".to_string();
+
+        let bulk_with_ids = get_bulk_with_ids(&"2018/10/01/13/00_a.json".to_string(), &bulk);
+
+        let lines: Vec<&str> = bulk_with_ids.lines().collect();
+        assert_eq!(lines, vec![
+            r#"{"index":{"_id":"2018/10/01/13/00_a.json:2","_index":"logs","_type":"_doc"}}"#,
+            r#"{"id":1}"#,
+            r#"{"index":{"_id":"kept","_index":"logs","_type":"_doc"}}"#,
+            r#"{"id":2}"#,
+            r#"{"index":{"_id":"2018/10/01/13/00_a.json:6","_index":"logs","_type":"_doc"}}"#,
+            r#"{"id":3}"#,
+        ]);
+        assert!(bulk_with_ids.ends_with("\n"));
+    }
+
+    #[test]
+    fn gets_the_same_ids_when_an_object_is_indexed_again() {
+        let bulk = "{\"index\": {\"_index\": \"logs\"} }\n{\"id\":1}\n".to_string();
+        let key = "2018/10/01/13/00_a.json".to_string();
+
+        assert_eq!(get_bulk_with_ids(&key, &bulk), get_bulk_with_ids(&key, &bulk));
+        assert_eq!(get_bulk_with_ids(&key, &"\n".to_string()), "");
+    }
+}
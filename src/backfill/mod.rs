@@ -0,0 +1 @@
+pub mod backfill_job;
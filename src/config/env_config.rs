@@ -0,0 +1,42 @@
+use std::env;
+use std::str::FromStr;
+
+/// Read an environment variable, treating a blank value as unset.
+pub fn get_optional_env_var(name: &str) -> Option<String> {
+    let value = env::var(name);
+    if value.is_err() || value.clone().unwrap().trim().is_empty() {
+        return None;
+    }
+
+    return Some(value.unwrap().trim().to_string());
+}
+
+pub fn get_env_var_or(name: &str, default_value: &str) -> String {
+    return get_optional_env_var(name).unwrap_or(default_value.to_string());
+}
+
+/// Parse an environment variable, falling back to the default if it's unset or invalid.
+pub fn get_parsed_env_var_or<T: FromStr>(name: &str, default_value: T) -> T {
+    let value = get_optional_env_var(name);
+    if value.is_none() {
+        return default_value;
+    }
+
+    let parsed = value.clone().unwrap().parse::<T>();
+    if parsed.is_err() {
+        eprintln!("Invalid value {} for {} - using the default.", value.unwrap(), name);
+        return default_value;
+    }
+
+    return parsed.ok().unwrap();
+}
+
+pub fn get_bool_env_var(name: &str) -> bool {
+    let value = get_optional_env_var(name);
+    if value.is_none() {
+        return false;
+    }
+
+    let value_string = value.unwrap().to_lowercase();
+    return value_string == "true" || value_string == "1" || value_string == "yes";
+}
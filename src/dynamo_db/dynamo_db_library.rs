@@ -1,10 +1,48 @@
 use rusoto_core::Region;
 use rusoto_dynamodb::*;
 use std::collections::HashMap;
+use std::thread;
+use std::time;
+use chrono::Utc;
+use serde_json::Value;
+use dynamo_db::dynamo_db_table::DynamoDbTable;
+use dynamo_db::table_config::TableCapacity;
+use kinesis_stream::kcl_error::KclError;
+use retry::retry_policy::{ClassifiedError, RetryPolicy};
+
+const SINK_SEQUENCE_NUMBER_PREFIX: &str = "sink_sequence_number_";
+const TABLE_STATUS_CHECK_INTERVAL_MILLIS: u64 = 1000;
+
+/// Where a backfill stopped, the last indexed object and the number of objects indexed so far.
+#[derive(Clone, Debug)]
+pub struct BackfillCheckpoint {
+    pub last_key: String,
+    pub number_of_objects: u64,
+}
+
+/// A row of the lease table.
+#[derive(Clone, Debug)]
+pub struct ShardLease {
+    pub shard_id: String,
+    pub owner_id: Option<String>,
+}
+
+/// A row of the worker registry.
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkerRecord {
+    pub worker_id: String,
+    pub host: String,
+    pub started_at: String,
+    pub last_heartbeat: String,
+    pub owned_shards: Vec<String>,
+    pub version: String,
+}
 
 pub struct DynamoDbLibrary {
     dynamo_db_client: DynamoDbClient,
+    dynamo_db_table: Box<DynamoDbTable>,
     table_name: String,
+    retry_policy: RetryPolicy,
 }
 
 impl DynamoDbLibrary {
@@ -12,26 +50,158 @@ impl DynamoDbLibrary {
         let dynamo_db_client =
             DynamoDbLibrary::initialize_new_dynamo_db_client();
 
-        DynamoDbLibrary { dynamo_db_client, table_name }
+        DynamoDbLibrary {
+            dynamo_db_table: Box::new(dynamo_db_client.clone()),
+            dynamo_db_client,
+            table_name,
+            retry_policy: RetryPolicy::from_env()
+        }
+    }
+
+    /// The item calls go to the given table instead, e.g. an in-memory one.
+    #[cfg(test)]
+    pub fn new_with_table(table_name: String, dynamo_db_table: Box<DynamoDbTable>) -> DynamoDbLibrary {
+        DynamoDbLibrary {
+            dynamo_db_client: DynamoDbLibrary::initialize_new_dynamo_db_client(),
+            dynamo_db_table,
+            table_name,
+            retry_policy: RetryPolicy::from_env()
+        }
     }
 
     fn initialize_new_dynamo_db_client() -> DynamoDbClient {
         return DynamoDbClient::new(Region::EuWest1);
     }
 
-    /// Get the shard sequence number if it's already owned (has owner id).
-    pub fn get_owned_shard_sequence_number_given_shard_id(&self, shard_id: &String) -> Option<String> {
-        let db_record = self.get_db_full_record_using_shard_id(shard_id);
-        if db_record.is_none() {
-            return None;
+    pub fn get_table_name(&self) -> String {
+        return self.table_name.to_string();
+    }
+
+    /// Create the table keyed by the given string hash key if it's missing, wait for it to be
+    /// ACTIVE and check that its key schema is the expected one.
+    pub fn ensure_table(&self, hash_key: &str, capacity: &TableCapacity,
+                        active_timeout_seconds: u64) -> Result<(), KclError> {
+        let describe_table_result = self.dynamo_db_client.describe_table(
+            DescribeTableInput { table_name: self.table_name.to_string() }
+        ).sync();
+
+        match describe_table_result {
+            Ok(_) => {}
+            Err(DescribeTableError::ResourceNotFound(_)) => self.create_table(hash_key, capacity)?,
+            Err(error) => return Err(KclError::from(error)),
         }
 
-        let item_output = db_record.unwrap();
-        if item_output.item.is_none() {
-            return None;
+        let table_description = self.wait_for_active_table(active_timeout_seconds)?;
+        let key_schema = table_description.key_schema.unwrap_or(vec![]);
+        let hash_key_type = table_description.attribute_definitions.unwrap_or(vec![]).into_iter()
+            .find(|attribute_definition| attribute_definition.attribute_name == hash_key)
+            .map(|attribute_definition| attribute_definition.attribute_type);
+
+        let is_key_schema_valid = key_schema.len() == 1 &&
+            key_schema[0].attribute_name == hash_key && key_schema[0].key_type == "HASH" &&
+            hash_key_type == Some("S".to_string());
+
+        if !is_key_schema_valid {
+            return Err(KclError::Service(
+                format!("The table {} should only be keyed by the string hash key {}.", self.table_name, hash_key)
+            ));
         }
 
-        let item = item_output.item.unwrap();
+        return Ok(());
+    }
+
+    /// Let DynamoDB delete the rows once the given number attribute, in seconds since the epoch, is past.
+    pub fn enable_time_to_live(&self, attribute_name: &str) -> Result<(), KclError> {
+        let time_to_live = self.dynamo_db_client.describe_time_to_live(
+            DescribeTimeToLiveInput { table_name: self.table_name.to_string() }
+        ).sync()?;
+
+        let time_to_live_status = time_to_live.time_to_live_description
+            .and_then(|time_to_live_description| time_to_live_description.time_to_live_status)
+            .unwrap_or(String::new());
+        if time_to_live_status == "ENABLED" || time_to_live_status == "ENABLING" {
+            return Ok(());
+        }
+
+        eprintln!("Enabling the time to live of the table {} on {}.", self.table_name, attribute_name);
+        self.dynamo_db_client.update_time_to_live(
+            UpdateTimeToLiveInput {
+                table_name: self.table_name.to_string(),
+                time_to_live_specification: TimeToLiveSpecification {
+                    attribute_name: attribute_name.to_string(),
+                    enabled: true
+                }
+            }
+        ).sync()?;
+
+        return Ok(());
+    }
+
+    fn create_table(&self, hash_key: &str, capacity: &TableCapacity) -> Result<(), KclError> {
+        eprintln!("Creating the table {}.", self.table_name);
+        let mut create_table_input = CreateTableInput {
+            attribute_definitions: vec![
+                AttributeDefinition { attribute_name: hash_key.to_string(), attribute_type: "S".to_string() }
+            ],
+            key_schema: vec![
+                KeySchemaElement { attribute_name: hash_key.to_string(), key_type: "HASH".to_string() }
+            ],
+            table_name: self.table_name.to_string(),
+            ..Default::default()
+        };
+
+        match *capacity {
+            TableCapacity::OnDemand => create_table_input.billing_mode = Some("PAY_PER_REQUEST".to_string()),
+            TableCapacity::Provisioned { read_capacity_units, write_capacity_units } => {
+                create_table_input.billing_mode = Some("PROVISIONED".to_string());
+                create_table_input.provisioned_throughput =
+                    Some(ProvisionedThroughput { read_capacity_units, write_capacity_units });
+            }
+        }
+
+        let create_table_result = self.dynamo_db_client.create_table(create_table_input).sync();
+        match create_table_result {
+            Ok(_) => Ok(()),
+            // Another worker is creating it.
+            Err(CreateTableError::ResourceInUse(_)) => Ok(()),
+            Err(error) => Err(KclError::from(error)),
+        }
+    }
+
+    fn wait_for_active_table(&self, active_timeout_seconds: u64) -> Result<TableDescription, KclError> {
+        let started_at = time::Instant::now();
+        while started_at.elapsed() < time::Duration::from_secs(active_timeout_seconds) {
+            let describe_table_result = self.dynamo_db_client.describe_table(
+                DescribeTableInput { table_name: self.table_name.to_string() }
+            ).sync();
+
+            if describe_table_result.is_err() {
+                eprintln!("Can't describe the table {}. {}", self.table_name, describe_table_result.err().unwrap());
+            } else {
+                let table_description = describe_table_result.unwrap().table;
+                if table_description.is_some() &&
+                    table_description.clone().unwrap().table_status == Some("ACTIVE".to_string()) {
+                    return Ok(table_description.unwrap());
+                }
+            }
+
+            thread::sleep(time::Duration::from_millis(TABLE_STATUS_CHECK_INTERVAL_MILLIS));
+        }
+
+        return Err(KclError::Service(
+            format!("The table {} isn't ACTIVE after {} seconds.", self.table_name, active_timeout_seconds)
+        ));
+    }
+
+    /// Get the shard sequence number if it's already owned (has owner id).
+    pub fn get_owned_shard_sequence_number_given_shard_id(&self, shard_id: &String)
+                                                          -> Result<Option<String>, KclError> {
+        let item = self.get_db_full_record_using_shard_id(shard_id)?;
+        if item.is_none() {
+            return Ok(None);
+        }
+
+        let item = item.unwrap();
         let owner_id_attribute = item.get("owner_id");
         let sequence_number_attribute = item.get("sequence_number");
 
@@ -40,33 +210,25 @@ impl DynamoDbLibrary {
             let owner_id_dynamo_option = owner_id_attribute.unwrap().clone().s;
 
             if sequence_number_dynamo_option.is_some() && owner_id_dynamo_option.is_some() {
-                return Some(sequence_number_dynamo_option.unwrap());
+                return Ok(Some(sequence_number_dynamo_option.unwrap()));
             }
         }
 
-        return None;
+        return Ok(None);
     }
 
-    /// Get the shard owner id.
-    pub fn get_shard_owned_id(&self, shard_id: &String) -> Option<String> {
-        let db_record = self.get_db_full_record_using_shard_id(shard_id);
-        let item = db_record.unwrap().item.unwrap();
-        let owner_id = item.get("owner_id");
-
-        if owner_id.is_some() {
-            let owner_id_string = owner_id.unwrap().clone().s;
-            if owner_id_string.is_some() {
-                return Some(owner_id_string.unwrap());
-            }
+    /// Get the shard owner id, a shard without a lease row is NotFound.
+    pub fn get_shard_owned_id(&self, shard_id: &String) -> Result<Option<String>, KclError> {
+        let item = self.get_db_full_record_using_shard_id(shard_id)?;
+        if item.is_none() {
+            return Err(KclError::NotFound(format!("No lease for shard {}.", shard_id)));
         }
 
-        return None;
+        let owner_id = item.unwrap().get("owner_id").and_then(|owner_id| owner_id.s.clone());
+        return Ok(owner_id);
     }
 
-    pub fn extract_sequence_number_from_record(&self,
-                                               item_option: Option<HashMap<String, AttributeValue>>)
-                                               -> Option<String> {
-        let item = item_option.unwrap();
+    pub fn extract_sequence_number_from_record(&self, item: &HashMap<String, AttributeValue>) -> Option<String> {
         let sequence_number_attribute = item.get("sequence_number");
         if sequence_number_attribute.is_some() {
             let sequence_number_dynamo_option = sequence_number_attribute.unwrap().clone().s;
@@ -78,32 +240,75 @@ impl DynamoDbLibrary {
         return None;
     }
 
-    /// Update the owner of the shard to no owner.
-    pub fn release_shard_from_owner(&self, shard_id: &String) {
-        println!("Shard {} will be released from owner.", shard_id);
+    /// The last sequence number acknowledged by each sink of the shard.
+    pub fn extract_sink_sequence_numbers_from_record(&self, item: &HashMap<String, AttributeValue>)
+                                                     -> HashMap<String, String> {
+        let mut sink_sequence_numbers = HashMap::new();
+        for (attribute_name, attribute_value) in item {
+            if attribute_name.starts_with(SINK_SEQUENCE_NUMBER_PREFIX) && attribute_value.s.is_some() {
+                sink_sequence_numbers.insert(
+                    attribute_name[SINK_SEQUENCE_NUMBER_PREFIX.len()..].to_string(),
+                    attribute_value.s.clone().unwrap()
+                );
+            }
+        }
+
+        return sink_sequence_numbers;
+    }
+
+    /// Update the owner of the shard to no owner, as long as the given worker still owns it.
+    pub fn release_shard_from_owner(&self, shard_id: &String, owner_id: &String) -> Result<(), KclError> {
+        eprintln!("Shard {} will be released from owner {}.", shard_id, owner_id);
+        let mut update_item_input = self.get_shard_release_update_item_input(shard_id);
+        update_item_input.condition_expression = Some("owner_id = :current_owner_id_val".to_string());
+        update_item_input.expression_attribute_values.as_mut().unwrap().insert(
+            ":current_owner_id_val".to_string(), self.get_string_attribute_value(owner_id.to_string())
+        );
+
+        return self.update_shard_owner_conditionally(update_item_input);
+    }
+
+    /// Release the shard whose checkpoint is still the given one, whoever owns it.
+    pub fn release_idle_shard(&self, shard_id: &String, sequence_number: &String) -> Result<(), KclError> {
+        eprintln!("Idle shard {} will be released from owner.", shard_id);
+        let mut update_item_input = self.get_shard_release_update_item_input(shard_id);
+        update_item_input.condition_expression = Some("sequence_number = :idle_sequence_number_val".to_string());
+        update_item_input.expression_attribute_values.as_mut().unwrap().insert(
+            ":idle_sequence_number_val".to_string(), self.get_string_attribute_value(sequence_number.to_string())
+        );
+
+        return self.update_shard_owner_conditionally(update_item_input);
+    }
+
+    fn get_shard_release_update_item_input(&self, shard_id: &String) -> UpdateItemInput {
         let shard_id_attribute_value =
             self.get_string_attribute_value(shard_id.to_string());
 
         let mut item_input_hash_map = HashMap::new();
         item_input_hash_map.insert("shard_id".to_string(), shard_id_attribute_value);
 
-        let get_item_input = self.get_shard_owner_reset_update_item_input(item_input_hash_map);
-        let get_item_result = self.dynamo_db_client.update_item(get_item_input).sync();
+        return self.get_shard_owner_reset_update_item_input(item_input_hash_map);
+    }
 
-        if get_item_result.is_err() {
-            println!("Error while writing to Dynamo. {}", get_item_result.err().unwrap());
-        }
+    /// Ownership only ever changes through conditional updates, so two workers can't both win a shard.
+    /// ConditionalCheckFailed means the owner changed in the meantime.
+    fn update_shard_owner_conditionally(&self, update_item_input: UpdateItemInput) -> Result<(), KclError> {
+        self.update_item(update_item_input)?;
+        return Ok(());
     }
 
-    pub fn get_db_full_record_using_shard_id(&self, shard_id: &String) -> Option<GetItemOutput> {
+    /// The lease row of the shard, None if there's none yet.
+    pub fn get_db_full_record_using_shard_id(&self, shard_id: &String)
+                                             -> Result<Option<HashMap<String, AttributeValue>>, KclError> {
         let index_key: &str = "shard_id";
         let attribute_value = self.get_string_attribute_value(shard_id.to_string());
 
         let mut item_input_hash_map = HashMap::new();
         item_input_hash_map.insert(index_key.to_string(), attribute_value);
+        // Ownership checks must see the latest owner.
         let get_item_input = GetItemInput {
             attributes_to_get: None,
-            consistent_read: None,
+            consistent_read: Some(true),
             expression_attribute_names: None,
             key: item_input_hash_map,
             projection_expression: None,
@@ -111,16 +316,12 @@ impl DynamoDbLibrary {
             table_name: self.table_name.to_string()
         };
 
-        let item_output = self.dynamo_db_client.get_item(get_item_input).sync();
-        if item_output.is_err() {
-            println!("Error while reading from Dynamo. {}", item_output.err().unwrap());
-            return None;
-        }
-
-        return Some(item_output.unwrap());
+        let item_output = self.get_item(get_item_input)?;
+        return Ok(item_output.item);
     }
 
-    pub fn add_new_shard_record(&self, shard_id: &String, worker_id: &String) -> bool {
+    /// ConditionalCheckFailed means another worker added the shard first.
+    pub fn add_new_shard_record(&self, shard_id: &String, worker_id: &String) -> Result<(), KclError> {
         let shard_id_attribute_value = self.get_string_attribute_value(shard_id.to_string());
         let owner_id_attribute_value = self.get_string_attribute_value(worker_id.to_string());
         let number_of_owners_switched_attribute_value =
@@ -134,51 +335,564 @@ impl DynamoDbLibrary {
             number_of_owners_switched_attribute_value
         );
 
-        let put_item_input = self.get_put_item_input(item_input_hash_map);
-        let put_item_result =
-            self.dynamo_db_client.put_item(put_item_input).sync();
+        // Another worker may be adding the same shard.
+        let mut put_item_input = self.get_put_item_input(item_input_hash_map);
+        put_item_input.condition_expression = Some("attribute_not_exists(shard_id)".to_string());
 
-        if put_item_result.is_err() {
-            println!("Error while writing to Dynamo. {}", put_item_result.err().unwrap());
-            return false;
-        }
-
-        return true;
+        return self.take_ownership_conditionally(shard_id, worker_id, || {
+            self.put_item(put_item_input)?;
+            Ok(())
+        });
     }
 
-    pub fn update_shard_owner(&self, shard_id: &String, worker_id: &String) -> bool {
+    pub fn update_shard_owner(&self, shard_id: &String, worker_id: &String) -> Result<(), KclError> {
         let shard_id_attribute_value = self.get_string_attribute_value(shard_id.to_string());
 
         let mut item_input_hash_map = HashMap::new();
         item_input_hash_map.insert("shard_id".to_string(), shard_id_attribute_value);
 
-        let get_item_input = self.get_shard_owner_update_item_input(item_input_hash_map, worker_id.to_string());
-        let get_item_result = self.dynamo_db_client.update_item(get_item_input).sync();
-        if get_item_result.is_err() {
-            println!("Error while writing to Dynamo. {}", get_item_result.err().unwrap());
-            return false;
+        let update_item_input = self.get_shard_owner_update_item_input(item_input_hash_map, worker_id.to_string());
+        return self.take_ownership_conditionally(shard_id, worker_id, || {
+            self.update_shard_owner_conditionally(update_item_input)
+        });
+    }
+
+    /// A write retried after a transport error may have landed the first time, the retry then
+    /// fails its condition. The owner is read again, the worker owning the shard is a success.
+    fn take_ownership_conditionally<F>(&self, shard_id: &String, worker_id: &String,
+                                       write: F) -> Result<(), KclError>
+        where F: FnOnce() -> Result<(), KclError> {
+        match write() {
+            Err(KclError::ConditionalCheckFailed(message)) => {
+                if self.get_shard_owned_id(shard_id)? == Some(worker_id.to_string()) {
+                    return Ok(());
+                }
+
+                Err(KclError::ConditionalCheckFailed(message))
+            }
+            result => result,
+        }
+    }
+
+    /// Every lease of the table, read consistently page by page.
+    pub fn get_shard_leases(&self) -> Result<Vec<ShardLease>, KclError> {
+        let mut shard_leases = vec![];
+        let mut exclusive_start_key = None;
+        loop {
+            let scan_input = ScanInput {
+                consistent_read: Some(true),
+                exclusive_start_key: exclusive_start_key.clone(),
+                projection_expression: Some("shard_id, owner_id".to_string()),
+                table_name: self.table_name.to_string(),
+                ..Default::default()
+            };
+
+            let scan_output = self.scan(scan_input)?;
+            for item in scan_output.items.unwrap_or(vec![]) {
+                let shard_id = item.get("shard_id").and_then(|shard_id| shard_id.s.clone());
+                if shard_id.is_some() {
+                    shard_leases.push(ShardLease {
+                        shard_id: shard_id.unwrap(),
+                        owner_id: item.get("owner_id").and_then(|owner_id| owner_id.s.clone())
+                    });
+                }
+            }
+
+            exclusive_start_key = scan_output.last_evaluated_key;
+            if exclusive_start_key.is_none() {
+                return Ok(shard_leases);
+            }
+        }
+    }
+
+    /// Move the lease to the given worker, as long as the other worker still owns it.
+    pub fn steal_shard_lease(&self, shard_id: &String, current_owner_id: &String,
+                             worker_id: &String) -> Result<(), KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert("shard_id".to_string(), self.get_string_attribute_value(shard_id.to_string()));
+
+        let mut update_item_input = self.get_shard_owner_update_item_input(item_input_hash_map, worker_id.to_string());
+        update_item_input.condition_expression = Some("owner_id = :current_owner_id_val".to_string());
+        update_item_input.expression_attribute_values.as_mut().unwrap().remove(":null_attribute_type");
+        update_item_input.expression_attribute_values.as_mut().unwrap().insert(
+            ":current_owner_id_val".to_string(), self.get_string_attribute_value(current_owner_id.to_string())
+        );
+
+        return self.take_ownership_conditionally(shard_id, worker_id, || {
+            self.update_shard_owner_conditionally(update_item_input)
+        });
+    }
+
+    /// Write the worker's registry row, every heartbeat replaces it. DynamoDB deletes the row
+    /// some time after expires_at, in seconds since the epoch.
+    pub fn put_worker_record(&self, worker_record: &WorkerRecord, expires_at: i64) -> Result<(), KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "worker_id".to_string(), self.get_string_attribute_value(worker_record.worker_id.to_string())
+        );
+        item_input_hash_map.insert(
+            "host".to_string(), self.get_string_attribute_value(worker_record.host.to_string())
+        );
+        item_input_hash_map.insert(
+            "started_at".to_string(), self.get_string_attribute_value(worker_record.started_at.to_string())
+        );
+        item_input_hash_map.insert(
+            "last_heartbeat".to_string(), self.get_string_attribute_value(worker_record.last_heartbeat.to_string())
+        );
+        item_input_hash_map.insert(
+            "owned_shards".to_string(),
+            AttributeValue {
+                l: Some(worker_record.owned_shards.iter()
+                    .map(|shard_id| self.get_string_attribute_value(shard_id.to_string()))
+                    .collect()),
+                ..Default::default()
+            }
+        );
+        item_input_hash_map.insert(
+            "version".to_string(), self.get_string_attribute_value(worker_record.version.to_string())
+        );
+        item_input_hash_map.insert(
+            "expires_at".to_string(), self.get_number_attribute_value(expires_at.to_string())
+        );
+
+        let put_item_input = self.get_put_item_input(item_input_hash_map);
+        self.put_item(put_item_input)?;
+        return Ok(());
+    }
+
+    /// Every row of the worker registry.
+    pub fn get_worker_records(&self) -> Result<Vec<WorkerRecord>, KclError> {
+        let mut worker_records = vec![];
+        let mut exclusive_start_key = None;
+        loop {
+            let scan_input = ScanInput {
+                consistent_read: Some(true),
+                exclusive_start_key: exclusive_start_key.clone(),
+                table_name: self.table_name.to_string(),
+                ..Default::default()
+            };
+
+            let scan_output = self.scan(scan_input)?;
+            for item in scan_output.items.unwrap_or(vec![]) {
+                let get_string = |name: &str| item.get(name).and_then(|attribute| attribute.s.clone());
+                if get_string("worker_id").is_none() {
+                    continue;
+                }
+
+                let owned_shards = item.get("owned_shards")
+                    .and_then(|attribute| attribute.l.clone())
+                    .unwrap_or(vec![])
+                    .into_iter()
+                    .filter_map(|attribute| attribute.s)
+                    .collect();
+
+                worker_records.push(WorkerRecord {
+                    worker_id: get_string("worker_id").unwrap(),
+                    host: get_string("host").unwrap_or(String::new()),
+                    started_at: get_string("started_at").unwrap_or(String::new()),
+                    last_heartbeat: get_string("last_heartbeat").unwrap_or(String::new()),
+                    owned_shards,
+                    version: get_string("version").unwrap_or(String::new())
+                });
+            }
+
+            exclusive_start_key = scan_output.last_evaluated_key;
+            if exclusive_start_key.is_none() {
+                return Ok(worker_records);
+            }
         }
+    }
+
+    /// Checkpoint the shard along with the progress of each of its sinks, as long as the worker
+    /// still owns it. ConditionalCheckFailed means the shard was lost.
+    pub fn update_shard_sequence_number(&self, shard_id: &String, worker_id: &String, sequence_number: &String,
+                                        sink_sequence_numbers: &HashMap<String, String>) -> Result<(), KclError> {
+        let shard_id_attribute_value = self.get_string_attribute_value(shard_id.to_string());
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert("shard_id".to_string(), shard_id_attribute_value);
+
+        let update_item_input =
+            self.get_sequence_number_update_item_input(
+                item_input_hash_map, worker_id, Some(sequence_number), sink_sequence_numbers
+            );
 
-        return true;
+        return self.update_checkpoint(update_item_input);
     }
 
-    pub fn update_shard_sequence_number(&self, shard_id: &String, sequence_number: &String) -> bool {
+    /// Save the progress of the sinks without moving the shard checkpoint.
+    pub fn update_sink_sequence_numbers(&self, shard_id: &String, worker_id: &String,
+                                        sink_sequence_numbers: &HashMap<String, String>) -> Result<(), KclError> {
+        if sink_sequence_numbers.is_empty() {
+            return Ok(());
+        }
+
         let shard_id_attribute_value = self.get_string_attribute_value(shard_id.to_string());
         let mut item_input_hash_map = HashMap::new();
         item_input_hash_map.insert("shard_id".to_string(), shard_id_attribute_value);
 
         let update_item_input =
             self.get_sequence_number_update_item_input(
-                item_input_hash_map, sequence_number
+                item_input_hash_map, worker_id, None, sink_sequence_numbers
             );
 
-        let put_item_result = self.dynamo_db_client.update_item(update_item_input).sync();
-        if put_item_result.is_err() {
-            println!("Error while writing to Dynamo. {}", put_item_result.err().unwrap());
-            return false;
+        return self.update_checkpoint(update_item_input);
+    }
+
+    fn update_checkpoint(&self, update_item_input: UpdateItemInput) -> Result<(), KclError> {
+        self.update_item(update_item_input)?;
+        return Ok(());
+    }
+
+    /// Claim the Glacier archive of the given hour until claim_expires_at (epoch seconds). An hour
+    /// never claimed or whose last claim expired is claimed, Ok(false) if it's archived or claimed.
+    pub fn add_archive_inventory_claim(&self, archive_hour: &String, worker_id: &String,
+                                       claim_expires_at: i64) -> Result<bool, KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "archive_hour".to_string(), self.get_string_attribute_value(archive_hour.to_string())
+        );
+        item_input_hash_map.insert(
+            "owner_id".to_string(), self.get_string_attribute_value(worker_id.to_string())
+        );
+        item_input_hash_map.insert(
+            "archive_status".to_string(), self.get_string_attribute_value("IN_PROGRESS".to_string())
+        );
+        item_input_hash_map.insert(
+            "claim_expires_at".to_string(), self.get_number_attribute_value(claim_expires_at.to_string())
+        );
+
+        let mut expression_attribute_values = HashMap::new();
+        expression_attribute_values.insert(
+            ":in_progress".to_string(), self.get_string_attribute_value("IN_PROGRESS".to_string())
+        );
+        expression_attribute_values.insert(
+            ":now".to_string(), self.get_number_attribute_value(Utc::now().timestamp().to_string())
+        );
+
+        let mut put_item_input = self.get_put_item_input(item_input_hash_map);
+        put_item_input.condition_expression = Some(
+            "attribute_not_exists(archive_hour) OR (archive_status = :in_progress AND claim_expires_at < :now)"
+                .to_string()
+        );
+        put_item_input.expression_attribute_values = Some(expression_attribute_values);
+
+        match self.put_item(put_item_input) {
+            Ok(_) => Ok(true),
+            // A retried claim fails its condition when the first attempt landed, the claim is then ours.
+            Err(KclError::ConditionalCheckFailed(_)) => {
+                let record = self.get_archive_inventory_record(archive_hour)?;
+                let get_attribute = |name: &str| record.as_ref()
+                    .and_then(|record| record.get(name))
+                    .and_then(|attribute_value| attribute_value.s.clone());
+
+                Ok(get_attribute("owner_id") == Some(worker_id.to_string()) &&
+                    get_attribute("archive_status") == Some("IN_PROGRESS".to_string()))
+            }
+            Err(error) => Err(error),
         }
+    }
 
-        return true;
+    /// The archive_status of the given hour, None if it was never claimed.
+    pub fn get_archive_inventory_status(&self, archive_hour: &String) -> Result<Option<String>, KclError> {
+        let record = self.get_archive_inventory_record(archive_hour)?;
+        return Ok(record.and_then(|record| record.get("archive_status").and_then(|status| status.s.clone())));
+    }
+
+    fn get_archive_inventory_record(&self, archive_hour: &String)
+                                    -> Result<Option<HashMap<String, AttributeValue>>, KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "archive_hour".to_string(), self.get_string_attribute_value(archive_hour.to_string())
+        );
+
+        let get_item_input = GetItemInput {
+            consistent_read: Some(true),
+            key: item_input_hash_map,
+            table_name: self.table_name.to_string(),
+            ..Default::default()
+        };
+
+        let item_output = self.get_item(get_item_input)?;
+        return Ok(item_output.item);
+    }
+
+    /// Record where the archive of the given hour is kept in Glacier.
+    pub fn complete_archive_inventory_record(&self, archive_hour: &String, archive_id: &String,
+                                             vault_name: &String, number_of_objects: usize,
+                                             size_in_bytes: usize, checksum: &String) -> Result<(), KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "archive_hour".to_string(), self.get_string_attribute_value(archive_hour.to_string())
+        );
+        item_input_hash_map.insert(
+            "archive_status".to_string(), self.get_string_attribute_value("ARCHIVED".to_string())
+        );
+        item_input_hash_map.insert(
+            "archive_id".to_string(), self.get_string_attribute_value(archive_id.to_string())
+        );
+        item_input_hash_map.insert(
+            "vault_name".to_string(), self.get_string_attribute_value(vault_name.to_string())
+        );
+        item_input_hash_map.insert(
+            "number_of_objects".to_string(),
+            self.get_number_attribute_value(number_of_objects.to_string())
+        );
+        item_input_hash_map.insert(
+            "size_in_bytes".to_string(), self.get_number_attribute_value(size_in_bytes.to_string())
+        );
+        item_input_hash_map.insert(
+            "checksum".to_string(), self.get_string_attribute_value(checksum.to_string())
+        );
+        item_input_hash_map.insert(
+            "archived_at".to_string(), self.get_string_attribute_value(Utc::now().to_rfc3339())
+        );
+
+        let put_item_input = self.get_put_item_input(item_input_hash_map);
+        self.put_item(put_item_input)?;
+        return Ok(());
+    }
+
+    /// Drop the claim of an archive that failed, so it's retried later.
+    pub fn release_archive_inventory_claim(&self, archive_hour: &String) -> Result<(), KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "archive_hour".to_string(), self.get_string_attribute_value(archive_hour.to_string())
+        );
+
+        let delete_item_input = DeleteItemInput {
+            key: item_input_hash_map,
+            table_name: self.table_name.to_string(),
+            ..Default::default()
+        };
+
+        self.delete_item(delete_item_input)?;
+        return Ok(());
+    }
+
+    /// The last archived object replayed by the given backfill.
+    pub fn get_backfill_checkpoint(&self, backfill_id: &String) -> Result<Option<BackfillCheckpoint>, KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "backfill_id".to_string(), self.get_string_attribute_value(backfill_id.to_string())
+        );
+
+        let get_item_input = GetItemInput {
+            consistent_read: Some(true),
+            key: item_input_hash_map,
+            table_name: self.table_name.to_string(),
+            ..Default::default()
+        };
+
+        let item = self.get_item(get_item_input)?.item;
+        if item.is_none() {
+            return Ok(None);
+        }
+
+        let item = item.unwrap();
+        let last_key = item.get("last_key").and_then(|attribute| attribute.s.clone());
+        if last_key.is_none() {
+            return Ok(None);
+        }
+
+        let number_of_objects = item.get("number_of_objects")
+            .and_then(|attribute| attribute.n.clone())
+            .and_then(|number| number.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        return Ok(Some(BackfillCheckpoint { last_key: last_key.unwrap(), number_of_objects }));
+    }
+
+    pub fn update_backfill_checkpoint(&self, backfill_id: &String, last_key: &String,
+                                      number_of_objects: u64) -> Result<(), KclError> {
+        let mut item_input_hash_map = HashMap::new();
+        item_input_hash_map.insert(
+            "backfill_id".to_string(), self.get_string_attribute_value(backfill_id.to_string())
+        );
+        item_input_hash_map.insert(
+            "last_key".to_string(), self.get_string_attribute_value(last_key.to_string())
+        );
+        item_input_hash_map.insert(
+            "number_of_objects".to_string(),
+            self.get_number_attribute_value(number_of_objects.to_string())
+        );
+        item_input_hash_map.insert(
+            "updated_at".to_string(), self.get_string_attribute_value(Utc::now().to_rfc3339())
+        );
+
+        let put_item_input = self.get_put_item_input(item_input_hash_map);
+        self.put_item(put_item_input)?;
+        return Ok(());
+    }
+
+    /// Write the items 25 at a time, sending the unprocessed items again with a back off.
+    /// Fails with the last error, or Throttling if items were still unprocessed, once the retries ran out.
+    /// A batch Dynamo rejects, e.g. for a key of the wrong type or an item over 400 KB, is written
+    /// item by item instead. Returns the number of items skipped as Dynamo rejects them.
+    pub fn batch_write_items(&self, items: Vec<HashMap<String, AttributeValue>>,
+                             max_retries: u32) -> Result<usize, KclError> {
+        let mut number_of_rejected_items = 0;
+        for chunk in items.chunks(25) {
+            let mut write_requests: Vec<WriteRequest> = chunk.iter().map(|item| {
+                WriteRequest {
+                    delete_request: None,
+                    put_request: Some(PutRequest { item: item.clone() })
+                }
+            }).collect();
+
+            let mut number_of_retries = 0;
+            let mut last_error = None;
+            while !write_requests.is_empty() {
+                if number_of_retries > max_retries {
+                    return Err(last_error.unwrap_or(KclError::Throttling(
+                        format!("{} items couldn't be written to {}.", write_requests.len(), self.table_name)
+                    )));
+                }
+
+                if number_of_retries > 0 {
+                    thread::sleep(self.retry_policy.get_delay(number_of_retries));
+                }
+
+                number_of_retries = number_of_retries + 1;
+                let mut request_items = HashMap::new();
+                request_items.insert(self.table_name.to_string(), write_requests.clone());
+
+                let batch_write_result = self.dynamo_db_client.batch_write_item(
+                    BatchWriteItemInput {
+                        request_items,
+                        return_consumed_capacity: None,
+                        return_item_collection_metrics: None
+                    }
+                ).sync();
+
+                if batch_write_result.is_err() {
+                    let error = KclError::from(batch_write_result.err().unwrap());
+                    if let KclError::Service(_) = error {
+                        eprintln!("Dynamo rejected a batch for {}, writing its items one by one. {}", self.table_name, error);
+                        let items = write_requests.drain(..)
+                            .filter_map(|write_request| write_request.put_request.map(|put_request| put_request.item))
+                            .collect();
+                        number_of_rejected_items = number_of_rejected_items + self.put_items(items)?;
+                        continue;
+                    }
+
+                    if !error.get_error_class().is_retryable() {
+                        return Err(error);
+                    }
+
+                    eprintln!("Error while writing to Dynamo. {}", error);
+                    last_error = Some(error);
+                    continue;
+                }
+
+                last_error = None;
+                write_requests = batch_write_result.unwrap().unprocessed_items
+                    .and_then(|mut unprocessed_items| unprocessed_items.remove(&self.table_name))
+                    .unwrap_or(vec![]);
+            }
+        }
+
+        return Ok(number_of_rejected_items);
+    }
+
+    /// Puts the items one at a time, skipping the ones Dynamo rejects. Returns the number of skipped items.
+    fn put_items(&self, items: Vec<HashMap<String, AttributeValue>>) -> Result<usize, KclError> {
+        let mut number_of_rejected_items = 0;
+        for item in items {
+            match self.put_item(self.get_put_item_input(item)) {
+                Ok(_) => {}
+                Err(KclError::Service(message)) => {
+                    eprintln!("Skipping an item {} rejects. - {}", self.table_name, message);
+                    number_of_rejected_items = number_of_rejected_items + 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        return Ok(number_of_rejected_items);
+    }
+
+    /// Put the item unless the stored one has the same or a newer version, a stored item
+    /// without a version is replaced. Numbers compare as numbers, strings byte by byte and a
+    /// version of another type never replaces the stored one. Returns false if the item was
+    /// discarded as out of order.
+    pub fn put_item_if_newer_version(&self, item: HashMap<String, AttributeValue>,
+                                     key_attributes: &Vec<String>, version_attribute: &String)
+                                     -> Result<bool, KclError> {
+        let mut expression_attribute_names = HashMap::new();
+        expression_attribute_names.insert("#key".to_string(), key_attributes[0].to_string());
+        expression_attribute_names.insert("#version".to_string(), version_attribute.to_string());
+
+        let version_attribute_value = item.get(version_attribute).cloned();
+        if version_attribute_value.is_none() {
+            return Ok(false);
+        }
+
+        let mut expression_attribute_values = HashMap::new();
+        expression_attribute_values.insert(":version".to_string(), version_attribute_value.unwrap());
+
+        let mut put_item_input = self.get_put_item_input(item);
+        put_item_input.condition_expression =
+            Some("attribute_not_exists(#key) OR attribute_not_exists(#version) OR #version < :version".to_string());
+        put_item_input.expression_attribute_names = Some(expression_attribute_names);
+        put_item_input.expression_attribute_values = Some(expression_attribute_values);
+
+        match self.put_item(put_item_input) {
+            Ok(_) => Ok(true),
+            Err(KclError::ConditionalCheckFailed(_)) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Map a JSON value to its DynamoDB attribute, empty strings are stored as NULL.
+    pub fn get_attribute_value_from_json(&self, value: &Value) -> AttributeValue {
+        match *value {
+            Value::Null => self.get_null_attribute_value(),
+            Value::Bool(boolean) => AttributeValue { bool: Some(boolean), ..Default::default() },
+            Value::Number(ref number) => self.get_number_attribute_value(number.to_string()),
+            Value::String(ref text) if text.is_empty() => self.get_null_attribute_value(),
+            Value::String(ref text) => self.get_string_attribute_value(text.to_string()),
+            Value::Array(ref values) => AttributeValue {
+                l: Some(values.iter().map(|value| self.get_attribute_value_from_json(value)).collect()),
+                ..Default::default()
+            },
+            Value::Object(ref fields) => AttributeValue {
+                m: Some(fields.iter().map(|(name, value)| {
+                    (name.to_string(), self.get_attribute_value_from_json(value))
+                }).collect()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Throttled and failed calls are retried, a failed condition isn't.
+    fn get_item(&self, get_item_input: GetItemInput) -> Result<GetItemOutput, KclError> {
+        return self.retry_policy.retry("Reading from Dynamo", || {
+            self.dynamo_db_table.get_item(get_item_input.clone())
+        });
+    }
+
+    fn scan(&self, scan_input: ScanInput) -> Result<ScanOutput, KclError> {
+        return self.retry_policy.retry("Reading from Dynamo", || {
+            self.dynamo_db_table.scan(scan_input.clone())
+        });
+    }
+
+    fn put_item(&self, put_item_input: PutItemInput) -> Result<PutItemOutput, KclError> {
+        return self.retry_policy.retry("Writing to Dynamo", || {
+            self.dynamo_db_table.put_item(put_item_input.clone())
+        });
+    }
+
+    fn update_item(&self, update_item_input: UpdateItemInput) -> Result<UpdateItemOutput, KclError> {
+        return self.retry_policy.retry("Writing to Dynamo", || {
+            self.dynamo_db_table.update_item(update_item_input.clone())
+        });
+    }
+
+    fn delete_item(&self, delete_item_input: DeleteItemInput) -> Result<DeleteItemOutput, KclError> {
+        return self.retry_policy.retry("Writing to Dynamo", || {
+            self.dynamo_db_table.delete_item(delete_item_input.clone())
+        });
     }
 
     fn get_shard_owner_reset_update_item_input(&self, hash_map: HashMap<String, AttributeValue>)
@@ -228,7 +942,8 @@ impl DynamoDbLibrary {
 
         return UpdateItemInput {
             attribute_updates: None,
-            condition_expression: Some("owner_id = :null_attribute_type".to_string()),
+            condition_expression:
+                Some("attribute_not_exists(owner_id) OR owner_id = :null_attribute_type".to_string()),
             conditional_operator: None,
             expected: None,
             expression_attribute_names: None,
@@ -303,28 +1018,51 @@ impl DynamoDbLibrary {
     }
 
     fn get_sequence_number_update_item_input(&self, hash_map: HashMap<String, AttributeValue>,
-                                             sequence_number: &String) -> UpdateItemInput {
-        let sequence_number_attribute_value =
-            self.get_string_attribute_value(sequence_number.to_string());
-
+                                             owner_id: &String, sequence_number: Option<&String>,
+                                             sink_sequence_numbers: &HashMap<String, String>)
+                                             -> UpdateItemInput {
         let mut expression_attribute_values = HashMap::new();
         expression_attribute_values.insert(
-            ":sequence_number_val".to_string(), sequence_number_attribute_value
+            ":owner_id_val".to_string(), self.get_string_attribute_value(owner_id.to_string())
         );
 
+        let mut update_clauses = vec![];
+        if sequence_number.is_some() {
+            let sequence_number_attribute_value =
+                self.get_string_attribute_value(sequence_number.unwrap().to_string());
+
+            expression_attribute_values.insert(
+                ":sequence_number_val".to_string(), sequence_number_attribute_value
+            );
+            update_clauses.push("sequence_number = :sequence_number_val".to_string());
+        }
+
+        let mut expression_attribute_names = HashMap::new();
+        for (index, (sink_name, sink_sequence_number)) in sink_sequence_numbers.iter().enumerate() {
+            expression_attribute_names.insert(
+                format!("#sink_{}", index), format!("{}{}", SINK_SEQUENCE_NUMBER_PREFIX, sink_name)
+            );
+            expression_attribute_values.insert(
+                format!(":sink_{}_val", index),
+                self.get_string_attribute_value(sink_sequence_number.to_string())
+            );
+            update_clauses.push(format!("#sink_{} = :sink_{}_val", index, index));
+        }
+
         return UpdateItemInput {
             attribute_updates: None,
-            condition_expression: None,
+            condition_expression: Some("owner_id = :owner_id_val".to_string()),
             conditional_operator: None,
             expected: None,
-            expression_attribute_names: None,
+            expression_attribute_names:
+                if expression_attribute_names.is_empty() { None } else { Some(expression_attribute_names) },
             expression_attribute_values: Some(expression_attribute_values),
             key: hash_map,
             return_consumed_capacity: None,
             return_item_collection_metrics: None,
             return_values: None,
             table_name: self.table_name.to_string(),
-            update_expression: Some("SET sequence_number = :sequence_number_val".to_string())
+            update_expression: Some(format!("SET {}", update_clauses.join(", ")))
         };
     }
 }
@@ -0,0 +1,34 @@
+use rusoto_dynamodb::*;
+use kinesis_stream::kcl_error::KclError;
+
+/// The item calls the leases, checkpoints and inventories are made of. DynamoDbLibrary retries
+/// them, the implementation only makes each call once.
+pub trait DynamoDbTable: Send + Sync {
+    fn get_item(&self, get_item_input: GetItemInput) -> Result<GetItemOutput, KclError>;
+    fn scan(&self, scan_input: ScanInput) -> Result<ScanOutput, KclError>;
+    fn put_item(&self, put_item_input: PutItemInput) -> Result<PutItemOutput, KclError>;
+    fn update_item(&self, update_item_input: UpdateItemInput) -> Result<UpdateItemOutput, KclError>;
+    fn delete_item(&self, delete_item_input: DeleteItemInput) -> Result<DeleteItemOutput, KclError>;
+}
+
+impl DynamoDbTable for DynamoDbClient {
+    fn get_item(&self, get_item_input: GetItemInput) -> Result<GetItemOutput, KclError> {
+        return DynamoDb::get_item(self, get_item_input).sync().map_err(KclError::from);
+    }
+
+    fn scan(&self, scan_input: ScanInput) -> Result<ScanOutput, KclError> {
+        return DynamoDb::scan(self, scan_input).sync().map_err(KclError::from);
+    }
+
+    fn put_item(&self, put_item_input: PutItemInput) -> Result<PutItemOutput, KclError> {
+        return DynamoDb::put_item(self, put_item_input).sync().map_err(KclError::from);
+    }
+
+    fn update_item(&self, update_item_input: UpdateItemInput) -> Result<UpdateItemOutput, KclError> {
+        return DynamoDb::update_item(self, update_item_input).sync().map_err(KclError::from);
+    }
+
+    fn delete_item(&self, delete_item_input: DeleteItemInput) -> Result<DeleteItemOutput, KclError> {
+        return DynamoDb::delete_item(self, delete_item_input).sync().map_err(KclError::from);
+    }
+}
@@ -0,0 +1,421 @@
+use rusoto_dynamodb::*;
+use dynamo_db::dynamo_db_table::DynamoDbTable;
+use kinesis_stream::kcl_error::KclError;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type Item = HashMap<String, AttributeValue>;
+
+/// A table kept in memory for the tests, keyed by a string hash key. Every call is atomic, and the
+/// condition and update expressions the library writes are evaluated the way DynamoDB does.
+#[derive(Clone)]
+pub struct InMemoryTable {
+    hash_key: String,
+    items: Arc<Mutex<HashMap<String, Item>>>,
+}
+
+impl InMemoryTable {
+    pub fn new(hash_key: &str) -> InMemoryTable {
+        InMemoryTable { hash_key: hash_key.to_string(), items: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// The stored item of the given key.
+    pub fn get(&self, key: &str) -> Option<Item> {
+        return self.items.lock().unwrap().get(key).cloned();
+    }
+
+    fn get_key(&self, key: &Item) -> Result<String, KclError> {
+        return key.get(&self.hash_key)
+            .and_then(|attribute_value| attribute_value.s.clone())
+            .ok_or(KclError::Service(format!("The key should be the string {}.", self.hash_key)));
+    }
+}
+
+impl DynamoDbTable for InMemoryTable {
+    fn get_item(&self, get_item_input: GetItemInput) -> Result<GetItemOutput, KclError> {
+        let key = self.get_key(&get_item_input.key)?;
+        return Ok(GetItemOutput { item: self.get(&key), ..Default::default() });
+    }
+
+    fn scan(&self, _scan_input: ScanInput) -> Result<ScanOutput, KclError> {
+        let items: Vec<Item> = self.items.lock().unwrap().values().cloned().collect();
+        return Ok(ScanOutput { count: Some(items.len() as i64), items: Some(items), ..Default::default() });
+    }
+
+    fn put_item(&self, put_item_input: PutItemInput) -> Result<PutItemOutput, KclError> {
+        let key = self.get_key(&put_item_input.item)?;
+        let mut items = self.items.lock().unwrap();
+        check_condition(&put_item_input.condition_expression, &put_item_input.expression_attribute_names,
+                        &put_item_input.expression_attribute_values, items.get(&key))?;
+
+        items.insert(key, put_item_input.item);
+        return Ok(PutItemOutput::default());
+    }
+
+    fn update_item(&self, update_item_input: UpdateItemInput) -> Result<UpdateItemOutput, KclError> {
+        let key = self.get_key(&update_item_input.key)?;
+        let mut items = self.items.lock().unwrap();
+        check_condition(&update_item_input.condition_expression, &update_item_input.expression_attribute_names,
+                        &update_item_input.expression_attribute_values, items.get(&key))?;
+
+        // Like DynamoDB, updating a missing item creates it.
+        let mut item = items.get(&key).cloned().unwrap_or(update_item_input.key.clone());
+        if update_item_input.update_expression.is_some() {
+            let expression = Expression {
+                tokens: tokenize(update_item_input.update_expression.as_ref().unwrap()),
+                position: 0,
+                names: &update_item_input.expression_attribute_names,
+                values: &update_item_input.expression_attribute_values,
+                item: None
+            };
+
+            expression.apply_update(&mut item)?;
+        }
+
+        items.insert(key, item);
+        return Ok(UpdateItemOutput::default());
+    }
+
+    fn delete_item(&self, delete_item_input: DeleteItemInput) -> Result<DeleteItemOutput, KclError> {
+        let key = self.get_key(&delete_item_input.key)?;
+        let mut items = self.items.lock().unwrap();
+        check_condition(&delete_item_input.condition_expression, &delete_item_input.expression_attribute_names,
+                        &delete_item_input.expression_attribute_values, items.get(&key))?;
+
+        items.remove(&key);
+        return Ok(DeleteItemOutput::default());
+    }
+}
+
+fn check_condition(condition_expression: &Option<String>, names: &Option<HashMap<String, String>>,
+                   values: &Option<HashMap<String, AttributeValue>>, item: Option<&Item>) -> Result<(), KclError> {
+    if condition_expression.is_none() {
+        return Ok(());
+    }
+
+    let mut expression = Expression {
+        tokens: tokenize(condition_expression.as_ref().unwrap()),
+        position: 0,
+        names,
+        values,
+        item
+    };
+
+    if expression.evaluate()? {
+        return Ok(());
+    }
+
+    return Err(KclError::ConditionalCheckFailed("The conditional request failed".to_string()));
+}
+
+/// Names, placeholders, parentheses, commas and the comparison and arithmetic operators.
+fn tokenize(expression: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    for character in expression.chars() {
+        if character.is_alphanumeric() || "_:#.".contains(character) {
+            token.push(character);
+            continue;
+        }
+
+        if !token.is_empty() {
+            tokens.push(token.clone());
+            token.clear();
+        }
+
+        let is_second_character = (character == '=' || character == '>') &&
+            tokens.last().map(|last| last == "<" || last == ">").unwrap_or(false);
+        if is_second_character {
+            tokens.last_mut().unwrap().push(character);
+        } else if !character.is_whitespace() {
+            tokens.push(character.to_string());
+        }
+    }
+
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    return tokens;
+}
+
+/// Evaluates the subset of the expression syntax the library uses: comparisons, attribute_exists,
+/// attribute_not_exists, AND, OR, NOT and parentheses, and SET updates with + and -.
+struct Expression<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    names: &'a Option<HashMap<String, String>>,
+    values: &'a Option<HashMap<String, AttributeValue>>,
+    item: Option<&'a Item>,
+}
+
+impl<'a> Expression<'a> {
+    fn evaluate(&mut self) -> Result<bool, KclError> {
+        let result = self.parse_or()?;
+        if self.position < self.tokens.len() {
+            return Err(self.get_syntax_error());
+        }
+
+        return Ok(result);
+    }
+
+    fn parse_or(&mut self) -> Result<bool, KclError> {
+        let mut result = self.parse_and()?;
+        while self.is_next("OR") {
+            self.position += 1;
+            let right = self.parse_and()?;
+            result = result || right;
+        }
+
+        return Ok(result);
+    }
+
+    fn parse_and(&mut self) -> Result<bool, KclError> {
+        let mut result = self.parse_condition()?;
+        while self.is_next("AND") {
+            self.position += 1;
+            let right = self.parse_condition()?;
+            result = result && right;
+        }
+
+        return Ok(result);
+    }
+
+    fn parse_condition(&mut self) -> Result<bool, KclError> {
+        let token = self.next_token()?;
+        if token == "(" {
+            let result = self.parse_or()?;
+            self.expect(")")?;
+            return Ok(result);
+        }
+
+        if token.eq_ignore_ascii_case("NOT") {
+            return Ok(!self.parse_condition()?);
+        }
+
+        if token == "attribute_exists" || token == "attribute_not_exists" {
+            self.expect("(")?;
+            let path = self.next_token()?;
+            self.expect(")")?;
+            let exists = self.get_attribute(&path).is_some();
+            return Ok(exists == (token == "attribute_exists"));
+        }
+
+        let left = self.get_operand(&token)?;
+        let comparator = self.next_token()?;
+        let right_token = self.next_token()?;
+        let right = self.get_operand(&right_token)?;
+
+        // A missing attribute compares to nothing.
+        if left.is_none() || right.is_none() {
+            return Ok(false);
+        }
+
+        let ordering = compare(&left.unwrap(), &right.unwrap());
+        return match comparator.as_str() {
+            "=" => Ok(ordering == Some(Ordering::Equal)),
+            "<>" => Ok(ordering != Some(Ordering::Equal)),
+            "<" => Ok(ordering == Some(Ordering::Less)),
+            "<=" => Ok(ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal)),
+            ">" => Ok(ordering == Some(Ordering::Greater)),
+            ">=" => Ok(ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal)),
+            _ => Err(self.get_syntax_error()),
+        };
+    }
+
+    /// SET path = operand [+|- operand], ...
+    fn apply_update(mut self, item: &mut Item) -> Result<(), KclError> {
+        if !self.next_token()?.eq_ignore_ascii_case("SET") {
+            return Err(self.get_syntax_error());
+        }
+
+        loop {
+            let path = self.next_token()?;
+            self.expect("=")?;
+            let operand = self.next_token()?;
+            let mut value = self.get_update_operand(item, &operand)?;
+            if self.is_next("+") || self.is_next("-") {
+                let operator = self.next_token()?;
+                let operand = self.next_token()?;
+                let other_value = self.get_update_operand(item, &operand)?;
+                value = add_numbers(&value, &other_value, operator == "-")
+                    .ok_or(KclError::Service(format!("{} {} {} needs two numbers.", path, operator, operand)))?;
+            }
+
+            let name = self.get_name(&path);
+            item.insert(name, value);
+            if !self.is_next(",") {
+                break;
+            }
+
+            self.position += 1;
+        }
+
+        if self.position < self.tokens.len() {
+            return Err(self.get_syntax_error());
+        }
+
+        return Ok(());
+    }
+
+    fn get_update_operand(&self, item: &Item, token: &String) -> Result<AttributeValue, KclError> {
+        if token.starts_with(':') {
+            return self.get_value(token);
+        }
+
+        return item.get(&self.get_name(token)).cloned()
+            .ok_or(KclError::Service(format!("The attribute {} doesn't exist.", token)));
+    }
+
+    fn get_operand(&self, token: &String) -> Result<Option<AttributeValue>, KclError> {
+        if token.starts_with(':') {
+            return self.get_value(token).map(Some);
+        }
+
+        return Ok(self.get_attribute(token));
+    }
+
+    fn get_value(&self, token: &String) -> Result<AttributeValue, KclError> {
+        return self.values.as_ref()
+            .and_then(|values| values.get(token).cloned())
+            .ok_or(KclError::Service(format!("The value {} isn't defined.", token)));
+    }
+
+    fn get_attribute(&self, path: &String) -> Option<AttributeValue> {
+        return self.item.and_then(|item| item.get(&self.get_name(path)).cloned());
+    }
+
+    fn get_name(&self, path: &String) -> String {
+        if path.starts_with('#') {
+            let name = self.names.as_ref().and_then(|names| names.get(path).cloned());
+            if name.is_some() {
+                return name.unwrap();
+            }
+        }
+
+        return path.to_string();
+    }
+
+    fn is_next(&self, expected: &str) -> bool {
+        return self.tokens.get(self.position).map(|token| token.eq_ignore_ascii_case(expected)).unwrap_or(false);
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), KclError> {
+        if !self.is_next(expected) {
+            return Err(self.get_syntax_error());
+        }
+
+        self.position += 1;
+        return Ok(());
+    }
+
+    fn next_token(&mut self) -> Result<String, KclError> {
+        let token = self.tokens.get(self.position).cloned();
+        if token.is_none() {
+            return Err(self.get_syntax_error());
+        }
+
+        self.position += 1;
+        return Ok(token.unwrap());
+    }
+
+    fn get_syntax_error(&self) -> KclError {
+        return KclError::Service(format!("Invalid expression {} at token {}.", self.tokens.join(" "), self.position));
+    }
+}
+
+/// Strings, numbers, booleans and nulls compare to their own type only.
+fn compare(left: &AttributeValue, right: &AttributeValue) -> Option<Ordering> {
+    if left.s.is_some() && right.s.is_some() {
+        return Some(left.s.cmp(&right.s));
+    }
+
+    if left.n.is_some() && right.n.is_some() {
+        let left_number: Option<f64> = left.n.as_ref().unwrap().parse().ok();
+        let right_number: Option<f64> = right.n.as_ref().unwrap().parse().ok();
+        return left_number.and_then(|left_number| right_number.and_then(|right_number| {
+            left_number.partial_cmp(&right_number)
+        }));
+    }
+
+    if left.bool.is_some() && right.bool.is_some() {
+        return Some(left.bool.cmp(&right.bool));
+    }
+
+    if left.null.is_some() && right.null.is_some() {
+        return Some(Ordering::Equal);
+    }
+
+    return None;
+}
+
+fn add_numbers(left: &AttributeValue, right: &AttributeValue, is_subtraction: bool) -> Option<AttributeValue> {
+    let left_number: i64 = left.n.as_ref().and_then(|number| number.parse().ok())?;
+    let right_number: i64 = right.n.as_ref().and_then(|number| number.parse().ok())?;
+    let result = if is_subtraction { left_number - right_number } else { left_number + right_number };
+
+    return Some(AttributeValue { n: Some(result.to_string()), ..Default::default() });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_string(value: &str) -> AttributeValue {
+        AttributeValue { s: Some(value.to_string()), ..Default::default() }
+    }
+
+    fn get_item(shard_id: &str, owner_id: &str) -> Item {
+        let mut item = HashMap::new();
+        item.insert("shard_id".to_string(), get_string(shard_id));
+        item.insert("owner_id".to_string(), get_string(owner_id));
+        item.insert("number_of_owners_switched".to_string(), AttributeValue { n: Some("1".to_string()), ..Default::default() });
+        return item;
+    }
+
+    #[test]
+    fn honors_the_condition_of_a_put() {
+        let table = InMemoryTable::new("shard_id");
+        let put_item_input = PutItemInput {
+            item: get_item("shard-1", "worker-1"),
+            condition_expression: Some("attribute_not_exists(shard_id)".to_string()),
+            ..Default::default()
+        };
+
+        assert!(table.put_item(put_item_input.clone()).is_ok());
+        match table.put_item(put_item_input) {
+            Err(KclError::ConditionalCheckFailed(_)) => {}
+            result => panic!("expected a failed condition, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn applies_an_update_only_when_its_condition_holds() {
+        let table = InMemoryTable::new("shard_id");
+        table.put_item(PutItemInput { item: get_item("shard-1", "worker-1"), ..Default::default() }).unwrap();
+
+        let mut key = HashMap::new();
+        key.insert("shard_id".to_string(), get_string("shard-1"));
+        let mut values = HashMap::new();
+        values.insert(":owner".to_string(), get_string("worker-2"));
+        values.insert(":current_owner".to_string(), get_string("worker-1"));
+        values.insert(":one".to_string(), AttributeValue { n: Some("1".to_string()), ..Default::default() });
+        let update_item_input = UpdateItemInput {
+            key,
+            condition_expression: Some("(owner_id = :current_owner) AND attribute_exists(#shard)".to_string()),
+            expression_attribute_names: Some(vec![("#shard".to_string(), "shard_id".to_string())].into_iter().collect()),
+            expression_attribute_values: Some(values),
+            update_expression:
+                Some("SET owner_id = :owner, number_of_owners_switched = number_of_owners_switched + :one".to_string()),
+            ..Default::default()
+        };
+
+        assert!(table.update_item(update_item_input.clone()).is_ok());
+        let item = table.get("shard-1").unwrap();
+        assert_eq!(item["owner_id"].s, Some("worker-2".to_string()));
+        assert_eq!(item["number_of_owners_switched"].n, Some("2".to_string()));
+
+        assert!(table.update_item(update_item_input).is_err());
+    }
+}
@@ -1 +1,5 @@
-pub mod dynamo_db_library;
\ No newline at end of file
+pub mod dynamo_db_library;
+pub mod dynamo_db_table;
+#[cfg(test)]
+pub mod in_memory_table;
+pub mod table_config;
@@ -0,0 +1,41 @@
+use config::env_config::{get_env_var_or, get_optional_env_var, get_parsed_env_var_or};
+
+/// How a created table is billed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TableCapacity {
+    OnDemand,
+    Provisioned { read_capacity_units: i64, write_capacity_units: i64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct LeaseTableConfig {
+    pub table_name: String,
+    pub capacity: TableCapacity,
+    pub active_timeout_seconds: u64,
+}
+
+impl LeaseTableConfig {
+    /// KCL_LEASE_TABLE_NAME, by default KCL_APPLICATION_NAME or else the given name, so every
+    /// application reading a stream keeps its own leases. KCL_LEASE_TABLE_BILLING_MODE
+    /// (on_demand | provisioned), KCL_LEASE_TABLE_READ_CAPACITY and KCL_LEASE_TABLE_WRITE_CAPACITY
+    /// (default 10) apply when the table is created, KCL_LEASE_TABLE_ACTIVE_TIMEOUT_SECONDS
+    /// (default 300) bounds the wait for it to be ACTIVE.
+    pub fn from_env(default_table_name: &str) -> LeaseTableConfig {
+        let application_name = get_env_var_or("KCL_APPLICATION_NAME", default_table_name);
+        let table_name = get_optional_env_var("KCL_LEASE_TABLE_NAME").unwrap_or(application_name);
+
+        let mut capacity = TableCapacity::OnDemand;
+        if get_env_var_or("KCL_LEASE_TABLE_BILLING_MODE", "on_demand").to_lowercase() == "provisioned" {
+            capacity = TableCapacity::Provisioned {
+                read_capacity_units: get_parsed_env_var_or("KCL_LEASE_TABLE_READ_CAPACITY", 10),
+                write_capacity_units: get_parsed_env_var_or("KCL_LEASE_TABLE_WRITE_CAPACITY", 10)
+            };
+        }
+
+        LeaseTableConfig {
+            table_name,
+            capacity,
+            active_timeout_seconds: get_parsed_env_var_or("KCL_LEASE_TABLE_ACTIVE_TIMEOUT_SECONDS", 300)
+        }
+    }
+}
@@ -4,12 +4,22 @@ use rusoto_core::credential::{AwsCredentials, DefaultCredentialsProvider, ChainP
 use rusoto_kinesis::*;
 use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
 use dynamo_db::dynamo_db_library::DynamoDbLibrary;
-use std::collections::HashMap;
+use dynamo_db::table_config::TableCapacity;
+use kinesis_stream::kcl_error::KclError;
+use kinesis_stream::lease_balancer::LeaseBalancer;
+use kinesis_stream::polling_policy::PollingPolicy;
+use kinesis_stream::worker_registry::WorkerRegistry;
+use retry::retry_policy::{ClassifiedError, ErrorClass, RetryPolicy};
+use rusoto_dynamodb::AttributeValue;
+use sink::sink_fan_out::SinkFanOut;
+use sink::stdout_sink::StdoutSink;
+use sink::record_sink::RecordSink;
+use transform::record_transform::RecordContext;
+use transform::transform_pipeline::TransformPipeline;
+use std::collections::{HashMap, HashSet};
 use std::thread;
-use std::time;
 use std::time::{Duration, Instant};
 use rusoto_s3::*;
-use tokio_core::reactor;
 use chrono::{DateTime, TimeZone, NaiveDateTime, Utc};
 use uuid::Uuid;
 
@@ -19,23 +29,62 @@ use hyper::rt::{self, Future, Stream};
 use hyper::client::HttpConnector;
 use rusoto_core::request::HttpClient;
 use rusoto_core::DispatchSignedRequest;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use rusoto_credential::AutoRefreshingProvider;
+use futures::future::{self, Loop};
+use tokio;
+use tokio::timer::{Delay, Interval};
+use tokio_threadpool::blocking;
+
+pub const STREAM_NAME_STR: &str = "kinesis_stream_name";
+const LEASE_CHECK_INTERVAL_SECONDS: u64 = 10;
+const IDLE_SHARD_CHECK_INTERVAL_SECONDS: u64 = 300;
+const OWNERSHIP_CHECK_READS: u32 = 10;
+
+type ShardFuture<T> = Box<Future<Item = T, Error = ()> + Send>;
+
+/// Where a shard task is in its shard.
+struct ShardReadState {
+    shard_iterator: String,
+    /// The last delivered record, or the checkpoint the shard was taken at. An expired
+    /// iterator is replaced by one starting after it.
+    sequence_number: Option<String>,
+    sink_sequence_numbers: HashMap<String, String>,
+    number_of_failures: u32,
+    failing_since: Option<Instant>,
+    number_of_reads: u32,
+    last_read_at: Instant,
+}
 
-const STREAM_NAME_STR: &str = "kinesis_stream_name";
+/// Runs blocking work (DynamoDB, the sinks) on the runtime's blocking pool, so it doesn't
+/// hold up the shard tasks waiting on Kinesis. Only the Kinesis reads and the timers are
+/// asynchronous, the DynamoDB, S3 and sink calls still wait on a blocking thread each.
+fn run_blocking<T, F>(function: F) -> ShardFuture<T>
+    where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+    let mut function = Some(function);
+    Box::new(future::poll_fn(move || {
+        blocking(|| (function.take().unwrap())()).map_err(|error| {
+            eprintln!("The blocking pool is unavailable. - {}", error);
+        })
+    }))
+}
 
 pub struct KinesisStreamLibrary {
     stream_name: String,
     dynamo_db_library: DynamoDbLibrary,
-    s3_client: S3Client,
+    sink_fan_out: SinkFanOut,
+    transform_pipeline: TransformPipeline,
     kinesis_client: Arc<KinesisClient>,
+    retry_policy: RetryPolicy,
+    polling_policy: PollingPolicy,
     is_debug_enabled: bool,
 }
 
 // TODO .. Update the code to handle if the iam_role_arn is given so it's a multi account setup, otherwise follow the normal AWS Credentials setup.
 impl KinesisStreamLibrary {
-    pub fn new(iam_role_arn: String, dynamo_db_library: DynamoDbLibrary,
-               s3_client: S3Client, is_debug_enabled: bool) -> KinesisStreamLibrary {
+    pub fn new(iam_role_arn: String, stream_name: String, dynamo_db_library: DynamoDbLibrary,
+               sink_fan_out: SinkFanOut, transform_pipeline: TransformPipeline,
+               is_debug_enabled: bool) -> KinesisStreamLibrary {
         let region = Region::EuWest1;
         let sts = StsClient::new(region.clone());
         let provider =
@@ -54,15 +103,27 @@ impl KinesisStreamLibrary {
             ));
 
         KinesisStreamLibrary {
-            stream_name: STREAM_NAME_STR.to_string(),
+            stream_name,
             dynamo_db_library,
-            s3_client,
+            sink_fan_out,
+            transform_pipeline,
             kinesis_client,
+            retry_policy: RetryPolicy::from_env(),
+            polling_policy: PollingPolicy::from_env(),
             is_debug_enabled
         }
     }
 
-    pub fn get_stream_shards(&self) -> Option<Vec<Shard>> {
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        return self.retry_policy.clone();
+    }
+
+    /// The lease table is keyed by shard_id, it's created on the first start.
+    pub fn ensure_lease_table(&self, capacity: &TableCapacity, active_timeout_seconds: u64) -> Result<(), KclError> {
+        return self.dynamo_db_library.ensure_table("shard_id", capacity, active_timeout_seconds);
+    }
+
+    pub fn get_stream_shards(&self) -> Result<Vec<Shard>, KclError> {
         let mut all_stream_shards: Vec<Shard> = Vec::new();
         let mut has_more_shards = true;
         let mut exclusive_shard_id = None;
@@ -75,21 +136,9 @@ impl KinesisStreamLibrary {
                     stream_name: self.stream_name.to_string()
                 };
 
-            let describe_stream_request =
-                self.kinesis_client.describe_stream(describe_stream_input).sync();
+            let describe_stream_output =
+                self.kinesis_client.describe_stream(describe_stream_input).sync()?;
 
-            if describe_stream_request.is_err() {
-                println!("{}",
-                         format!(
-                             "Couldn't describe stream. {}",
-                             describe_stream_request.err().unwrap()
-                         )
-                );
-
-                return None;
-            }
-
-            let describe_stream_output = describe_stream_request.unwrap();
             let mut stream_shards = describe_stream_output.stream_description.shards;
 
             has_more_shards = describe_stream_output.stream_description.has_more_shards.clone();
@@ -101,227 +150,647 @@ impl KinesisStreamLibrary {
             all_stream_shards.append(&mut stream_shards);
         }
 
-        return Some(all_stream_shards);
+        return Ok(all_stream_shards);
     }
 
-    /// Release the shards that are idle, no one is reading from it.
-    pub fn cut_the_loose_for_the_idle_shards(&self, shards: &Vec<Shard>) {
-        let mut old_stream_shards_sequence_number_map = HashMap::new();
+    /// Release the shards that are idle, no one is reading from it: the ones whose sequence number
+    /// didn't change since the previous check. Returns the sequence numbers for the next check.
+    pub fn release_idle_shards(&self, shards: &Vec<Shard>,
+                               previous_sequence_numbers: &HashMap<String, String>) -> HashMap<String, String> {
+        let mut stream_shards_sequence_number_map = HashMap::new();
 
+        // TODO .. check if the record is empty - shard owner with empty shard id.
         for shard in shards {
             let shard_sequence_number =
                 self.dynamo_db_library.get_owned_shard_sequence_number_given_shard_id(
                     &(shard.shard_id)
                 );
 
-            if shard_sequence_number.is_some() {
-                old_stream_shards_sequence_number_map.insert(
-                    shard.shard_id.to_string(),
-                    shard_sequence_number.unwrap().to_string()
-                );
+            if shard_sequence_number.is_err() {
+                eprintln!("Can't read the lease of shard {}. {}", shard.shard_id, shard_sequence_number.unwrap_err());
+                continue;
             }
-        }
-
-        /// Wait for 5 minutes and re-check that the sequence number changed.
-        /// If doesn't change for 5 minutes, this shard should be assigned to a new thread.
-        let sleep_time = time::Duration::from_millis(300000);
-        thread::sleep(sleep_time);
-
-        // TODO .. check if the record is empty - shard owner with empty shard id.
-        for shard in shards {
-            let shard_sequence_number =
-                self.dynamo_db_library.get_owned_shard_sequence_number_given_shard_id(
-                    &(shard.shard_id)
-                );
 
+            let shard_sequence_number = shard_sequence_number.unwrap();
             if shard_sequence_number.is_some() {
-                let old_sequence_number =
-                    old_stream_shards_sequence_number_map.get(&shard.shard_id);
-
                 let shard_sequence_number_string = shard_sequence_number.unwrap().to_string();
+                let old_sequence_number = previous_sequence_numbers.get(&shard.shard_id);
                 if old_sequence_number.is_some() &&
                     shard_sequence_number_string == old_sequence_number.unwrap().to_string() {
-                    self.dynamo_db_library.release_shard_from_owner(&shard.shard_id);
+                    match self.dynamo_db_library.release_idle_shard(&shard.shard_id, &shard_sequence_number_string) {
+                        Ok(_) => {}
+                        // The shard moved on since it was read.
+                        Err(KclError::ConditionalCheckFailed(_)) => {}
+                        Err(error) => eprintln!("Can't release idle shard {}. {}", shard.shard_id, error),
+                    }
+                } else {
+                    stream_shards_sequence_number_map.insert(shard.shard_id.to_string(), shard_sequence_number_string);
                 }
             }
         }
+
+        return stream_shards_sequence_number_map;
     }
 
-    pub fn read_from_given_shard(&self, worker_id: &String, shard_id: &String) {
-        let mut sequence_number: Option<String> = None;
-        let item_output =
-            self.dynamo_db_library.get_db_full_record_using_shard_id(shard_id);
+    /// The worker: takes its share of the shards' leases every few seconds and reads each owned shard
+    /// in its own task, while it sends its heartbeat and the idle shards are released every 5 minutes.
+    pub fn run_worker(kcl: Arc<KinesisStreamLibrary>, worker_registry: Arc<WorkerRegistry>, shards: Vec<Shard>,
+                      lease_balancer: LeaseBalancer) -> ShardFuture<()> {
+        let worker_id = worker_registry.get_worker_id();
+        let lease_kcl = kcl.clone();
+        let lease_balancer = Arc::new(lease_balancer);
+        let lease_registry = worker_registry.clone();
+        let lease_shards = shards.clone();
+        let processed_shards: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+        let heartbeat_shards = processed_shards.clone();
+        let lease_coordinator =
+            Interval::new(Instant::now(), Duration::from_secs(LEASE_CHECK_INTERVAL_SECONDS))
+                .map_err(|error| eprintln!("The lease timer failed. - {}", error))
+                .for_each(move |_| {
+                    KinesisStreamLibrary::take_free_shards(
+                        lease_kcl.clone(), worker_id.to_string(), &lease_shards, processed_shards.clone(),
+                        lease_balancer.clone(), lease_registry.clone()
+                    )
+                });
+
+        let heartbeat_interval = Duration::from_secs(worker_registry.get_heartbeat_interval_seconds());
+        let heartbeat =
+            Interval::new(Instant::now(), heartbeat_interval)
+                .map_err(|error| eprintln!("The heartbeat timer failed. - {}", error))
+                .for_each(move |_| {
+                    let heartbeat_registry = worker_registry.clone();
+                    let owned_shards: Vec<String> = heartbeat_shards.lock().unwrap().iter().cloned().collect();
+                    run_blocking(move || {
+                        let heartbeat = heartbeat_registry.heartbeat(owned_shards);
+                        if heartbeat.is_err() {
+                            eprintln!("Can't send the worker heartbeat. {}", heartbeat.unwrap_err());
+                        }
+                    })
+                });
+
+        let previous_sequence_numbers = Arc::new(Mutex::new(HashMap::new()));
+        let idle_shards_releaser =
+            Interval::new(Instant::now(), Duration::from_secs(IDLE_SHARD_CHECK_INTERVAL_SECONDS))
+                .map_err(|error| eprintln!("The idle shards timer failed. - {}", error))
+                .for_each(move |_| {
+                    let releaser_kcl = kcl.clone();
+                    let releaser_shards = shards.clone();
+                    let previous_sequence_numbers = previous_sequence_numbers.clone();
+                    run_blocking(move || {
+                        let mut previous_sequence_numbers = previous_sequence_numbers.lock().unwrap();
+                        let sequence_numbers =
+                            releaser_kcl.release_idle_shards(&releaser_shards, &previous_sequence_numbers);
+                        *previous_sequence_numbers = sequence_numbers;
+                    })
+                });
+
+        return Box::new(lease_coordinator.join3(heartbeat, idle_shards_releaser).map(|_| ()));
+    }
 
-        if item_output.is_none() {
-            println!("No records for shard {} - Adding one for Worker {}.", shard_id, worker_id);
-            let added = self.dynamo_db_library.add_new_shard_record(shard_id, worker_id);
-            if !added {
-                println!("Can't add new shard record.");
-                return;
+    /// Takes the leases the balancer plans for this worker: free shards first, then shards taken over
+    /// from dead or overloaded workers, whose tasks stop at their next ownership check.
+    fn take_free_shards(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shards: &Vec<Shard>,
+                        processed_shards: Arc<Mutex<HashSet<String>>>, lease_balancer: Arc<LeaseBalancer>,
+                        worker_registry: Arc<WorkerRegistry>) -> ShardFuture<()> {
+        let shard_ids: Vec<String> = shards.iter().map(|shard| shard.shard_id.to_string()).collect();
+        let lease_processed_shards = processed_shards.clone();
+        let lease_kcl = kcl.clone();
+        let lease_worker_id = worker_id.to_string();
+        let leases = run_blocking(move || {
+            let shard_leases = lease_kcl.dynamo_db_library.get_shard_leases();
+            if shard_leases.is_err() {
+                eprintln!("Can't read the shard leases. {}", shard_leases.unwrap_err());
+                return vec![];
             }
-        } else {
-            let item_option = item_output.unwrap().item;
-            if item_option.is_none() {
-                println!("Empty shard {} record - Adding one for Worker {}.", shard_id, worker_id);
-                let added = self.dynamo_db_library.add_new_shard_record(shard_id, worker_id);
-                if !added {
-                    println!("Can't add new shard record.");
-                    return;
+
+            // Without the registry every lease owner counts as alive.
+            let active_worker_ids: Option<Vec<String>> = worker_registry.get_active_workers().ok().map(|active_workers| {
+                active_workers.into_iter().map(|active_worker| active_worker.worker_id).collect()
+            });
+
+            let lease_plan = lease_balancer.plan(
+                &lease_worker_id, &shard_ids, &shard_leases.unwrap(), active_worker_ids.as_ref()
+            );
+            let mut leases = vec![];
+            for shard_id in lease_plan.shards_to_take {
+                if lease_processed_shards.lock().unwrap().contains(&shard_id) {
+                    continue;
                 }
-            } else {
-                let item = item_option.clone().unwrap();
-                let owner_id = item.get("owner_id");
-                if owner_id.is_none() || owner_id.unwrap().clone().s.is_none() {
-                    // No owner for this shard.
-                    println!("Update shard owner for shard {} - Worker {}.", shard_id, worker_id);
-                    let updated = self.dynamo_db_library.update_shard_owner(shard_id, worker_id);
-                    if !updated {
-                        println!("Shard {} owner can't be updated. ", shard_id);
-                        return;
-                    }
-                } else {
-                    println!("Shard {} is already owned.", shard_id);
-                    return;
+
+                match lease_kcl.take_shard_lease(&lease_worker_id, &shard_id) {
+                    Ok(Some(checkpoint)) => leases.push((shard_id, checkpoint)),
+                    Ok(None) => {}
+                    // Another worker won the shard.
+                    Err(KclError::ConditionalCheckFailed(_)) => eprintln!("Shard {} owner changed in the meantime.", shard_id),
+                    Err(error) => eprintln!("Can't take the lease of shard {}. {}", shard_id, error),
                 }
+            }
 
-                sequence_number = self.dynamo_db_library.extract_sequence_number_from_record(item_option.clone());
+            for (shard_id, owner_id) in lease_plan.shards_to_steal {
+                let stolen = lease_kcl.dynamo_db_library.steal_shard_lease(&shard_id, &owner_id, &lease_worker_id);
+                if stolen.is_err() {
+                    eprintln!("Can't take shard {} from worker {}. {}", shard_id, owner_id, stolen.unwrap_err());
+                    continue;
+                }
+
+                eprintln!("Worker {} took shard {} from worker {}.", lease_worker_id, shard_id, owner_id);
+                let item = lease_kcl.dynamo_db_library.get_db_full_record_using_shard_id(&shard_id);
+                if item.is_err() {
+                    // Without its checkpoint the shard can't be read, it's taken again on the next round.
+                    eprintln!("Can't read the checkpoint of shard {}. {}", shard_id, item.unwrap_err());
+                    lease_kcl.release_shard(&shard_id, &lease_worker_id);
+                    continue;
+                }
+
+                leases.push((shard_id, lease_kcl.get_shard_checkpoint(item.unwrap())));
+            }
+
+            leases
+        });
+
+        return Box::new(leases.map(move |leases| {
+            for (shard_id, (sequence_number, sink_sequence_numbers)) in leases {
+                processed_shards.lock().unwrap().insert(shard_id.to_string());
+                let finished_shards = processed_shards.clone();
+                let finished_shard_id = shard_id.to_string();
+                tokio::spawn(
+                    KinesisStreamLibrary::read_from_given_shard(
+                        kcl.clone(), worker_id.to_string(), shard_id, sequence_number, sink_sequence_numbers
+                    ).then(move |_| {
+                        finished_shards.lock().unwrap().remove(&finished_shard_id);
+                        Ok(())
+                    })
+                );
             }
+        }));
+    }
+
+    /// Takes the lease of the shard if it's free, returning its checkpoint - the shard sequence number
+    /// and the sinks' sequence numbers. None if the shard is already owned, ConditionalCheckFailed if
+    /// another worker took it first.
+    fn take_shard_lease(&self, worker_id: &String, shard_id: &String)
+                        -> Result<Option<(Option<String>, HashMap<String, String>)>, KclError> {
+        let item_option = self.dynamo_db_library.get_db_full_record_using_shard_id(shard_id)?;
+        if item_option.is_none() {
+            eprintln!("No records for shard {} - Adding one for Worker {}.", shard_id, worker_id);
+            self.dynamo_db_library.add_new_shard_record(shard_id, worker_id)?;
+
+            // A new lease row, the shard is read from its start.
+            return Ok(Some((None, HashMap::new())));
         }
 
-        self.initialize_shard_stream_processing(sequence_number, shard_id, worker_id);
+        let item = item_option.clone().unwrap();
+        let owner_id = item.get("owner_id");
+        if owner_id.is_some() && owner_id.unwrap().clone().s.is_some() {
+            eprintln!("Shard {} is already owned.", shard_id);
+            return Ok(None);
+        }
+
+        // No owner for this shard.
+        eprintln!("Update shard owner for shard {} - Worker {}.", shard_id, worker_id);
+        self.dynamo_db_library.update_shard_owner(shard_id, worker_id)?;
+
+        return Ok(Some(self.get_shard_checkpoint(item_option)));
     }
 
-    fn initialize_shard_stream_processing(&self, sequence_number: Option<String>,
-                                          shard_id: &String, worker_id: &String) {
-        let is_valid = self.validate_shard_owner_with_current_thread(shard_id, worker_id);
-        if !is_valid {
-            println!("Owner {} is trying to read from an already owned shard.", worker_id.to_string());
-            return;
+    /// The shard sequence number and the sinks' sequence numbers of the lease row.
+    fn get_shard_checkpoint(&self, item_option: Option<HashMap<String, AttributeValue>>)
+                            -> (Option<String>, HashMap<String, String>) {
+        if item_option.is_none() {
+            return (None, HashMap::new());
         }
 
-        let shard_iterator = self.get_shard_iterator_input(shard_id, sequence_number);
-        let shard_iterator_output =
-            self.kinesis_client
-                .get_shard_iterator(shard_iterator)
-                .sync()
-                .expect("No Shard Iterator.");
-
-        // Read records from Kinesis Stream
-        self.read_from_kinesis_stream(
-            shard_id,
-            shard_iterator_output.shard_iterator.unwrap().to_string(),
-            1000,
-            worker_id
-        );
+        let item = item_option.unwrap();
+        let sequence_number = self.dynamo_db_library.extract_sequence_number_from_record(&item);
+        let mut sink_sequence_numbers = self.dynamo_db_library.extract_sink_sequence_numbers_from_record(&item);
+
+        // The shard is read again at its checkpoint, which every sink has already acknowledged.
+        if sequence_number.is_some() {
+            for sink_name in self.sink_fan_out.get_sink_names() {
+                if !sink_sequence_numbers.contains_key(&sink_name) {
+                    sink_sequence_numbers.insert(sink_name, sequence_number.clone().unwrap());
+                }
+            }
+        }
+
+        return (sequence_number, sink_sequence_numbers);
+    }
+
+    /// Gives the lease up, unless another worker took it in the meantime.
+    fn release_shard(&self, shard_id: &String, worker_id: &String) {
+        match self.dynamo_db_library.release_shard_from_owner(shard_id, worker_id) {
+            Ok(_) => {}
+            Err(KclError::ConditionalCheckFailed(_)) => eprintln!("Shard {} owner changed in the meantime.", shard_id),
+            Err(error) => eprintln!("Can't release shard {}. {}", shard_id, error),
+        }
     }
 
-    fn read_from_kinesis_stream(&self, shard_id: &String, mut shard_iterator_string: String,
-                                mut number_of_records_limit: i64, worker_id: &String) {
-        let mut number_of_retries = 1;
-        let mut number_of_reads = 0;
+    /// Reads the owned shard from its checkpoint until it's closed, the lease is lost or the
+    /// records can't be delivered.
+    fn read_from_given_shard(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shard_id: String,
+                             sequence_number: Option<String>,
+                             sink_sequence_numbers: HashMap<String, String>) -> ShardFuture<()> {
+        let shard_iterator_output = KinesisStreamLibrary::get_shard_iterator(&kcl, &shard_id, sequence_number.clone());
+
+        return Box::new(shard_iterator_output.then(move |shard_iterator| -> ShardFuture<()> {
+            if shard_iterator.is_err() || shard_iterator.clone().unwrap().is_none() {
+                // The lease is given up either way, it's taken again once the shard can be read.
+                let error = shard_iterator.err().map(|error| error.to_string()).unwrap_or(String::new());
+                eprintln!("No Shard Iterator for shard {} - releasing it. {}", shard_id, error);
+                let release_kcl = kcl.clone();
+                let release_worker_id = worker_id.to_string();
+                return run_blocking(move || {
+                    release_kcl.release_shard(&shard_id, &release_worker_id);
+                });
+            }
+
+            let state = ShardReadState {
+                shard_iterator: shard_iterator.unwrap().unwrap(),
+                sequence_number,
+                sink_sequence_numbers,
+                number_of_failures: 0,
+                failing_since: None,
+                number_of_reads: 0,
+                last_read_at: Instant::now()
+            };
 
-        let mut client = Client::new();
-        let mut reactor = reactor::Core::new().unwrap();
+            Box::new(future::loop_fn(state, move |state| {
+                KinesisStreamLibrary::read_next_records(kcl.clone(), worker_id.to_string(), shard_id.to_string(), state)
+            }))
+        }));
+    }
 
-        loop {
-            let start_time = Instant::now();
+    fn read_next_records(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shard_id: String,
+                         mut state: ShardReadState) -> ShardFuture<Loop<(), ShardReadState>> {
+        state.number_of_reads = state.number_of_reads + 1;
+        let is_owner = if state.number_of_reads % OWNERSHIP_CHECK_READS == 0 {
+            let owner_kcl = kcl.clone();
+            let (owner_shard_id, owner_worker_id) = (shard_id.to_string(), worker_id.to_string());
+            run_blocking(move || owner_kcl.validate_shard_owner_with_current_thread(&owner_shard_id, &owner_worker_id))
+        } else {
+            Box::new(future::ok(Ok(true)))
+        };
 
-            number_of_reads = number_of_reads + 1;
-            if number_of_reads == 10 {
-                number_of_reads = 0;
-                let is_valid = self.validate_shard_owner_with_current_thread(shard_id, worker_id);
-                if !is_valid {
-                    println!("Owner {} is trying to read from an already owned shard.", worker_id);
-                    return;
+        return Box::new(is_owner.and_then(move |is_owner| -> ShardFuture<Loop<(), ShardReadState>> {
+            match is_owner {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Owner {} is trying to read from an already owned shard.", worker_id);
+                    return Box::new(future::ok(Loop::Break(())));
                 }
+                Err(KclError::NotFound(_)) => {
+                    eprintln!("The lease of shard {} is gone - stop reading it.", shard_id);
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                // The lease can't be read right now, it's checked again after the next reads.
+                Err(error) => eprintln!("Can't check the owner of shard {}. {}", shard_id, error),
             }
 
-            let records_input = GetRecordsInput { limit: Some(number_of_records_limit), shard_iterator: (*shard_iterator_string).to_string() };
-            let records_result = self.kinesis_client.get_records(records_input).sync();
+            state.last_read_at = Instant::now();
+            let records_input = GetRecordsInput {
+                limit: Some(kcl.polling_policy.max_records),
+                shard_iterator: state.shard_iterator.to_string()
+            };
+            let records_output = kcl.kinesis_client.get_records(records_input);
+            Box::new(records_output.then(move |records_result| {
+                KinesisStreamLibrary::handle_records_result(kcl, worker_id, shard_id, state, records_result)
+            }))
+        }));
+    }
 
-            if records_result.is_err() {
-                if self.is_debug_enabled {
-                    println!(
-                        "Error while reading {} records from stream. Number of retries: {} - {}",
-                        number_of_records_limit, number_of_retries,
-                        format!("{:?}", records_result.unwrap_err())
-                    );
-                }
+    fn handle_records_result(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shard_id: String,
+                             mut state: ShardReadState,
+                             records_result: Result<GetRecordsOutput, GetRecordsError>)
+                             -> ShardFuture<Loop<(), ShardReadState>> {
+        if records_result.is_err() {
+            let error = KclError::from(records_result.unwrap_err());
+            return KinesisStreamLibrary::handle_read_error(kcl, worker_id, shard_id, state, error);
+        }
 
-                number_of_retries = number_of_retries + 1;
-                if number_of_retries > 10 {
-                    self.dynamo_db_library.release_shard_from_owner(shard_id);
-                    return;
-                }
+        if kcl.is_debug_enabled {
+            eprintln!("Reading records from shard {} Successfully.", shard_id);
+        }
+
+        state.number_of_failures = 0;
+        state.failing_since = None;
+        let GetRecordsOutput { records, next_shard_iterator, millis_behind_latest, .. } = records_result.unwrap();
+        let number_of_records = records.len();
+        if records.is_empty() {
+            if next_shard_iterator.is_none() {
+                eprintln!("No more records in shard {}.", shard_id);
+                return Box::new(future::ok(Loop::Break(())));
+            }
+
+            state.shard_iterator = next_shard_iterator.unwrap();
+            let delay = kcl.polling_policy.get_delay(0, millis_behind_latest, state.last_read_at.elapsed());
+            return KinesisStreamLibrary::continue_after(state, delay);
+        }
 
-                let back_off_time = self.get_back_off_milli(number_of_retries) * 100;
-                let sleep_time = time::Duration::from_millis(back_off_time);
-                thread::sleep(sleep_time);
-            } else {
-                if self.is_debug_enabled {
-                    println!("Reading {} records from Kinesis stream Successfully.", number_of_records_limit);
+        let last_sequence_number = records[records.len() - 1].sequence_number.to_string();
+        let mut sink_sequence_numbers = state.sink_sequence_numbers.clone();
+        let delivery_kcl = kcl.clone();
+        let delivery_worker_id = worker_id.to_string();
+        let delivery = run_blocking(move || {
+            let is_delivered =
+                delivery_kcl.deliver_records(&shard_id, &delivery_worker_id, records, &mut sink_sequence_numbers);
+            (is_delivered, sink_sequence_numbers, shard_id)
+        });
+
+        return Box::new(delivery.and_then(move |(is_delivered, sink_sequence_numbers, shard_id)| {
+            let stop: ShardFuture<Loop<(), ShardReadState>> = Box::new(future::ok(Loop::Break(())));
+            match is_delivered {
+                Ok(true) => {}
+                Ok(false) => {
+                    // A lease without a task would look owned to the balancer, it's given up so the
+                    // shard is read again from its checkpoint on the next round, here or by another worker.
+                    eprintln!("Giving up shard {} - its records can't be delivered.", shard_id);
+                    return Box::new(run_blocking(move || {
+                        kcl.release_shard(&shard_id, &worker_id);
+                    }).map(|_| Loop::Break(())));
+                }
+                Err(KclError::ConditionalCheckFailed(_)) => {
+                    eprintln!("Shard {} isn't owned by this worker anymore - not checkpointing.", shard_id);
+                    return stop;
+                }
+                Err(KclError::NotFound(_)) => {
+                    eprintln!("The lease of shard {} is gone - stop reading it.", shard_id);
+                    return stop;
                 }
+                // The next checkpoint covers these records.
+                Err(error) => eprintln!("Can't checkpoint shard {}. {}", shard_id, error),
+            }
 
-                number_of_retries = 1;
-                let records = records_result.unwrap();
-                let shard_iterator = records.next_shard_iterator;
+            if next_shard_iterator.is_none() {
+                eprintln!("No more records in shard {}.", shard_id);
+                return stop;
+            }
 
-                if records.records.len() > 0 {
-                    let pushed = self.push_logs_to_s3_and_elastic_search(
-                        &(records.records), &client, &mut reactor
-                    );
+            state.shard_iterator = next_shard_iterator.unwrap();
+            state.sequence_number = Some(last_sequence_number);
+            state.sink_sequence_numbers = sink_sequence_numbers;
 
-                    if !pushed {
-                        println!("Can't push logs to ES.");
-                        return;
-                    }
+            // The time spent delivering counts towards the wait.
+            let delay = kcl.polling_policy.get_delay(
+                number_of_records, millis_behind_latest, state.last_read_at.elapsed()
+            );
+            KinesisStreamLibrary::continue_after(state, delay)
+        }));
+    }
 
-                    let mut sequence_number = None;
-                    for record in records.records {
-                        sequence_number = Some(record.sequence_number.to_string());
-                    }
+    /// An expired iterator is replaced, any other failed read is retried with the retry policy.
+    /// Throttling only means the shard is read too often, the reads are slowed down but never given up.
+    fn handle_read_error(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shard_id: String,
+                         mut state: ShardReadState, error: KclError) -> ShardFuture<Loop<(), ShardReadState>> {
+        state.number_of_failures = state.number_of_failures + 1;
+        let failing_since = *state.failing_since.get_or_insert(Instant::now());
+        if kcl.is_debug_enabled {
+            eprintln!(
+                "Error while reading records from shard {}. Number of failures: {} - {}",
+                shard_id, state.number_of_failures, error
+            );
+        }
 
-                    if sequence_number.is_some() {
-                        self.dynamo_db_library.update_shard_sequence_number(
-                            &shard_id, &(sequence_number.unwrap())
-                        );
-                    }
+        let error_class = error.get_error_class();
+        if error_class == ErrorClass::ExpiredIterator {
+            eprintln!("The iterator of shard {} expired - reading again after {}.",
+                     shard_id, state.sequence_number.clone().unwrap_or("its start".to_string()));
+            return KinesisStreamLibrary::renew_shard_iterator(kcl, worker_id, shard_id, state);
+        }
+
+        if error_class != ErrorClass::Throttled &&
+            !kcl.retry_policy.should_retry(error_class, state.number_of_failures, failing_since) {
+            eprintln!("Giving up shard {}. {}", shard_id, error);
+            return Box::new(run_blocking(move || {
+                kcl.release_shard(&shard_id, &worker_id);
+            }).map(|_| Loop::Break(())));
+        }
+
+        let back_off_time = kcl.retry_policy.get_delay(state.number_of_failures);
+        return KinesisStreamLibrary::continue_after(state, back_off_time);
+    }
+
+    /// A new iterator after the last delivered record. Failing to get one counts as another failed read.
+    fn renew_shard_iterator(kcl: Arc<KinesisStreamLibrary>, worker_id: String, shard_id: String,
+                            mut state: ShardReadState) -> ShardFuture<Loop<(), ShardReadState>> {
+        let shard_iterator_output =
+            KinesisStreamLibrary::get_shard_iterator(&kcl, &shard_id, state.sequence_number.clone());
+
+        return Box::new(shard_iterator_output.then(move |shard_iterator_result| -> ShardFuture<Loop<(), ShardReadState>> {
+            match shard_iterator_result {
+                Ok(Some(shard_iterator)) => {
+                    state.shard_iterator = shard_iterator;
+                    Box::new(future::ok(Loop::Continue(state)))
+                }
+                Ok(None) => {
+                    eprintln!("No more records in shard {}.", shard_id);
+                    Box::new(future::ok(Loop::Break(())))
                 }
+                Err(error) => KinesisStreamLibrary::handle_read_error(kcl, worker_id, shard_id, state, error),
+            }
+        }));
+    }
 
-                if shard_iterator.is_some() {
-                    shard_iterator_string = shard_iterator.unwrap().to_string();
-                } else {
-                    println!("No more records in shard {}.", shard_id.to_string());
-                    return;
+    /// An iterator after the given sequence number, or at the shard's start without one. A sequence
+    /// number the stream no longer holds, after an outage longer than its retention, is an invalid
+    /// argument: reading then starts at the oldest record left and the records in between are lost.
+    fn get_shard_iterator(kcl: &Arc<KinesisStreamLibrary>, shard_id: &String, sequence_number: Option<String>)
+                          -> Box<Future<Item = Option<String>, Error = KclError> + Send> {
+        let shard_iterator = kcl.get_shard_iterator_input(shard_id, sequence_number.clone());
+        let shard_iterator_output = kcl.kinesis_client.get_shard_iterator(shard_iterator);
+        let trim_horizon_kcl = kcl.clone();
+        let trim_horizon_shard_id = shard_id.to_string();
+
+        return Box::new(shard_iterator_output.then(move |shard_iterator_result|
+                                                       -> Box<Future<Item = Option<String>, Error = KclError> + Send> {
+            match shard_iterator_result {
+                Ok(output) => Box::new(future::ok(output.shard_iterator)),
+                Err(GetShardIteratorError::InvalidArgument(message)) if sequence_number.is_some() => {
+                    eprintln!(
+                        "WARNING: shard {} can't be read after {} - reading from its oldest record, the records in between are lost. {}",
+                        trim_horizon_shard_id, sequence_number.unwrap(), message
+                    );
+                    let shard_iterator = trim_horizon_kcl.get_shard_iterator_input(&trim_horizon_shard_id, None);
+                    Box::new(
+                        trim_horizon_kcl.kinesis_client.get_shard_iterator(shard_iterator)
+                            .map(|output| output.shard_iterator)
+                            .map_err(KclError::from)
+                    )
                 }
+                Err(error) => Box::new(future::err(KclError::from(error))),
+            }
+        }));
+    }
+
+    fn continue_after(state: ShardReadState, delay: Duration) -> ShardFuture<Loop<(), ShardReadState>> {
+        return Box::new(
+            Delay::new(Instant::now() + delay)
+                .map_err(|error| eprintln!("The shard timer failed. - {}", error))
+                .map(move |_| Loop::Continue(state))
+        );
+    }
+
+    /// Transforms the records, pushes them to the sinks and checkpoints the shard.
+    /// Returns false when the transforms or the sinks failed, the checkpoint error otherwise.
+    fn deliver_records(&self, shard_id: &String, worker_id: &String, records: Vec<Record>,
+                       sink_sequence_numbers: &mut HashMap<String, String>) -> Result<bool, KclError> {
+        let last_sequence_number = records[records.len() - 1].sequence_number.to_string();
+        let transformed_records = self.transform_records(shard_id, records);
+        if transformed_records.is_err() {
+            eprintln!("Can't transform the records of shard {}. - {}", shard_id, transformed_records.unwrap_err());
+            return Ok(false);
+        }
+
+        let transformed_records = transformed_records.unwrap();
+
+        // Every record was dropped by the transforms, there's nothing to deliver.
+        let sequence_number = if transformed_records.is_empty() {
+            Some(last_sequence_number)
+        } else {
+            self.sink_fan_out.push_records(shard_id, &transformed_records, sink_sequence_numbers)
+        };
+
+        if sequence_number.is_none() {
+            eprintln!("Can't push the records of shard {} to the required sinks.", shard_id);
+            let is_checkpointed =
+                self.dynamo_db_library.update_sink_sequence_numbers(shard_id, worker_id, sink_sequence_numbers);
+            if is_checkpointed.is_err() {
+                eprintln!("Can't save the sinks' progress of shard {}. {}", shard_id, is_checkpointed.unwrap_err());
             }
+
+            return Ok(false);
         }
+
+        self.dynamo_db_library.update_shard_sequence_number(
+            shard_id, worker_id, &(sequence_number.unwrap()), sink_sequence_numbers
+        )?;
+
+        return Ok(true);
     }
 
-    pub fn get_back_off_milli(&self, number_of_retries: u32) -> u64 {
-        if self.is_debug_enabled {
-            println!("Calculating the exponential back_off");
+    fn transform_records(&self, shard_id: &String, records: Vec<Record>) -> Result<Vec<Record>, String> {
+        if self.transform_pipeline.is_empty() {
+            return Ok(records);
         }
 
-        let two: u64 = 2;
-        return two.pow(number_of_retries);
+        let context = RecordContext {
+            stream_name: self.stream_name.to_string(),
+            shard_id: shard_id.to_string()
+        };
+
+        return self.transform_pipeline.apply(&context, records);
     }
 
-    fn validate_shard_owner_with_current_thread(&self, shard_id: &String, worker_id: &String) -> bool {
-        let _owner_id = self.dynamo_db_library.get_shard_owned_id(shard_id);
-        if _owner_id.is_none() {
+    /// Print the records of the shard without taking its lease, until the shard is closed.
+    /// Diagnostics go to stderr, stdout only carries the records.
+    /// Prints the records of the shard, then of its child shards from their start once it's
+    /// closed by a reshard. A merged shard is followed from its parent only, not from the adjacent
+    /// parent, so it's printed once.
+    pub fn tail_shard(kcl: Arc<KinesisStreamLibrary>, shard_id: String, stdout_sink: Arc<StdoutSink>,
+                      shard_iterator_type: &'static str) {
+        if !kcl.tail_open_shard(&shard_id, &stdout_sink, shard_iterator_type) {
+            return;
+        }
+
+        let stream_shards = kcl.get_stream_shards();
+        if stream_shards.is_err() {
+            eprintln!("Can't find the child shards of {}. {}", shard_id, stream_shards.unwrap_err());
+            return;
+        }
+
+        let child_shard_ids: Vec<String> = stream_shards.unwrap().into_iter()
+            .filter(|shard| shard.parent_shard_id.as_ref() == Some(&shard_id))
+            .map(|shard| shard.shard_id)
+            .collect();
+        eprintln!("Shard {} is closed, following its child shards {:?}.", shard_id, child_shard_ids);
+
+        let mut tail_threads = vec![];
+        for child_shard_id in child_shard_ids {
+            let kcl_clone = kcl.clone();
+            let stdout_sink_clone = stdout_sink.clone();
+            tail_threads.push(thread::spawn(move || {
+                KinesisStreamLibrary::tail_shard(kcl_clone, child_shard_id, stdout_sink_clone, "TRIM_HORIZON");
+            }));
+        }
+
+        for tail_thread in tail_threads {
+            let _ = tail_thread.join();
+        }
+    }
+
+    /// Returns true once the shard is closed, false when it can't be read.
+    fn tail_open_shard(&self, shard_id: &String, stdout_sink: &StdoutSink, shard_iterator_type: &str) -> bool {
+        let shard_iterator = GetShardIteratorInput {
+            shard_id: shard_id.to_string(),
+            shard_iterator_type: shard_iterator_type.to_string(),
+            starting_sequence_number: None,
+            stream_name: self.stream_name.to_string(),
+            timestamp: None
+        };
+
+        let shard_iterator_output = self.kinesis_client.get_shard_iterator(shard_iterator).sync();
+        if shard_iterator_output.is_err() {
+            eprintln!("No Shard Iterator for shard {}. - {:?}", shard_id, shard_iterator_output.unwrap_err());
             return false;
         }
 
-        return _owner_id.unwrap().to_string() == worker_id.to_string();
+        let mut shard_iterator_option = shard_iterator_output.unwrap().shard_iterator;
+        let mut number_of_failures = 0;
+        let mut failing_since = Instant::now();
+        while shard_iterator_option.is_some() {
+            let read_at = Instant::now();
+            let records_input = GetRecordsInput {
+                limit: Some(self.polling_policy.max_records),
+                shard_iterator: shard_iterator_option.clone().unwrap()
+            };
+
+            let records_result = self.kinesis_client.get_records(records_input).sync().map_err(KclError::from);
+            if records_result.is_err() {
+                let error = records_result.unwrap_err();
+                eprintln!("Error while reading from shard {}. - {}", shard_id, error);
+                if number_of_failures == 0 {
+                    failing_since = Instant::now();
+                }
+
+                number_of_failures = number_of_failures + 1;
+                if !self.retry_policy.should_retry(error.get_error_class(), number_of_failures, failing_since) {
+                    return false;
+                }
+
+                thread::sleep(self.retry_policy.get_delay(number_of_failures));
+                continue;
+            }
+
+            number_of_failures = 0;
+            let records = records_result.unwrap();
+            if records.records.len() > 0 && !stdout_sink.push_records(shard_id, &(records.records)) {
+                return false;
+            }
+
+            // Stay well under the 5 reads per second of the shard once caught up.
+            thread::sleep(self.polling_policy.get_delay(
+                records.records.len(), records.millis_behind_latest, read_at.elapsed()
+            ));
+
+            shard_iterator_option = records.next_shard_iterator;
+        }
+
+        eprintln!("No more records in shard {}.", shard_id);
+        return true;
+    }
+
+    fn validate_shard_owner_with_current_thread(&self, shard_id: &String, worker_id: &String)
+                                                -> Result<bool, KclError> {
+        let owner_id = self.dynamo_db_library.get_shard_owned_id(shard_id)?;
+        return Ok(owner_id == Some(worker_id.to_string()));
     }
 
+    /// The sequence number is the last delivered record, reading goes on after it.
     fn get_shard_iterator_input(&self, shard_id: &String, sequence_number: Option<String>)
                                 -> GetShardIteratorInput {
         if sequence_number.is_some() {
             return GetShardIteratorInput {
                 shard_id: shard_id.to_string(),
-                shard_iterator_type: "AT_SEQUENCE_NUMBER".to_string(),
+                shard_iterator_type: "AFTER_SEQUENCE_NUMBER".to_string(),
                 starting_sequence_number: sequence_number,
                 stream_name: self.stream_name.to_string(),
                 timestamp: None
@@ -336,110 +805,206 @@ impl KinesisStreamLibrary {
             timestamp: None
         };
     }
+}
 
-    /// Hourly index.
-    fn push_logs_to_s3_and_elastic_search(&self, docs: &Vec<Record>,
-                                          client: &Client<HttpConnector, Body>,
-                                          reactor: &mut reactor::Core) -> bool {
-        let date = Utc::now();
-        let index_name = format!("{}_{}", "index_name", date.format("%Y_%m_%d_%H"));
-        let mut batch: Vec<String> = vec![];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dynamo_db::in_memory_table::InMemoryTable;
+    use rand::{thread_rng, Rng};
+    use std::sync::Barrier;
+
+    const NUMBER_OF_WORKERS: usize = 8;
+
+    fn get_kcl(table: &InMemoryTable) -> Arc<KinesisStreamLibrary> {
+        let dynamo_db_library = DynamoDbLibrary::new_with_table("leases".to_string(), Box::new(table.clone()));
+        return Arc::new(KinesisStreamLibrary::new(
+            "arn:aws:iam::123456789012:role/kcl".to_string(), "stream".to_string(), dynamo_db_library,
+            SinkFanOut::new(vec![], None), TransformPipeline::new(), false
+        ));
+    }
 
-        for doc in docs {
-            let record = (*doc).clone().data;
-            let doc_string = String::from_utf8(record).unwrap().to_string();
-            batch.push(format!("{{\"index\": {{\"_index\": \"{}\", \"_type\": \"_doc\"}} }}", index_name).to_string());
-            batch.push(doc_string);
-        }
+    fn get_owner(table: &InMemoryTable, shard_id: &str) -> Option<String> {
+        return table.get(shard_id).and_then(|item| item.get("owner_id").and_then(|owner_id| owner_id.s.clone()));
+    }
 
-        let bulk = batch.join("\n") + "\n";
-        let mut pushed = false;
-        while !pushed {
-            pushed = false;
-            let logs_pushed_to_s3 = self.push_logs_to_s3(bulk.clone());
-            if logs_pushed_to_s3 {
-                pushed = true;
+    /// Starts the operation on every worker at the same time, the results are in the workers' order.
+    fn run_workers<T, F>(kcl: &Arc<KinesisStreamLibrary>, operation: F) -> Vec<T>
+        where T: Send + 'static, F: Fn(&KinesisStreamLibrary, String) -> T + Send + Sync + 'static {
+        let barrier = Arc::new(Barrier::new(NUMBER_OF_WORKERS));
+        let operation = Arc::new(operation);
+        let workers: Vec<thread::JoinHandle<T>> = (0..NUMBER_OF_WORKERS).map(|index| {
+            let worker_kcl = kcl.clone();
+            let worker_barrier = barrier.clone();
+            let worker_operation = operation.clone();
+            thread::spawn(move || {
+                worker_barrier.wait();
+                worker_operation(&worker_kcl, format!("worker-{}", index))
+            })
+        }).collect();
+
+        return workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+    }
 
-                let sleep_time = time::Duration::from_millis(1000);
-                thread::sleep(sleep_time);
-            }
+    fn get_winners(results: Vec<(String, bool)>) -> Vec<String> {
+        return results.into_iter().filter(|&(_, is_won)| is_won).map(|(worker_id, _)| worker_id).collect();
+    }
+
+    fn is_condition_failed<T>(result: Result<T, KclError>) -> bool {
+        match result {
+            Err(KclError::ConditionalCheckFailed(_)) => true,
+            _ => false,
         }
+    }
 
-        let mut number_of_retrials = 0;
-        let mut client = Client::new();
-        let mut reactor = reactor::Core::new().unwrap();
-        let mut pushed = false;
-        while !pushed && number_of_retrials <= 5 {
-            number_of_retrials = number_of_retrials + 1;
-            let elasticsearch_url = "http://localhost:8081/_bulk";
-            let uri: hyper::Uri = elasticsearch_url.parse().unwrap();
-            let mut req = hyper::Request::new(Body::from(bulk.clone()));
-            *req.method_mut() = Method::POST;
-            *req.uri_mut() = uri.clone();
-            req.headers_mut().insert("content-type", HeaderValue::from_str("application/json").unwrap());
-            let future = client.request(req);
-            let ret = reactor.run(future);
-
-            if self.is_debug_enabled {
-                println!("pushing to elastic search. - {}", format!("{:?}", ret));
-            }
+    #[test]
+    fn only_one_worker_takes_a_new_shard() {
+        let table = InMemoryTable::new("shard_id");
+        let kcl = get_kcl(&table);
 
-            if ret.is_err() {
-                println!("Error while pushing to Elastic search. - {}", ret.unwrap_err());
+        let winners = get_winners(run_workers(&kcl, |kcl, worker_id| {
+            let taken = kcl.take_shard_lease(&worker_id, &"shard-1".to_string());
+            (worker_id, taken.map(|checkpoint| checkpoint.is_some()).unwrap_or(false))
+        }));
 
-                let sleep_time = time::Duration::from_millis(1000);
-                thread::sleep(sleep_time);
-            } else {
-                pushed = true;
-            }
+        assert_eq!(winners.len(), 1);
+        assert_eq!(get_owner(&table, "shard-1"), Some(winners[0].to_string()));
+    }
+
+    #[test]
+    fn only_one_worker_takes_a_released_shard() {
+        let table = InMemoryTable::new("shard_id");
+        let kcl = get_kcl(&table);
+        let shard_id = "shard-1".to_string();
+        kcl.take_shard_lease(&"worker-0".to_string(), &shard_id).unwrap();
+        kcl.dynamo_db_library.release_shard_from_owner(&shard_id, &"worker-0".to_string()).unwrap();
+
+        let winners = get_winners(run_workers(&kcl, |kcl, worker_id| {
+            let taken = kcl.take_shard_lease(&worker_id, &"shard-1".to_string());
+            (worker_id, taken.map(|checkpoint| checkpoint.is_some()).unwrap_or(false))
+        }));
+
+        assert_eq!(winners.len(), 1);
+        assert_eq!(get_owner(&table, "shard-1"), Some(winners[0].to_string()));
+    }
+
+    #[test]
+    fn only_one_worker_steals_a_shard_and_the_previous_owner_cant_checkpoint() {
+        let table = InMemoryTable::new("shard_id");
+        let kcl = get_kcl(&table);
+        let shard_id = "shard-1".to_string();
+        let owner_id = "owner".to_string();
+        kcl.take_shard_lease(&owner_id, &shard_id).unwrap();
+        kcl.dynamo_db_library.update_shard_sequence_number(&shard_id, &owner_id, &"1".to_string(), &HashMap::new())
+            .unwrap();
+
+        let winners = get_winners(run_workers(&kcl, |kcl, worker_id| {
+            let stolen = kcl.dynamo_db_library.steal_shard_lease(
+                &"shard-1".to_string(), &"owner".to_string(), &worker_id
+            );
+            (worker_id, stolen.is_ok())
+        }));
+
+        assert_eq!(winners.len(), 1);
+        let checkpoint = |worker_id: &String| kcl.dynamo_db_library.update_shard_sequence_number(
+            &shard_id, worker_id, &"2".to_string(), &HashMap::new()
+        );
+        assert!(is_condition_failed(checkpoint(&owner_id)));
+        for index in 0..NUMBER_OF_WORKERS {
+            let worker_id = format!("worker-{}", index);
+            assert_eq!(checkpoint(&worker_id).is_ok(), worker_id == winners[0]);
         }
+    }
 
-        return pushed;
-    }
-
-    /// Save the logs to S3 to a second granularity.
-    fn push_logs_to_s3(&self, log_messages: String) -> bool {
-        let vector = log_messages.as_bytes().to_vec();
-        let date = Utc::now();
-        let file_path = format!("{}_{}.json", date.format("%Y/%m/%d/%H/%M/%S"), Uuid::new_v4());
-        let s3_bucket_name = "s3_bucket_name";
-
-        let response = self.s3_client.put_object(
-            PutObjectRequest {
-                acl: None,
-                body: Some(StreamingBody::from(vector)),
-                bucket: s3_bucket_name.to_string(),
-                cache_control: None,
-                content_disposition: None,
-                content_encoding: None,
-                content_language: None,
-                content_length: None,
-                content_md5: None,
-                content_type: None,
-                expires: None,
-                grant_full_control: None,
-                grant_read: None,
-                grant_read_acp: None,
-                grant_write_acp: None,
-                metadata: None,
-                request_payer: None,
-                sse_customer_algorithm: None,
-                sse_customer_key: None,
-                sse_customer_key_md5: None,
-                ssekms_key_id: None,
-                server_side_encryption: None,
-                storage_class: None,
-                tagging: None,
-                website_redirect_location: None,
-                key: file_path.to_string()
+    #[test]
+    fn an_idle_shard_release_and_a_checkpoint_dont_both_succeed() {
+        for _ in 0..50 {
+            let table = InMemoryTable::new("shard_id");
+            let kcl = get_kcl(&table);
+            let shard_id = "shard-1".to_string();
+            let owner_id = "owner".to_string();
+            kcl.take_shard_lease(&owner_id, &shard_id).unwrap();
+            kcl.dynamo_db_library.update_shard_sequence_number(&shard_id, &owner_id, &"1".to_string(), &HashMap::new())
+                .unwrap();
+
+            let releaser_kcl = kcl.clone();
+            let releaser = thread::spawn(move || {
+                releaser_kcl.dynamo_db_library.release_idle_shard(&"shard-1".to_string(), &"1".to_string()).is_ok()
+            });
+            let is_checkpointed = kcl.dynamo_db_library.update_shard_sequence_number(
+                &shard_id, &owner_id, &"2".to_string(), &HashMap::new()
+            ).is_ok();
+            let is_released = releaser.join().unwrap();
+
+            assert!(is_checkpointed != is_released);
+            assert_eq!(get_owner(&table, "shard-1").is_some(), is_checkpointed);
+        }
+    }
+
+    #[test]
+    fn workers_under_contention_never_checkpoint_the_same_shard() {
+        let table = InMemoryTable::new("shard_id");
+        let kcl = get_kcl(&table);
+        let shard_ids: Vec<String> = (0..4).map(|index| format!("shard-{}", index)).collect();
+        let worker_shard_ids = shard_ids.clone();
+
+        // Each worker takes, steals, releases and checkpoints random shards, and keeps the ones it
+        // believes it owns until a checkpoint tells it otherwise.
+        let owned_shards = run_workers(&kcl, move |kcl, worker_id| {
+            let mut owned_shards: HashSet<String> = HashSet::new();
+            let library = &kcl.dynamo_db_library;
+            for iteration in 0..200 {
+                let shard_id = &worker_shard_ids[thread_rng().gen_range(0, worker_shard_ids.len())];
+                match thread_rng().gen_range(0, 4) {
+                    0 => {
+                        if kcl.take_shard_lease(&worker_id, shard_id).map(|taken| taken.is_some()).unwrap_or(false) {
+                            owned_shards.insert(shard_id.to_string());
+                        }
+                    }
+                    1 => {
+                        let owner_id = library.get_shard_owned_id(shard_id).ok().and_then(|owner_id| owner_id);
+                        if owner_id.is_some() && owner_id != Some(worker_id.to_string()) &&
+                            library.steal_shard_lease(shard_id, &owner_id.unwrap(), &worker_id).is_ok() {
+                            owned_shards.insert(shard_id.to_string());
+                        }
+                    }
+                    2 => {
+                        let sequence_number = library.get_owned_shard_sequence_number_given_shard_id(shard_id);
+                        if sequence_number.is_ok() && sequence_number.clone().unwrap().is_some() {
+                            let _ = library.release_idle_shard(shard_id, &sequence_number.unwrap().unwrap());
+                        }
+                    }
+                    _ => {
+                        if owned_shards.contains(shard_id) {
+                            let sequence_number = format!("{}-{}", worker_id, iteration);
+                            let checkpointed =
+                                library.update_shard_sequence_number(shard_id, &worker_id, &sequence_number, &HashMap::new());
+                            if is_condition_failed(checkpointed) {
+                                owned_shards.remove(shard_id);
+                            }
+                        }
+                    }
+                }
             }
-        ).sync();
 
-        if response.is_err() {
-            println!("Can't save the data to S3. - {}", format!("{:?}", response.unwrap_err()));
-            return false;
+            (worker_id, owned_shards)
+        });
+
+        for shard_id in &shard_ids {
+            let checkpointing_workers: Vec<&String> = owned_shards.iter()
+                .filter(|&&(ref worker_id, ref owned_shards)| {
+                    owned_shards.contains(shard_id) && kcl.dynamo_db_library.update_shard_sequence_number(
+                        shard_id, worker_id, &"final".to_string(), &HashMap::new()
+                    ).is_ok()
+                })
+                .map(|&(ref worker_id, _)| worker_id)
+                .collect();
+
+            assert!(checkpointing_workers.len() <= 1);
+            let owner_id = get_owner(&table, shard_id);
+            if owner_id.is_some() && !checkpointing_workers.is_empty() {
+                assert_eq!(owner_id.as_ref(), Some(checkpointing_workers[0]));
+            }
         }
-
-        return true;
     }
 }
@@ -0,0 +1,171 @@
+use rusoto_dynamodb::{BatchWriteItemError, CreateTableError, DeleteItemError, DescribeTableError,
+                      DescribeTimeToLiveError, GetItemError, PutItemError, ScanError, UpdateItemError,
+                      UpdateTimeToLiveError};
+use rusoto_glacier::{AbortMultipartUploadError, CompleteMultipartUploadError, DeleteArchiveError,
+                     InitiateMultipartUploadError, UploadArchiveError, UploadMultipartPartError};
+use rusoto_kinesis::{DescribeStreamError, GetRecordsError, GetShardIteratorError, PutRecordsError};
+use rusoto_s3::PutObjectError;
+use retry::retry_policy::{ClassifiedError, ErrorClass};
+use std::error::Error;
+use std::fmt;
+
+/// What went wrong talking to AWS, each kind calls for a different reaction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KclError {
+    /// The stream or table throughput was exceeded, the same call succeeds after a back off.
+    Throttling(String),
+    /// A conditional write lost, the lease or the item changed in the meantime.
+    ConditionalCheckFailed(String),
+    /// The stream, shard, table or item doesn't exist.
+    NotFound(String),
+    /// The shard iterator is older than 5 minutes, reading goes on with a new one.
+    ExpiredIterator(String),
+    /// The request got no answer: the connection, the credentials or an unreadable response.
+    Transport(String),
+    /// The service failed on its side (a 5xx response), the same call may succeed later.
+    Unavailable(String),
+    /// Anything else the service rejected, e.g. a validation error or a denied access.
+    Service(String),
+}
+
+impl fmt::Display for KclError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KclError::Throttling(ref message) => write!(formatter, "Throttled - {}", message),
+            KclError::ConditionalCheckFailed(ref message) => write!(formatter, "Condition failed - {}", message),
+            KclError::NotFound(ref message) => write!(formatter, "Not found - {}", message),
+            KclError::ExpiredIterator(ref message) => write!(formatter, "Expired iterator - {}", message),
+            KclError::Transport(ref message) => write!(formatter, "Transport error - {}", message),
+            KclError::Unavailable(ref message) => write!(formatter, "Service unavailable - {}", message),
+            KclError::Service(ref message) => write!(formatter, "Service error - {}", message),
+        }
+    }
+}
+
+impl Error for KclError {
+    fn description(&self) -> &str {
+        match *self {
+            KclError::Throttling(ref message) |
+            KclError::ConditionalCheckFailed(ref message) |
+            KclError::NotFound(ref message) |
+            KclError::ExpiredIterator(ref message) |
+            KclError::Transport(ref message) |
+            KclError::Unavailable(ref message) |
+            KclError::Service(ref message) => message,
+        }
+    }
+}
+
+/// Only the failures on the way or on the service's side are retried, a rejected request fails the same way again.
+impl ClassifiedError for KclError {
+    fn get_error_class(&self) -> ErrorClass {
+        match *self {
+            KclError::Throttling(_) => ErrorClass::Throttled,
+            KclError::Transport(_) | KclError::Unavailable(_) => ErrorClass::Transient,
+            KclError::ExpiredIterator(_) => ErrorClass::ExpiredIterator,
+            KclError::ConditionalCheckFailed(_) | KclError::NotFound(_) | KclError::Service(_) => ErrorClass::Permanent,
+        }
+    }
+}
+
+/// Maps the listed variants of a rusoto error. The dispatch, credentials and parse failures are
+/// transport errors, an unknown response is unavailable with a 5xx status, the rest are service errors.
+macro_rules! impl_from_aws_error {
+    ($error_type:ident { $($variant:ident => $kcl_error:ident),* }) => {
+        impl From<$error_type> for KclError {
+            fn from(error: $error_type) -> KclError {
+                match error {
+                    $($error_type::$variant(message) => KclError::$kcl_error(message),)*
+                    $error_type::HttpDispatch(error) => KclError::Transport(error.to_string()),
+                    $error_type::Credentials(error) => KclError::Transport(error.to_string()),
+                    $error_type::ParseError(message) => KclError::Transport(message),
+                    $error_type::Unknown(response) => {
+                        let message = format!("{} - {}", response.status, String::from_utf8_lossy(&response.body));
+                        if response.status.is_server_error() {
+                            KclError::Unavailable(message)
+                        } else {
+                            KclError::Service(message)
+                        }
+                    }
+                    error => KclError::Service(error.to_string()),
+                }
+            }
+        }
+    };
+}
+
+impl_from_aws_error!(DescribeTableError { ResourceNotFound => NotFound, InternalServerError => Unavailable });
+impl_from_aws_error!(CreateTableError { LimitExceeded => Throttling, InternalServerError => Unavailable });
+impl_from_aws_error!(DescribeTimeToLiveError { ResourceNotFound => NotFound, InternalServerError => Unavailable });
+impl_from_aws_error!(UpdateTimeToLiveError {
+    LimitExceeded => Throttling,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(GetItemError {
+    ProvisionedThroughputExceeded => Throttling,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(PutItemError {
+    ProvisionedThroughputExceeded => Throttling,
+    ConditionalCheckFailed => ConditionalCheckFailed,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(UpdateItemError {
+    ProvisionedThroughputExceeded => Throttling,
+    ConditionalCheckFailed => ConditionalCheckFailed,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(DeleteItemError {
+    ProvisionedThroughputExceeded => Throttling,
+    ConditionalCheckFailed => ConditionalCheckFailed,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(ScanError {
+    ProvisionedThroughputExceeded => Throttling,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(BatchWriteItemError {
+    ProvisionedThroughputExceeded => Throttling,
+    ResourceNotFound => NotFound,
+    InternalServerError => Unavailable
+});
+impl_from_aws_error!(DescribeStreamError {
+    LimitExceeded => Throttling,
+    ResourceNotFound => NotFound
+});
+impl_from_aws_error!(GetShardIteratorError {
+    ProvisionedThroughputExceeded => Throttling,
+    ResourceNotFound => NotFound
+});
+impl_from_aws_error!(GetRecordsError {
+    ProvisionedThroughputExceeded => Throttling,
+    KMSThrottling => Throttling,
+    ExpiredIterator => ExpiredIterator,
+    ResourceNotFound => NotFound
+});
+impl_from_aws_error!(PutRecordsError {
+    ProvisionedThroughputExceeded => Throttling,
+    KMSThrottling => Throttling,
+    ResourceNotFound => NotFound
+});
+impl_from_aws_error!(PutObjectError {});
+impl_from_aws_error!(UploadArchiveError {
+    RequestTimeout => Transport,
+    ResourceNotFound => NotFound,
+    ServiceUnavailable => Unavailable
+});
+impl_from_aws_error!(InitiateMultipartUploadError { ResourceNotFound => NotFound, ServiceUnavailable => Unavailable });
+impl_from_aws_error!(UploadMultipartPartError {
+    RequestTimeout => Transport,
+    ResourceNotFound => NotFound,
+    ServiceUnavailable => Unavailable
+});
+impl_from_aws_error!(CompleteMultipartUploadError { ResourceNotFound => NotFound, ServiceUnavailable => Unavailable });
+impl_from_aws_error!(AbortMultipartUploadError { ResourceNotFound => NotFound, ServiceUnavailable => Unavailable });
+impl_from_aws_error!(DeleteArchiveError { ResourceNotFound => NotFound, ServiceUnavailable => Unavailable });
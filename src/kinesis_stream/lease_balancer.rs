@@ -0,0 +1,273 @@
+use config::env_config::get_parsed_env_var_or;
+use dynamo_db::dynamo_db_library::ShardLease;
+use std::cmp;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct LeaseBalancerConfig {
+    pub max_leases_for_worker: usize,
+    pub max_leases_to_steal_at_one_time: usize,
+}
+
+impl LeaseBalancerConfig {
+    /// KCL_MAX_LEASES_FOR_WORKER (default unlimited) and KCL_MAX_LEASES_TO_STEAL_AT_ONE_TIME (default 1).
+    pub fn from_env() -> LeaseBalancerConfig {
+        LeaseBalancerConfig {
+            max_leases_for_worker: get_parsed_env_var_or("KCL_MAX_LEASES_FOR_WORKER", usize::max_value()),
+            max_leases_to_steal_at_one_time: get_parsed_env_var_or("KCL_MAX_LEASES_TO_STEAL_AT_ONE_TIME", 1)
+        }
+    }
+}
+
+/// The leases a worker should take on this round.
+#[derive(Debug, PartialEq)]
+pub struct LeasePlan {
+    pub shards_to_take: Vec<String>,
+    /// The shard and the dead or overloaded worker it's taken from.
+    pub shards_to_steal: Vec<(String, String)>,
+}
+
+/// Spreads the shards evenly: every worker targets shards / active workers leases, taking free
+/// shards first, then the leases of dead workers, and stealing from the most loaded workers only
+/// when none are left.
+pub struct LeaseBalancer {
+    config: LeaseBalancerConfig,
+}
+
+impl LeaseBalancer {
+    pub fn new(config: LeaseBalancerConfig) -> LeaseBalancer {
+        LeaseBalancer { config }
+    }
+
+    /// The number of leases each worker should hold.
+    pub fn get_target_lease_count(&self, number_of_shards: usize, number_of_workers: usize) -> usize {
+        let number_of_workers = cmp::max(number_of_workers, 1);
+        let fair_share = (number_of_shards + number_of_workers - 1) / number_of_workers;
+        return cmp::min(fair_share, self.config.max_leases_for_worker);
+    }
+
+    /// `active_workers` are the live workers of the registry, None when it's unknown and every
+    /// lease owner counts as alive. The leases of the other owners are taken over as orphans.
+    pub fn plan(&self, worker_id: &String, shard_ids: &Vec<String>, leases: &Vec<ShardLease>,
+                active_workers: Option<&Vec<String>>) -> LeasePlan {
+        let mut lease_counts: HashMap<String, usize> = HashMap::new();
+        lease_counts.insert(worker_id.to_string(), 0);
+        for active_worker in active_workers.unwrap_or(&vec![]) {
+            lease_counts.entry(active_worker.to_string()).or_insert(0);
+        }
+
+        let mut owners: HashMap<String, String> = HashMap::new();
+        let mut orphaned_leases = vec![];
+        for lease in leases {
+            if lease.owner_id.is_some() && shard_ids.contains(&lease.shard_id) {
+                let owner_id = lease.owner_id.clone().unwrap();
+                let is_owner_alive = owner_id == *worker_id ||
+                    active_workers.map_or(true, |active_workers| active_workers.contains(&owner_id));
+
+                if is_owner_alive {
+                    *lease_counts.entry(owner_id.to_string()).or_insert(0) += 1;
+                    owners.insert(lease.shard_id.to_string(), owner_id);
+                } else {
+                    orphaned_leases.push((lease.shard_id.to_string(), owner_id));
+                }
+            }
+        }
+
+        let target = self.get_target_lease_count(shard_ids.len(), lease_counts.len());
+        let owned = lease_counts[worker_id];
+        let mut plan = LeasePlan { shards_to_take: vec![], shards_to_steal: vec![] };
+        if owned >= target {
+            return plan;
+        }
+
+        let mut needed = target - owned;
+        let orphaned_shard_ids: Vec<String> = orphaned_leases.iter().map(|&(ref shard_id, _)| shard_id.to_string()).collect();
+        plan.shards_to_take = shard_ids.iter()
+            .filter(|shard_id| !owners.contains_key(*shard_id) && !orphaned_shard_ids.contains(*shard_id))
+            .take(needed)
+            .cloned()
+            .collect();
+
+        needed = needed - plan.shards_to_take.len();
+        plan.shards_to_steal = orphaned_leases.into_iter().take(needed).collect();
+        needed = needed - plan.shards_to_steal.len();
+
+        let mut steals_left = cmp::min(needed, self.config.max_leases_to_steal_at_one_time);
+        while steals_left > 0 {
+            // The most loaded worker, as long as it holds more than its share. A worker holding exactly
+            // its share only gives a lease to one short of more than a lease, which then can't take it back.
+            let most_loaded_worker = lease_counts.iter()
+                .filter(|&(owner_id, count)| owner_id != worker_id && (*count > target || (*count == target && needed > 1)))
+                .max_by_key(|&(_, count)| *count)
+                .map(|(owner_id, _)| owner_id.to_string());
+
+            if most_loaded_worker.is_none() {
+                break;
+            }
+
+            let victim = most_loaded_worker.unwrap();
+            let shard_to_steal = owners.iter()
+                .filter(|&(_, owner_id)| *owner_id == victim)
+                .map(|(shard_id, _)| shard_id.to_string())
+                .min();
+
+            if shard_to_steal.is_none() {
+                break;
+            }
+
+            let shard_id = shard_to_steal.unwrap();
+            owners.remove(&shard_id);
+            *lease_counts.get_mut(&victim).unwrap() -= 1;
+            plan.shards_to_steal.push((shard_id, victim));
+            needed = needed - 1;
+            steals_left = steals_left - 1;
+        }
+
+        return plan;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_lease_balancer(max_leases_for_worker: usize) -> LeaseBalancer {
+        LeaseBalancer::new(LeaseBalancerConfig { max_leases_for_worker, max_leases_to_steal_at_one_time: 1 })
+    }
+
+    fn get_shard_ids(number_of_shards: usize) -> Vec<String> {
+        (0..number_of_shards).map(|index| format!("shard-{}", index)).collect()
+    }
+
+    fn get_leases(owners: &[(&str, Option<&str>)]) -> Vec<ShardLease> {
+        owners.iter().map(|&(shard_id, owner_id)| {
+            ShardLease { shard_id: shard_id.to_string(), owner_id: owner_id.map(|owner_id| owner_id.to_string()) }
+        }).collect()
+    }
+
+    fn get_workers(worker_ids: &[&str]) -> Vec<String> {
+        worker_ids.iter().map(|worker_id| worker_id.to_string()).collect()
+    }
+
+    /// Plans and applies the rounds of every worker in turn until no worker changes its leases.
+    fn balance(lease_balancer: &LeaseBalancer, shard_ids: &Vec<String>, worker_ids: &Vec<String>,
+               leases: &mut Vec<ShardLease>) -> usize {
+        for round in 0..100 {
+            let mut is_changed = false;
+            for worker_id in worker_ids {
+                let plan = lease_balancer.plan(worker_id, shard_ids, leases, Some(worker_ids));
+                let taken = plan.shards_to_take.into_iter()
+                    .chain(plan.shards_to_steal.into_iter().map(|(shard_id, _)| shard_id));
+                for shard_id in taken {
+                    is_changed = true;
+                    match leases.iter().position(|lease| lease.shard_id == shard_id) {
+                        Some(position) => leases[position].owner_id = Some(worker_id.to_string()),
+                        None => leases.push(ShardLease { shard_id, owner_id: Some(worker_id.to_string()) }),
+                    }
+                }
+            }
+
+            if !is_changed {
+                return round;
+            }
+        }
+
+        panic!("The leases never settled.");
+    }
+
+    fn count_leases(leases: &Vec<ShardLease>, worker_id: &str) -> usize {
+        leases.iter().filter(|lease| lease.owner_id == Some(worker_id.to_string())).count()
+    }
+
+    #[test]
+    fn targets_the_fair_share_up_to_the_cap() {
+        assert_eq!(get_lease_balancer(usize::max_value()).get_target_lease_count(10, 3), 4);
+        assert_eq!(get_lease_balancer(usize::max_value()).get_target_lease_count(10, 0), 10);
+        assert_eq!(get_lease_balancer(2).get_target_lease_count(10, 3), 2);
+    }
+
+    #[test]
+    fn takes_free_shards_up_to_the_cap() {
+        let plan = get_lease_balancer(2).plan(
+            &"worker-a".to_string(), &get_shard_ids(4), &vec![], Some(&get_workers(&["worker-a"]))
+        );
+
+        assert_eq!(plan.shards_to_take, vec!["shard-0".to_string(), "shard-1".to_string()]);
+        assert!(plan.shards_to_steal.is_empty());
+    }
+
+    #[test]
+    fn takes_over_the_leases_of_dead_workers() {
+        let leases = get_leases(&[("shard-0", Some("worker-a")), ("shard-1", Some("dead-worker"))]);
+
+        let plan = get_lease_balancer(usize::max_value()).plan(
+            &"worker-a".to_string(), &get_shard_ids(2), &leases, Some(&get_workers(&["worker-a"]))
+        );
+
+        assert!(plan.shards_to_take.is_empty());
+        assert_eq!(plan.shards_to_steal, vec![("shard-1".to_string(), "dead-worker".to_string())]);
+    }
+
+    #[test]
+    fn counts_every_owner_alive_without_the_registry() {
+        let leases = get_leases(&[("shard-0", Some("worker-b")), ("shard-1", Some("worker-b"))]);
+
+        let plan = get_lease_balancer(usize::max_value()).plan(&"worker-a".to_string(), &get_shard_ids(3), &leases, None);
+
+        assert_eq!(plan, LeasePlan { shards_to_take: vec!["shard-2".to_string()], shards_to_steal: vec![] });
+    }
+
+    #[test]
+    fn steals_only_from_workers_above_target() {
+        let leases = get_leases(&[
+            ("shard-0", Some("worker-b")), ("shard-1", Some("worker-b")), ("shard-2", Some("worker-b")),
+            ("shard-3", Some("worker-c")), ("shard-4", Some("worker-c")), ("shard-5", Some("worker-c"))
+        ]);
+        let workers = get_workers(&["worker-a", "worker-b", "worker-c"]);
+
+        let plan = get_lease_balancer(usize::max_value()).plan(&"worker-a".to_string(), &get_shard_ids(6), &leases, Some(&workers));
+        assert_eq!(plan.shards_to_steal.len(), 1);
+
+        // Short of a single lease, worker-a leaves the workers holding exactly their share alone.
+        let balanced_leases = get_leases(&[
+            ("shard-0", Some("worker-a")), ("shard-1", Some("worker-b")), ("shard-2", Some("worker-b")),
+            ("shard-3", Some("worker-c")), ("shard-4", Some("worker-c"))
+        ]);
+        let plan = get_lease_balancer(usize::max_value()).plan(&"worker-a".to_string(), &get_shard_ids(5), &balanced_leases, Some(&workers));
+        assert!(plan.shards_to_steal.is_empty());
+    }
+
+    #[test]
+    fn settles_without_ping_pong_when_the_shards_dont_divide_evenly() {
+        let lease_balancer = get_lease_balancer(usize::max_value());
+        let shard_ids = get_shard_ids(4);
+        let workers = get_workers(&["worker-a", "worker-b", "worker-c"]);
+        let mut leases = vec![];
+
+        balance(&lease_balancer, &shard_ids, &get_workers(&["worker-a", "worker-b"]), &mut leases);
+        balance(&lease_balancer, &shard_ids, &workers, &mut leases);
+
+        // The new worker gets a lease, and another round changes nothing.
+        assert!(count_leases(&leases, "worker-c") >= 1);
+        for worker_id in &workers {
+            let plan = lease_balancer.plan(worker_id, &shard_ids, &leases, Some(&workers));
+            assert_eq!(plan, LeasePlan { shards_to_take: vec![], shards_to_steal: vec![] });
+        }
+    }
+
+    #[test]
+    fn settles_with_five_shards_over_two_workers() {
+        let lease_balancer = get_lease_balancer(usize::max_value());
+        let shard_ids = get_shard_ids(5);
+        let workers = get_workers(&["worker-a", "worker-b"]);
+        let mut leases = get_leases(&[
+            ("shard-0", Some("worker-a")), ("shard-1", Some("worker-a")), ("shard-2", Some("worker-a")),
+            ("shard-3", Some("worker-a")), ("shard-4", Some("worker-a"))
+        ]);
+
+        balance(&lease_balancer, &shard_ids, &workers, &mut leases);
+
+        assert_eq!(count_leases(&leases, "worker-a"), 3);
+        assert_eq!(count_leases(&leases, "worker-b"), 2);
+    }
+}
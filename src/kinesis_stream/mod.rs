@@ -1 +1,5 @@
-pub mod kcl;
\ No newline at end of file
+pub mod kcl;
+pub mod kcl_error;
+pub mod lease_balancer;
+pub mod polling_policy;
+pub mod worker_registry;
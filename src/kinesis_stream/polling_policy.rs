@@ -0,0 +1,106 @@
+use config::env_config::get_parsed_env_var_or;
+use std::time::Duration;
+
+/// GetRecords takes at most 10000 records.
+const GET_RECORDS_MAX_LIMIT: i64 = 10000;
+
+/// How often a shard is read. A shard serves 5 reads per second shared by all of its consumers,
+/// so a shard behind the tip of the stream is read at most every min_read_interval and a shard
+/// caught up with it is only read every idle_time_between_reads.
+#[derive(Clone, Debug)]
+pub struct PollingPolicy {
+    pub max_records: i64,
+    pub min_read_interval_millis: u64,
+    pub idle_time_between_reads_millis: u64,
+    pub caught_up_millis_behind_latest: i64,
+}
+
+impl PollingPolicy {
+    /// KCL_MAX_RECORDS per read (default 1000, at most 10000), KCL_MIN_READ_INTERVAL_MILLIS
+    /// (default 200), KCL_IDLE_TIME_BETWEEN_READS_MILLIS (default 1000) and
+    /// KCL_CAUGHT_UP_MILLIS_BEHIND_LATEST, how far behind the tip a shard still counts as
+    /// caught up (default 1000).
+    pub fn from_env() -> PollingPolicy {
+        let max_records: i64 = get_parsed_env_var_or("KCL_MAX_RECORDS", 1000);
+
+        PollingPolicy {
+            max_records: max_records.max(1).min(GET_RECORDS_MAX_LIMIT),
+            min_read_interval_millis: get_parsed_env_var_or("KCL_MIN_READ_INTERVAL_MILLIS", 200),
+            idle_time_between_reads_millis: get_parsed_env_var_or("KCL_IDLE_TIME_BETWEEN_READS_MILLIS", 1000),
+            caught_up_millis_behind_latest: get_parsed_env_var_or("KCL_CAUGHT_UP_MILLIS_BEHIND_LATEST", 1000)
+        }
+    }
+
+    /// The wait before the next read, given the last read and the time spent since it was sent.
+    /// Without millis_behind_latest, a full batch means the shard is behind.
+    pub fn get_delay(&self, number_of_records: usize, millis_behind_latest: Option<i64>,
+                     since_last_read: Duration) -> Duration {
+        let is_caught_up = match millis_behind_latest {
+            Some(millis_behind_latest) => millis_behind_latest <= self.caught_up_millis_behind_latest,
+            None => (number_of_records as i64) < self.max_records,
+        };
+
+        let read_interval_millis = if number_of_records == 0 || is_caught_up {
+            self.idle_time_between_reads_millis
+        } else {
+            self.min_read_interval_millis
+        };
+
+        let read_interval = Duration::from_millis(read_interval_millis);
+        if since_last_read >= read_interval {
+            return Duration::from_millis(0);
+        }
+
+        return read_interval - since_last_read;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_polling_policy() -> PollingPolicy {
+        PollingPolicy {
+            max_records: 100,
+            min_read_interval_millis: 200,
+            idle_time_between_reads_millis: 1000,
+            caught_up_millis_behind_latest: 1000
+        }
+    }
+
+    #[test]
+    fn reads_a_shard_behind_the_tip_quickly() {
+        let delay = get_polling_policy().get_delay(100, Some(60000), Duration::from_millis(50));
+
+        assert_eq!(delay, Duration::from_millis(150));
+    }
+
+    #[test]
+    fn slows_down_once_caught_up() {
+        let delay = get_polling_policy().get_delay(10, Some(0), Duration::from_millis(50));
+
+        assert_eq!(delay, Duration::from_millis(950));
+    }
+
+    #[test]
+    fn slows_down_on_an_empty_read() {
+        let delay = get_polling_policy().get_delay(0, Some(60000), Duration::from_millis(0));
+
+        assert_eq!(delay, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn uses_a_full_batch_without_millis_behind_latest() {
+        let polling_policy = get_polling_policy();
+
+        assert_eq!(polling_policy.get_delay(100, None, Duration::from_millis(0)), Duration::from_millis(200));
+        assert_eq!(polling_policy.get_delay(99, None, Duration::from_millis(0)), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn reads_right_away_when_the_read_took_longer_than_the_interval() {
+        let delay = get_polling_policy().get_delay(100, Some(60000), Duration::from_millis(500));
+
+        assert_eq!(delay, Duration::from_millis(0));
+    }
+}
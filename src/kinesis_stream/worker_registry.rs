@@ -0,0 +1,83 @@
+use config::env_config::{get_env_var_or, get_parsed_env_var_or};
+use dynamo_db::dynamo_db_library::{DynamoDbLibrary, WorkerRecord};
+use dynamo_db::table_config::TableCapacity;
+use kinesis_stream::kcl_error::KclError;
+use chrono::{DateTime, Duration, Utc};
+
+const WORKER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The workers of the application and their liveness, one row per worker kept fresh by its heartbeat.
+/// A row expires along with its worker, DynamoDB deletes it through the table's time to live.
+pub struct WorkerRegistry {
+    dynamo_db_library: DynamoDbLibrary,
+    worker_id: String,
+    host: String,
+    started_at: DateTime<Utc>,
+    heartbeat_interval_seconds: u64,
+    expiry_seconds: i64,
+}
+
+impl WorkerRegistry {
+    /// KCL_WORKER_TABLE (hash key `worker_id`, default `<lease table>_workers`, so only the workers
+    /// sharing the leases count), KCL_WORKER_HEARTBEAT_SECONDS (default 10) and
+    /// KCL_WORKER_EXPIRY_SECONDS, how long a silent worker counts as alive (default 60).
+    pub fn from_env(worker_id: String, lease_table_name: &String) -> WorkerRegistry {
+        let default_table_name = format!("{}_workers", lease_table_name);
+
+        WorkerRegistry {
+            dynamo_db_library: DynamoDbLibrary::new(get_env_var_or("KCL_WORKER_TABLE", &default_table_name)),
+            worker_id,
+            host: get_env_var_or("HOSTNAME", "unknown"),
+            started_at: Utc::now(),
+            heartbeat_interval_seconds: get_parsed_env_var_or("KCL_WORKER_HEARTBEAT_SECONDS", 10),
+            expiry_seconds: get_parsed_env_var_or("KCL_WORKER_EXPIRY_SECONDS", 60)
+        }
+    }
+
+    /// The registry table is created along with the lease table, with the same capacity.
+    /// Without the time to live the dead workers' rows stay, which only costs the scans.
+    pub fn ensure_table(&self, capacity: &TableCapacity, active_timeout_seconds: u64) -> Result<(), KclError> {
+        self.dynamo_db_library.ensure_table("worker_id", capacity, active_timeout_seconds)?;
+
+        let time_to_live = self.dynamo_db_library.enable_time_to_live("expires_at");
+        if time_to_live.is_err() {
+            eprintln!("Can't enable the time to live of the worker table. {}", time_to_live.unwrap_err());
+        }
+
+        return Ok(());
+    }
+
+    pub fn get_worker_id(&self) -> String {
+        return self.worker_id.to_string();
+    }
+
+    pub fn get_heartbeat_interval_seconds(&self) -> u64 {
+        return self.heartbeat_interval_seconds;
+    }
+
+    pub fn heartbeat(&self, owned_shards: Vec<String>) -> Result<(), KclError> {
+        let now = Utc::now();
+        let worker_record = WorkerRecord {
+            worker_id: self.worker_id.to_string(),
+            host: self.host.to_string(),
+            started_at: self.started_at.to_rfc3339(),
+            last_heartbeat: now.to_rfc3339(),
+            owned_shards,
+            version: WORKER_VERSION.to_string()
+        };
+
+        let expires_at = (now + Duration::seconds(self.expiry_seconds)).timestamp();
+        return self.dynamo_db_library.put_worker_record(&worker_record, expires_at);
+    }
+
+    /// The workers which sent a heartbeat recently.
+    pub fn get_active_workers(&self) -> Result<Vec<WorkerRecord>, KclError> {
+        let worker_records = self.dynamo_db_library.get_worker_records()?;
+
+        let oldest_heartbeat = Utc::now() - Duration::seconds(self.expiry_seconds);
+        return Ok(worker_records.into_iter().filter(|worker_record| {
+            let last_heartbeat = DateTime::parse_from_rfc3339(&worker_record.last_heartbeat);
+            last_heartbeat.is_ok() && last_heartbeat.unwrap().with_timezone(&Utc) > oldest_heartbeat
+        }).collect());
+    }
+}
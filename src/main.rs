@@ -5,6 +5,7 @@ extern crate rocket;
 
 #[macro_use]
 extern crate hyper;
+extern crate hyper_tls;
 
 #[macro_use]
 extern crate serde_derive;
@@ -12,8 +13,9 @@ extern crate serde_derive;
 extern crate rusoto_core;
 extern crate rusoto_kinesis;
 extern crate rusoto_dynamodb;
-extern crate tokio_core;
-extern crate threadpool;
+extern crate tokio;
+extern crate tokio_threadpool;
+extern crate futures;
 extern crate uuid;
 extern crate serde_json;
 extern crate chrono;
@@ -25,12 +27,43 @@ extern crate b64;
 extern crate libflate;
 extern crate env_logger;
 extern crate rusoto_credential;
+extern crate parquet;
+extern crate rusoto_glacier;
+extern crate regex;
+extern crate rlua;
+extern crate avro_rs;
+extern crate rand;
 
 mod kinesis_stream;
 mod dynamo_db;
+mod sink;
+mod config;
+mod backfill;
+mod routing;
+mod transform;
+mod retry;
 
-use kinesis_stream::kcl::KinesisStreamLibrary;
+use kinesis_stream::kcl::{KinesisStreamLibrary, STREAM_NAME_STR};
+use retry::retry_policy::ClassifiedError;
+use kinesis_stream::lease_balancer::{LeaseBalancer, LeaseBalancerConfig};
+use kinesis_stream::worker_registry::WorkerRegistry;
 use dynamo_db::dynamo_db_library::DynamoDbLibrary;
+use dynamo_db::table_config::LeaseTableConfig;
+use sink::s3_sink::{S3Sink, S3SinkConfig};
+use sink::glacier_sink::{GlacierSink, GlacierSinkConfig};
+use sink::elastic_search_sink::{ElasticSearchSink, ElasticSearchSinkConfig};
+use sink::http_sink::{HttpSink, HttpSinkConfig};
+use sink::kinesis_sink::{KinesisSink, KinesisSinkConfig};
+use sink::dynamo_db_sink::{DynamoDbSink, DynamoDbSinkConfig};
+use sink::file_sink::{FileSink, FileSinkConfig};
+use sink::stdout_sink::StdoutSink;
+use sink::record_sink::RecordSink;
+use sink::sink_fan_out::{SinkEntry, SinkFanOut};
+use config::env_config::{get_bool_env_var, get_env_var_or, get_optional_env_var, get_parsed_env_var_or};
+use routing::record_router::RecordRouter;
+use transform::transform_pipeline::TransformPipeline;
+use transform::schema_transform::{SchemaCounts, SchemaCountsReport};
+use backfill::backfill_job::BackfillJob;
 
 use chrono::{DateTime, TimeZone, NaiveDateTime, Utc};
 use std::io::Read;
@@ -54,78 +87,244 @@ use rusoto_core::credential::{AwsCredentials, DefaultCredentialsProvider, ChainP
 use rusoto_kinesis::*;
 use rusoto_dynamodb::*;
 use rusoto_s3::*;
-use threadpool::ThreadPool;
 use std::collections::HashMap;
-use tokio_core::reactor;
 use serde_json::{Value, Error};
 use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
 use std::sync::Arc;
 use std::env::args;
+use rocket::State;
 
 const IAM_ROLE_ARN: &str = "arn:aws:iam::123456:role/iam-role-1234";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let mut is_debug_enabled = false;
-    for argument in args {
+    let mut backfill_range = None;
+    let mut is_tail_enabled = false;
+    let mut is_tail_from_start = false;
+    let mut is_base64_enabled = false;
+    let mut stream_name = STREAM_NAME_STR.to_string();
+    for (index, argument) in args.iter().enumerate() {
         if argument.to_string() == "debug".to_string() {
             is_debug_enabled = true;
         }
+
+        /// kcl backfill <from> <to>, e.g. kcl backfill 2018-10-01T00 2018-10-02T00
+        if argument.to_string() == "backfill".to_string() && index + 2 < args.len() {
+            backfill_range = Some((args[index + 1].to_string(), args[index + 2].to_string()));
+        }
+
+        /// kcl tail --stream <name> [--from-start] [--base64], prints the records as JSON lines.
+        /// Only the command, a stream or a backfill bound named tail doesn't count.
+        if index == 1 && argument.to_string() == "tail".to_string() {
+            is_tail_enabled = true;
+        }
+
+        if argument.to_string() == "--stream".to_string() && index + 1 < args.len() {
+            stream_name = args[index + 1].to_string();
+        }
+
+        if argument.to_string() == "--from-start".to_string() {
+            is_tail_from_start = true;
+        }
+
+        if argument.to_string() == "--base64".to_string() {
+            is_base64_enabled = true;
+        }
     }
 
     let _ = env_logger::try_init();
     let region = Region::EuWest1;
-    let dynamo_db_library = DynamoDbLibrary::new(STREAM_NAME_STR.to_string());
+    let lease_table_config = LeaseTableConfig::from_env(&stream_name);
+    let dynamo_db_library = DynamoDbLibrary::new(lease_table_config.table_name.to_string());
     let s3_client = S3Client::new(region);
+    let s3_sink_config = S3SinkConfig::from_env();
+    let s3_sink = S3Sink::new(s3_client, s3_sink_config.clone());
+    let elastic_search_sink =
+        ElasticSearchSink::new(ElasticSearchSinkConfig::from_env(), is_debug_enabled);
+
+    if backfill_range.is_some() {
+        let (from, to) = backfill_range.unwrap();
+        let backfill_job = BackfillJob::new(s3_sink, elastic_search_sink, &from, &to);
+        if backfill_job.is_none() {
+            std::process::exit(1);
+        }
+
+        let backfill_job = backfill_job.unwrap();
+        let table_result =
+            backfill_job.ensure_table(&lease_table_config.capacity, lease_table_config.active_timeout_seconds);
+        if table_result.is_err() {
+            eprintln!("The backfill checkpoint table isn't usable. {}", table_result.unwrap_err());
+            std::process::exit(1);
+        }
+
+        if !backfill_job.run() {
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let mut sinks: Vec<Arc<RecordSink>> = vec![Arc::new(s3_sink), Arc::new(elastic_search_sink)];
+
+    let http_sink_config = HttpSinkConfig::from_env();
+    if http_sink_config.is_some() {
+        sinks.push(Arc::new(HttpSink::new(http_sink_config.unwrap(), is_debug_enabled)));
+    }
+
+    let kinesis_sink_config = KinesisSinkConfig::from_env();
+    if kinesis_sink_config.is_some() {
+        sinks.push(Arc::new(KinesisSink::new(kinesis_sink_config.unwrap())));
+    }
+
+    let dynamo_db_sink_config = DynamoDbSinkConfig::from_env();
+    if dynamo_db_sink_config.is_some() {
+        sinks.push(Arc::new(DynamoDbSink::new(dynamo_db_sink_config.unwrap())));
+    }
+
+    let file_sink_config = FileSinkConfig::from_env();
+    if file_sink_config.is_some() {
+        sinks.push(Arc::new(FileSink::new(file_sink_config.unwrap())));
+    }
+
+    if get_bool_env_var("KCL_STDOUT_SINK") {
+        sinks.push(Arc::new(StdoutSink::new(is_base64_enabled)));
+    }
+
+    /// KCL_OPTIONAL_SINKS, e.g. http,file - the shard checkpoint doesn't wait for these sinks.
+    let optional_sink_names: Vec<String> = get_env_var_or("KCL_OPTIONAL_SINKS", "")
+        .split(',')
+        .map(|sink_name| sink_name.trim().to_string())
+        .collect();
+
+    let sink_names: Vec<String> = sinks.iter().map(|sink| sink.get_name()).collect();
+    let sink_entries = sinks.into_iter().map(|sink| {
+        let is_required = !optional_sink_names.contains(&sink.get_name());
+        SinkEntry { sink, is_required }
+    }).collect();
+
+    /// KCL_ROUTING_RULES_FILE, the JSON routing rules - reloaded when the file changes.
+    let routing_rules_file = get_optional_env_var("KCL_ROUTING_RULES_FILE");
+    let mut record_router = None;
+    if routing_rules_file.is_some() {
+        record_router = RecordRouter::new(routing_rules_file.unwrap(), sink_names);
+        if record_router.is_none() {
+            std::process::exit(1);
+        }
+    }
+
+    /// KCL_TRANSFORMS_FILE, the JSON transforms applied before the sinks.
+    /// Custom Rust transforms are added with transform_pipeline.add_transform.
+    let transforms_file = get_optional_env_var("KCL_TRANSFORMS_FILE");
+    let mut transform_pipeline = TransformPipeline::new();
+    if transforms_file.is_some() {
+        let transform_pipeline_option = TransformPipeline::from_file(&transforms_file.unwrap());
+        if transform_pipeline_option.is_none() {
+            std::process::exit(1);
+        }
+
+        transform_pipeline = transform_pipeline_option.unwrap();
+    }
+
+    let schema_counts = transform_pipeline.get_schema_counts();
     let kcl =
         Arc::new(
             KinesisStreamLibrary::new(
                 IAM_ROLE_ARN.to_string(),
+                stream_name,
                 dynamo_db_library,
-                s3_client,
+                SinkFanOut::new(sink_entries, record_router),
+                transform_pipeline,
                 is_debug_enabled
             ));
 
-    /// Handle throttling exception with exponential back_off mechanism.
-    let mut stream_shards_option = kcl.get_stream_shards();
-    for i in 0..5 {
-        if stream_shards_option.is_none() {
-            println!("Describing stream exception.");
-            let back_off_time = kcl.get_back_off_milli(i);
-            let sleep_time = time::Duration::from_millis(back_off_time);
-            thread::sleep(sleep_time);
-
-            stream_shards_option = kcl.get_stream_shards();
-        } else {
+    /// Handle throttling exception with the retry policy, a missing stream isn't retried.
+    /// Diagnostics go to stderr, stdout carries the records when tailing.
+    let retry_policy = kcl.get_retry_policy();
+    let describe_started_at = time::Instant::now();
+    let mut stream_shards_result = kcl.get_stream_shards();
+    let mut number_of_failures = 0;
+    while stream_shards_result.is_err() {
+        let error = stream_shards_result.clone().unwrap_err();
+        number_of_failures = number_of_failures + 1;
+        if !retry_policy.should_retry(error.get_error_class(), number_of_failures, describe_started_at) {
             break;
         }
-    }
 
-    let stream_shards = stream_shards_option.unwrap();
-    let pool = ThreadPool::new(5);
-    let worker_uuid = Uuid::new_v4();
-    println!("Worker UUID: {}", worker_uuid);
+        eprintln!("Couldn't describe stream. {}", error);
+        thread::sleep(retry_policy.get_delay(number_of_failures));
+        stream_shards_result = kcl.get_stream_shards();
+    }
 
-    /// It's a separate thread for the AWS health-api.
-    pool.execute(move || {
-        rocket::ignite().mount("/", routes![health_api]).launch();
-    });
+    if stream_shards_result.is_err() {
+        eprintln!("Couldn't describe stream. {}", stream_shards_result.unwrap_err());
+        std::process::exit(1);
+    }
 
-    loop {
-        let kinesis_stream_shards = stream_shards.clone();
-        kcl.cut_the_loose_for_the_idle_shards(&kinesis_stream_shards);
-        for stream_shard in kinesis_stream_shards {
+    let stream_shards = stream_shards_result.unwrap();
+    if is_tail_enabled {
+        let stdout_sink = Arc::new(StdoutSink::new(is_base64_enabled));
+        let shard_iterator_type = if is_tail_from_start { "TRIM_HORIZON" } else { "LATEST" };
+        let mut tail_threads = vec![];
+        for stream_shard in stream_shards {
             let kcl_arc_clone = kcl.clone();
-            pool.execute(move || {
-                let worker_unique_id = format!("{}-{:?}", worker_uuid, thread::current().id());
-
-                /// Start processing the shard records.
-                kcl_arc_clone.read_from_given_shard(
-                    &worker_unique_id, &(stream_shard.shard_id)
-                );
-            });
+            let stdout_sink_arc_clone = stdout_sink.clone();
+            tail_threads.push(thread::spawn(move || {
+                KinesisStreamLibrary::tail_shard(kcl_arc_clone, stream_shard.shard_id, stdout_sink_arc_clone,
+                                                 shard_iterator_type);
+            }));
         }
+
+        for tail_thread in tail_threads {
+            let _ = tail_thread.join();
+        }
+
+        return;
     }
+
+    let worker_uuid = Uuid::new_v4();
+    eprintln!("Worker UUID: {}", worker_uuid);
+
+    /// Roll the hourly S3 batches into the Glacier vault, if it's configured.
+    let glacier_sink_config = GlacierSinkConfig::from_env();
+    if glacier_sink_config.is_some() {
+        let glacier_sink = GlacierSink::new(s3_sink_config.clone(), glacier_sink_config.unwrap());
+        let glacier_worker_id = worker_uuid.to_string();
+        thread::spawn(move || {
+            glacier_sink.run(&glacier_worker_id);
+        });
+    }
+
+    let worker_registry = Arc::new(WorkerRegistry::from_env(worker_uuid.to_string(), &lease_table_config.table_name));
+    let lease_table_capacity = &lease_table_config.capacity;
+    let active_timeout_seconds = lease_table_config.active_timeout_seconds;
+    let tables_result = kcl.ensure_lease_table(lease_table_capacity, active_timeout_seconds)
+        .and_then(|_| worker_registry.ensure_table(lease_table_capacity, active_timeout_seconds));
+    if tables_result.is_err() {
+        eprintln!("The lease tables aren't usable. {}", tables_result.unwrap_err());
+        std::process::exit(1);
+    }
+
+    /// It's a separate thread for the AWS health-api and the admin api.
+    let api_worker_registry = worker_registry.clone();
+    thread::spawn(move || {
+        rocket::ignite()
+            .manage(api_worker_registry)
+            .manage(schema_counts)
+            .mount("/", routes![health_api, workers_api, schemas_api])
+            .launch();
+    });
+
+    /// One task per owned shard on the runtime, the shard leases are owned by the worker.
+    /// KCL_BLOCKING_THREADS caps the threads delivering to the sinks and checkpointing at once.
+    let mut runtime = tokio::runtime::Builder::new()
+        .blocking_threads(get_parsed_env_var_or("KCL_BLOCKING_THREADS", 200))
+        .build()
+        .expect("Can't start the runtime.");
+
+    let lease_balancer = LeaseBalancer::new(LeaseBalancerConfig::from_env());
+    runtime.spawn(KinesisStreamLibrary::run_worker(kcl, worker_registry, stream_shards, lease_balancer));
+    let _ = runtime.shutdown_on_idle().wait();
 }
 
 #[get("/")]
@@ -135,3 +334,19 @@ fn health_api() -> String {
 
     return serde_json::to_string(&response).unwrap().to_string();
 }
+
+/// The live workers of the application with the shards they own.
+#[get("/workers")]
+fn workers_api(worker_registry: State<Arc<WorkerRegistry>>) -> String {
+    let active_workers = worker_registry.get_active_workers().unwrap_or(vec![]);
+
+    return serde_json::to_string(&active_workers).unwrap().to_string();
+}
+
+/// The records validated by each schema of the transforms and how many violated it.
+#[get("/schemas")]
+fn schemas_api(schema_counts: State<Vec<Arc<SchemaCounts>>>) -> String {
+    let reports: Vec<SchemaCountsReport> = schema_counts.iter().map(|counts| counts.get_report()).collect();
+
+    return serde_json::to_string(&reports).unwrap().to_string();
+}
@@ -0,0 +1,157 @@
+use config::env_config::get_parsed_env_var_or;
+use rand::{thread_rng, Rng};
+use std::fmt::Display;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Why a call failed, which decides whether calling again can help.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// ProvisionedThroughputExceeded, LimitExceeded and the like, the call succeeds after a back off.
+    Throttled,
+    /// Network errors and server side failures.
+    Transient,
+    /// The shard iterator expired, calling again needs a new one.
+    ExpiredIterator,
+    /// The same call fails again.
+    Permanent,
+}
+
+impl ErrorClass {
+    pub fn is_retryable(&self) -> bool {
+        return *self == ErrorClass::Throttled || *self == ErrorClass::Transient;
+    }
+}
+
+/// An error which knows its class.
+pub trait ClassifiedError: Display {
+    fn get_error_class(&self) -> ErrorClass;
+}
+
+/// Exponential back off with full jitter: the n-th retry waits a random time up to
+/// min(max_delay, base_delay * 2^n), so workers throttled together don't retry together.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub base_delay_millis: u64,
+    pub max_delay_millis: u64,
+    pub max_attempts: u32,
+    pub max_elapsed_millis: u64,
+}
+
+impl RetryPolicy {
+    /// KCL_RETRY_BASE_DELAY_MILLIS (default 100), KCL_RETRY_MAX_DELAY_MILLIS (default 10000),
+    /// KCL_RETRY_MAX_ATTEMPTS (default 10) and KCL_RETRY_MAX_ELAPSED_SECONDS (default 120).
+    pub fn from_env() -> RetryPolicy {
+        RetryPolicy {
+            base_delay_millis: get_parsed_env_var_or("KCL_RETRY_BASE_DELAY_MILLIS", 100),
+            max_delay_millis: get_parsed_env_var_or("KCL_RETRY_MAX_DELAY_MILLIS", 10000),
+            max_attempts: get_parsed_env_var_or("KCL_RETRY_MAX_ATTEMPTS", 10),
+            max_elapsed_millis: get_parsed_env_var_or::<u64>("KCL_RETRY_MAX_ELAPSED_SECONDS", 120) * 1000
+        }
+    }
+
+    /// The same back off with another number of attempts, for the sinks configuring their own retries.
+    pub fn with_max_attempts(&self, max_attempts: u32) -> RetryPolicy {
+        let mut retry_policy = self.clone();
+        retry_policy.max_attempts = max_attempts;
+        return retry_policy;
+    }
+
+    /// The wait before the given retry, the first retry being 1.
+    pub fn get_delay(&self, retry: u32) -> Duration {
+        let exponential_delay_millis = 1u64.checked_shl(retry)
+            .map(|factor| self.base_delay_millis.saturating_mul(factor))
+            .unwrap_or(self.max_delay_millis);
+        let max_delay_millis = exponential_delay_millis.min(self.max_delay_millis);
+
+        return Duration::from_millis(thread_rng().gen_range(0, max_delay_millis + 1));
+    }
+
+    /// Whether the call is tried again after the given number of failed attempts since started_at.
+    pub fn should_retry(&self, error_class: ErrorClass, attempts: u32, started_at: Instant) -> bool {
+        let elapsed = started_at.elapsed();
+        let elapsed_millis = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+
+        return error_class.is_retryable() && attempts < self.max_attempts &&
+            elapsed_millis < self.max_elapsed_millis;
+    }
+
+    /// Calls the operation until it succeeds, it fails in a way retrying won't help or the policy
+    /// gives up, sleeping between the attempts. The last error is returned.
+    pub fn retry<T, E, F>(&self, description: &str, mut operation: F) -> Result<T, E>
+        where E: ClassifiedError, F: FnMut() -> Result<T, E> {
+        let started_at = Instant::now();
+        let mut attempts = 0;
+        loop {
+            let result = operation();
+            attempts = attempts + 1;
+            if result.is_ok() {
+                return result;
+            }
+
+            let error_class = result.as_ref().err().unwrap().get_error_class();
+            if !self.should_retry(error_class, attempts, started_at) {
+                return result;
+            }
+
+            let delay = self.get_delay(attempts);
+            eprintln!("{} failed, attempt {} - retrying in {:?}. {}",
+                     description, attempts, delay, result.as_ref().err().unwrap());
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_retry_policy() -> RetryPolicy {
+        RetryPolicy { base_delay_millis: 100, max_delay_millis: 1000, max_attempts: 3, max_elapsed_millis: 60000 }
+    }
+
+    #[test]
+    fn delay_stays_under_the_exponential_cap() {
+        let retry_policy = get_retry_policy();
+        for _ in 0..100 {
+            assert!(retry_policy.get_delay(1) <= Duration::from_millis(200));
+            assert!(retry_policy.get_delay(2) <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn delay_stays_under_the_max_delay() {
+        let retry_policy = get_retry_policy();
+        for retry in 4..100 {
+            assert!(retry_policy.get_delay(retry) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn retries_only_retryable_errors() {
+        let retry_policy = get_retry_policy();
+        let started_at = Instant::now();
+
+        assert!(retry_policy.should_retry(ErrorClass::Throttled, 1, started_at));
+        assert!(retry_policy.should_retry(ErrorClass::Transient, 1, started_at));
+        assert!(!retry_policy.should_retry(ErrorClass::Permanent, 1, started_at));
+        assert!(!retry_policy.should_retry(ErrorClass::ExpiredIterator, 1, started_at));
+    }
+
+    #[test]
+    fn gives_up_after_the_max_attempts() {
+        let retry_policy = get_retry_policy();
+        let started_at = Instant::now();
+
+        assert!(retry_policy.should_retry(ErrorClass::Transient, 2, started_at));
+        assert!(!retry_policy.should_retry(ErrorClass::Transient, 3, started_at));
+    }
+
+    #[test]
+    fn gives_up_after_the_max_elapsed_time() {
+        let mut retry_policy = get_retry_policy();
+        retry_policy.max_elapsed_millis = 0;
+
+        assert!(!retry_policy.should_retry(ErrorClass::Transient, 1, Instant::now()));
+    }
+}
@@ -0,0 +1,321 @@
+use regex::Regex;
+use serde_json::Value;
+use rusoto_kinesis::Record;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+const RULES_RELOAD_CHECK_INTERVAL_SECONDS: u64 = 10;
+
+/// The routing rules file, e.g.
+/// {"rules": [
+///   {"name": "health_checks", "partition_key": "^health-", "action": "drop"},
+///   {"name": "payments", "field": "service", "equals": "payments",
+///    "sinks": ["elastic_search", "s3"], "index": "payments"},
+///   {"name": "errors", "payload_regex": "(?i)error", "sinks": ["http"]}
+/// ]}
+/// The first matching rule wins, a record matching no rule goes to all the sinks. The action is
+/// deliver (default) or drop, and the sinks are named as the configured sinks, e.g. http.
+#[derive(Deserialize, Debug)]
+struct RoutingRulesFile {
+    rules: Vec<RoutingRuleDefinition>,
+}
+
+/// All the given conditions have to match. `field` is a dotted path in the JSON payload,
+/// checked against `equals` or `field_regex`, or only for presence if neither is given.
+#[derive(Deserialize, Debug)]
+struct RoutingRuleDefinition {
+    name: Option<String>,
+    partition_key: Option<String>,
+    field: Option<String>,
+    equals: Option<Value>,
+    field_regex: Option<String>,
+    payload_regex: Option<String>,
+    action: Option<String>,
+    sinks: Option<Vec<String>>,
+    index: Option<String>,
+}
+
+struct RoutingRule {
+    partition_key: Option<Regex>,
+    field_pointer: Option<String>,
+    equals: Option<Value>,
+    field_regex: Option<Regex>,
+    payload_regex: Option<Regex>,
+    route: Route,
+}
+
+/// Where a record goes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    Drop,
+    /// None for all the sinks, and the index prefix overriding the default one.
+    Deliver { sinks: Option<Vec<String>>, index: Option<String> },
+}
+
+struct LoadedRules {
+    rules: Vec<RoutingRule>,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+/// Routes the records with the rules of a JSON file, reloaded when the file changes.
+pub struct RecordRouter {
+    rules_file_path: String,
+    sink_names: Vec<String>,
+    loaded_rules: Mutex<LoadedRules>,
+}
+
+impl RecordRouter {
+    /// None when the rules file can't be loaded, delivering everything everywhere would ignore
+    /// its drop rules.
+    pub fn new(rules_file_path: String, sink_names: Vec<String>) -> Option<RecordRouter> {
+        let modified = RecordRouter::get_modified_time(&rules_file_path);
+        let rules = RecordRouter::load_rules(&rules_file_path, &sink_names);
+        if rules.is_none() {
+            return None;
+        }
+
+        let rules = rules.unwrap();
+        eprintln!("Loaded {} routing rules from {}.", rules.len(), rules_file_path);
+
+        return Some(RecordRouter {
+            rules_file_path,
+            sink_names,
+            loaded_rules: Mutex::new(LoadedRules { rules, modified, last_checked: Instant::now() })
+        });
+    }
+
+    pub fn route_records(&self, records: &Vec<Record>) -> Vec<Route> {
+        self.reload_rules_if_modified();
+
+        let loaded_rules = self.loaded_rules.lock().unwrap();
+        return records.iter().map(|record| RecordRouter::route_record(&loaded_rules.rules, record)).collect();
+    }
+
+    fn route_record(rules: &Vec<RoutingRule>, record: &Record) -> Route {
+        if rules.is_empty() {
+            return Route::Deliver { sinks: None, index: None };
+        }
+
+        let payload = String::from_utf8_lossy(&record.data);
+        let mut document: Option<Value> = None;
+        let mut is_document_parsed = false;
+
+        for rule in rules {
+            if rule.partition_key.is_some() && !rule.partition_key.as_ref().unwrap().is_match(&record.partition_key) {
+                continue;
+            }
+
+            if rule.payload_regex.is_some() && !rule.payload_regex.as_ref().unwrap().is_match(&payload) {
+                continue;
+            }
+
+            if rule.field_pointer.is_some() {
+                if !is_document_parsed {
+                    document = serde_json::from_str(&payload).ok();
+                    is_document_parsed = true;
+                }
+
+                if !RecordRouter::is_field_matching(rule, &document) {
+                    continue;
+                }
+            }
+
+            return rule.route.clone();
+        }
+
+        return Route::Deliver { sinks: None, index: None };
+    }
+
+    fn is_field_matching(rule: &RoutingRule, document: &Option<Value>) -> bool {
+        let field_value = document.as_ref()
+            .and_then(|document| document.pointer(rule.field_pointer.as_ref().unwrap()));
+        if field_value.is_none() {
+            return false;
+        }
+
+        let field_value = field_value.unwrap();
+        if rule.equals.is_some() && rule.equals.as_ref().unwrap() != field_value {
+            return false;
+        }
+
+        if rule.field_regex.is_some() {
+            let field_string = match *field_value {
+                Value::String(ref text) => text.to_string(),
+                ref other => other.to_string(),
+            };
+
+            return rule.field_regex.as_ref().unwrap().is_match(&field_string);
+        }
+
+        return true;
+    }
+
+    /// A broken rules file keeps the previous rules, so a bad edit doesn't stop the consumer.
+    fn reload_rules_if_modified(&self) {
+        let mut loaded_rules = self.loaded_rules.lock().unwrap();
+        if loaded_rules.last_checked.elapsed() < Duration::from_secs(RULES_RELOAD_CHECK_INTERVAL_SECONDS) {
+            return;
+        }
+
+        loaded_rules.last_checked = Instant::now();
+        let modified = RecordRouter::get_modified_time(&self.rules_file_path);
+        if modified == loaded_rules.modified {
+            return;
+        }
+
+        let rules = RecordRouter::load_rules(&self.rules_file_path, &self.sink_names);
+        loaded_rules.modified = modified;
+        if rules.is_some() {
+            eprintln!("Reloaded {} routing rules from {}.", rules.as_ref().unwrap().len(), self.rules_file_path);
+            loaded_rules.rules = rules.unwrap();
+        }
+    }
+
+    fn get_modified_time(rules_file_path: &String) -> Option<SystemTime> {
+        return fs::metadata(rules_file_path).and_then(|metadata| metadata.modified()).ok();
+    }
+
+    fn load_rules(rules_file_path: &String, sink_names: &Vec<String>) -> Option<Vec<RoutingRule>> {
+        let mut content = String::new();
+        let file = File::open(rules_file_path);
+        if file.is_err() || file.unwrap().read_to_string(&mut content).is_err() {
+            eprintln!("Can't read the routing rules file {}.", rules_file_path);
+            return None;
+        }
+
+        let rules_file: Result<RoutingRulesFile, _> = serde_json::from_str(&content);
+        if rules_file.is_err() {
+            eprintln!("Invalid routing rules file {}. - {}", rules_file_path, rules_file.unwrap_err());
+            return None;
+        }
+
+        let mut rules = vec![];
+        for (index, definition) in rules_file.unwrap().rules.into_iter().enumerate() {
+            let name = definition.name.clone().unwrap_or(format!("rule_{}", index));
+            let rule = RecordRouter::compile_rule(definition, sink_names);
+            if rule.is_none() {
+                eprintln!("Invalid routing rule {} - the rules file is ignored.", name);
+                return None;
+            }
+
+            rules.push(rule.unwrap());
+        }
+
+        return Some(rules);
+    }
+
+    fn compile_rule(definition: RoutingRuleDefinition, sink_names: &Vec<String>) -> Option<RoutingRule> {
+        let partition_key = RecordRouter::compile_regex(&definition.partition_key);
+        let field_regex = RecordRouter::compile_regex(&definition.field_regex);
+        let payload_regex = RecordRouter::compile_regex(&definition.payload_regex);
+        if partition_key.is_err() || field_regex.is_err() || payload_regex.is_err() {
+            return None;
+        }
+
+        let unknown_sink = definition.sinks.as_ref()
+            .and_then(|sinks| sinks.iter().find(|sink| !sink_names.contains(sink)).cloned());
+        if unknown_sink.is_some() {
+            eprintln!("Unknown routing sink {}, expected one of {}.", unknown_sink.unwrap(), sink_names.join(", "));
+            return None;
+        }
+
+        let route = match definition.action.unwrap_or("deliver".to_string()).as_str() {
+            "drop" => Route::Drop,
+            "deliver" => Route::Deliver { sinks: definition.sinks, index: definition.index },
+            action => {
+                eprintln!("Unknown routing action {}, expected deliver or drop.", action);
+                return None;
+            }
+        };
+
+        let field_pointer = definition.field.map(|field| RecordRouter::get_field_pointer(&field));
+
+        return Some(RoutingRule {
+            partition_key: partition_key.ok().unwrap(),
+            field_pointer,
+            equals: definition.equals,
+            field_regex: field_regex.ok().unwrap(),
+            payload_regex: payload_regex.ok().unwrap(),
+            route
+        });
+    }
+
+    /// a.b.c is the JSON pointer /a/b/c, a ~ or a / in a name is escaped as ~0 or ~1.
+    fn get_field_pointer(field: &String) -> String {
+        let tokens: Vec<String> = field.split('.')
+            .map(|name| name.replace("~", "~0").replace("/", "~1"))
+            .collect();
+
+        return format!("/{}", tokens.join("/"));
+    }
+
+    fn compile_regex(pattern: &Option<String>) -> Result<Option<Regex>, ()> {
+        if pattern.is_none() {
+            return Ok(None);
+        }
+
+        let regex = Regex::new(pattern.as_ref().unwrap());
+        if regex.is_err() {
+            eprintln!("Invalid routing regex {}. - {}", pattern.as_ref().unwrap(), regex.unwrap_err());
+            return Err(());
+        }
+
+        return Ok(Some(regex.unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_the_field_names_of_the_pointer() {
+        assert_eq!(RecordRouter::get_field_pointer(&"request.path".to_string()), "/request/path".to_string());
+        assert_eq!(RecordRouter::get_field_pointer(&"a/b.c~d".to_string()), "/a~1b/c~0d".to_string());
+    }
+
+    #[test]
+    fn matches_a_field_whose_name_has_a_slash() {
+        let definition: RoutingRuleDefinition =
+            serde_json::from_str(r#"{"field": "http/status", "equals": 500, "index": "errors"}"#).unwrap();
+        let rule = RecordRouter::compile_rule(definition, &vec![]).unwrap();
+        let record = Record { data: r#"{"http/status": 500}"#.as_bytes().to_vec(), ..Default::default() };
+
+        assert_eq!(RecordRouter::route_record(&vec![rule], &record),
+                   Route::Deliver { sinks: None, index: Some("errors".to_string()) });
+    }
+
+    #[test]
+    fn rejects_unknown_actions_and_sinks() {
+        let sink_names = vec!["s3".to_string(), "http".to_string()];
+        let compile = |definition: &str| {
+            RecordRouter::compile_rule(serde_json::from_str(definition).unwrap(), &sink_names).map(|rule| rule.route)
+        };
+
+        assert_eq!(compile(r#"{"partition_key": "^health-", "action": "drop"}"#), Some(Route::Drop));
+        assert_eq!(compile(r#"{"action": "deliver", "sinks": ["http"]}"#),
+                   Some(Route::Deliver { sinks: Some(vec!["http".to_string()]), index: None }));
+        assert!(compile(r#"{"partition_key": "^health-", "action": "dorp"}"#).is_none());
+        assert!(compile(r#"{"sinks": ["s3", "elasticsearch"]}"#).is_none());
+    }
+
+    #[test]
+    fn fails_without_a_valid_rules_file() {
+        let rules_file_path = ::std::env::temp_dir().join("kcl_record_router_test_rules.json");
+        let sink_names = vec!["s3".to_string()];
+
+        let _ = fs::remove_file(&rules_file_path);
+        assert!(RecordRouter::new(rules_file_path.to_string_lossy().to_string(), sink_names.clone()).is_none());
+
+        fs::write(&rules_file_path, r#"{"rules": [{"action": "dorp"}]}"#).unwrap();
+        assert!(RecordRouter::new(rules_file_path.to_string_lossy().to_string(), sink_names.clone()).is_none());
+
+        fs::write(&rules_file_path, r#"{"rules": [{"partition_key": "^health-", "action": "drop"}]}"#).unwrap();
+        assert!(RecordRouter::new(rules_file_path.to_string_lossy().to_string(), sink_names).is_some());
+        let _ = fs::remove_file(&rules_file_path);
+    }
+}
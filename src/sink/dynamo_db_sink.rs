@@ -0,0 +1,299 @@
+use dynamo_db::dynamo_db_library::DynamoDbLibrary;
+use kinesis_stream::kcl_error::KclError;
+use config::env_config::{get_optional_env_var, get_parsed_env_var_or};
+use sink::record_sink::{get_documents, RecordSink};
+use rusoto_kinesis::Record;
+use rusoto_dynamodb::AttributeValue;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct DynamoDbSinkConfig {
+    pub table_name: String,
+    pub key_attributes: Vec<String>,
+    pub version_attribute: Option<String>,
+    pub max_retries: u32,
+}
+
+impl DynamoDbSinkConfig {
+    /// KCL_DYNAMO_DB_SINK_TABLE enables the sink. KCL_DYNAMO_DB_SINK_KEY_ATTRIBUTES, the hash key
+    /// and the optional range key (e.g. id,updated_at), KCL_DYNAMO_DB_SINK_VERSION_ATTRIBUTE to
+    /// discard out of order events and KCL_DYNAMO_DB_SINK_MAX_RETRIES.
+    pub fn from_env() -> Option<DynamoDbSinkConfig> {
+        let table_name = get_optional_env_var("KCL_DYNAMO_DB_SINK_TABLE");
+        if table_name.is_none() {
+            return None;
+        }
+
+        let key_attributes: Vec<String> = get_optional_env_var("KCL_DYNAMO_DB_SINK_KEY_ATTRIBUTES")
+            .unwrap_or("id".to_string())
+            .split(',')
+            .map(|attribute| attribute.trim().to_string())
+            .filter(|attribute| !attribute.is_empty())
+            .collect();
+
+        if key_attributes.is_empty() || key_attributes.len() > 2 {
+            eprintln!("KCL_DYNAMO_DB_SINK_KEY_ATTRIBUTES should be one or two attributes.");
+            return None;
+        }
+
+        return Some(DynamoDbSinkConfig {
+            table_name: table_name.unwrap(),
+            key_attributes,
+            version_attribute: get_optional_env_var("KCL_DYNAMO_DB_SINK_VERSION_ATTRIBUTE"),
+            max_retries: get_parsed_env_var_or("KCL_DYNAMO_DB_SINK_MAX_RETRIES", 5)
+        });
+    }
+}
+
+/// Materializes the JSON records as DynamoDB items, the latest record of a key wins.
+pub struct DynamoDbSink {
+    dynamo_db_library: DynamoDbLibrary,
+    config: DynamoDbSinkConfig,
+}
+
+impl DynamoDbSink {
+    pub fn new(config: DynamoDbSinkConfig) -> DynamoDbSink {
+        DynamoDbSink {
+            dynamo_db_library: DynamoDbLibrary::new(config.table_name.to_string()),
+            config
+        }
+    }
+
+    /// The items Dynamo rejects, e.g. for a key of the wrong type or a size over 400 KB, are
+    /// skipped, writing them again would fail the same way.
+    pub fn push_documents(&self, documents: &Vec<String>) -> bool {
+        let items = self.get_items(documents);
+
+        if self.config.version_attribute.is_none() {
+            let written = self.dynamo_db_library.batch_write_items(items, self.config.max_retries);
+            if written.is_err() {
+                eprintln!("Can't write the items to {}. {}", self.config.table_name, written.unwrap_err());
+                return false;
+            }
+
+            let number_of_rejected_items = written.unwrap();
+            if number_of_rejected_items > 0 {
+                eprintln!("{} items were rejected by {}.", number_of_rejected_items, self.config.table_name);
+            }
+
+            return true;
+        }
+
+        // BatchWriteItem has no conditions, so versioned items are written one by one.
+        let version_attribute = self.config.version_attribute.clone().unwrap();
+        let mut number_of_discarded_items = 0;
+        for item in items {
+            let written = self.dynamo_db_library.put_item_if_newer_version(
+                item, &self.config.key_attributes, &version_attribute
+            );
+
+            match written {
+                Ok(true) => {}
+                Ok(false) => number_of_discarded_items = number_of_discarded_items + 1,
+                Err(KclError::Service(message)) =>
+                    eprintln!("Skipping an item {} rejects. - {}", self.config.table_name, message),
+                Err(error) => {
+                    eprintln!("Error while writing to Dynamo. {}", error);
+                    return false;
+                }
+            }
+        }
+
+        if number_of_discarded_items > 0 {
+            eprintln!("{} out of order items were discarded for {}.",
+                     number_of_discarded_items, self.config.table_name);
+        }
+
+        return true;
+    }
+
+    /// One item per key, a batch can't hold the same key twice.
+    fn get_items(&self, documents: &Vec<String>) -> Vec<HashMap<String, AttributeValue>> {
+        let mut keys: Vec<Vec<String>> = vec![];
+        let mut items_by_key: HashMap<Vec<String>, HashMap<String, AttributeValue>> = HashMap::new();
+
+        for document in documents {
+            let parsed: Result<Value, _> = serde_json::from_str(document);
+            let fields = match parsed {
+                Ok(Value::Object(fields)) => fields,
+                _ => {
+                    eprintln!("Skipping a non JSON object record for {}.", self.config.table_name);
+                    continue;
+                }
+            };
+
+            // An empty string would be stored as NULL, which DynamoDB rejects for a key along with the whole batch.
+            let key_values: Vec<String> = self.config.key_attributes.iter()
+                .filter_map(|attribute| fields.get(attribute))
+                .filter(|value| value.is_number() || (value.is_string() && value.as_str() != Some("")))
+                .map(|value| value.to_string())
+                .collect();
+
+            if key_values.len() != self.config.key_attributes.len() {
+                eprintln!("Skipping a record without non empty key attributes {:?}.", self.config.key_attributes);
+                continue;
+            }
+
+            if self.config.version_attribute.is_some() &&
+                !fields.contains_key(&self.config.version_attribute.clone().unwrap()) {
+                eprintln!("Skipping a record without the version attribute {}.",
+                         self.config.version_attribute.clone().unwrap());
+                continue;
+            }
+
+            let item: HashMap<String, AttributeValue> = fields.iter().map(|(name, value)| {
+                (name.to_string(), self.dynamo_db_library.get_attribute_value_from_json(value))
+            }).collect();
+
+            if self.is_newer_item(&item, items_by_key.get(&key_values)) {
+                if !items_by_key.contains_key(&key_values) {
+                    keys.push(key_values.clone());
+                }
+
+                items_by_key.insert(key_values, item);
+            }
+        }
+
+        return keys.iter().filter_map(|key| items_by_key.remove(key)).collect();
+    }
+
+    /// Without a version the last record wins, with one the batch keeps the item the conditional
+    /// write would keep.
+    fn is_newer_item(&self, item: &HashMap<String, AttributeValue>,
+                     current_item: Option<&HashMap<String, AttributeValue>>) -> bool {
+        if current_item.is_none() || self.config.version_attribute.is_none() {
+            return true;
+        }
+
+        let version_attribute = self.config.version_attribute.clone().unwrap();
+        let version = item.get(&version_attribute);
+        let current_version = current_item.unwrap().get(&version_attribute);
+        if version.is_none() || current_version.is_none() {
+            return version.is_some();
+        }
+
+        return is_newer_version(version.unwrap(), current_version.unwrap());
+    }
+}
+
+/// The condition of the versioned write, `#version < :version`: numbers compare as numbers and
+/// strings byte by byte, versions of different types never replace each other.
+fn is_newer_version(version: &AttributeValue, current_version: &AttributeValue) -> bool {
+    if version.n.is_some() && current_version.n.is_some() {
+        let version_number = version.n.as_ref().unwrap().parse::<f64>();
+        let current_version_number = current_version.n.as_ref().unwrap().parse::<f64>();
+        if version_number.is_err() || current_version_number.is_err() {
+            return false;
+        }
+
+        return version_number.unwrap() > current_version_number.unwrap();
+    }
+
+    if version.s.is_some() && current_version.s.is_some() {
+        return version.s.as_ref().unwrap() > current_version.s.as_ref().unwrap();
+    }
+
+    return false;
+}
+
+impl RecordSink for DynamoDbSink {
+    fn get_name(&self) -> String {
+        return "dynamo_db".to_string();
+    }
+
+    fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+        return self.push_documents(&get_documents(records));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_dynamo_db_sink(key_attributes: Vec<&str>, version_attribute: Option<&str>) -> DynamoDbSink {
+        return DynamoDbSink::new(DynamoDbSinkConfig {
+            table_name: "items".to_string(),
+            key_attributes: key_attributes.iter().map(|attribute| attribute.to_string()).collect(),
+            version_attribute: version_attribute.map(|attribute| attribute.to_string()),
+            max_retries: 5
+        });
+    }
+
+    fn get_items(dynamo_db_sink: &DynamoDbSink, documents: Vec<&str>) -> Vec<HashMap<String, AttributeValue>> {
+        return dynamo_db_sink.get_items(&documents.iter().map(|document| document.to_string()).collect());
+    }
+
+    fn get_strings(items: &Vec<HashMap<String, AttributeValue>>, attribute: &str) -> Vec<String> {
+        return items.iter()
+            .map(|item| {
+                let value = item.get(attribute).unwrap();
+                value.s.clone().or(value.n.clone()).unwrap()
+            })
+            .collect();
+    }
+
+    #[test]
+    fn keeps_the_last_record_of_each_key_in_first_seen_order() {
+        let dynamo_db_sink = get_dynamo_db_sink(vec!["id"], None);
+
+        let items = get_items(&dynamo_db_sink, vec![
+            r#"{"id": "b", "value": "1"}"#,
+            r#"{"id": "a", "value": "2"}"#,
+            r#"{"id": "b", "value": "3"}"#,
+        ]);
+
+        assert_eq!(get_strings(&items, "id"), vec!["b".to_string(), "a".to_string()]);
+        assert_eq!(get_strings(&items, "value"), vec!["3".to_string(), "2".to_string()]);
+    }
+
+    #[test]
+    fn skips_records_without_valid_key_values() {
+        let dynamo_db_sink = get_dynamo_db_sink(vec!["id", "updated_at"], None);
+
+        let items = get_items(&dynamo_db_sink, vec![
+            "not json",
+            "[1, 2]",
+            r#"{"id": "a"}"#,
+            r#"{"id": "", "updated_at": 1}"#,
+            r#"{"id": true, "updated_at": 1}"#,
+            r#"{"id": {"nested": 1}, "updated_at": 1}"#,
+            r#"{"id": "a", "updated_at": 1}"#,
+            r#"{"id": 7, "updated_at": "2018-10-01"}"#,
+        ]);
+
+        assert_eq!(get_strings(&items, "id"), vec!["a".to_string(), "7".to_string()]);
+    }
+
+    #[test]
+    fn keeps_the_item_the_versioned_write_would_keep() {
+        let dynamo_db_sink = get_dynamo_db_sink(vec!["id"], Some("version"));
+
+        let items = get_items(&dynamo_db_sink, vec![
+            r#"{"id": "numbers", "version": 10, "value": "10"}"#,
+            r#"{"id": "numbers", "version": 9, "value": "9"}"#,
+            r#"{"id": "numbers", "version": 10, "value": "10 again"}"#,
+            r#"{"id": "strings", "version": "2018-10-02", "value": "second"}"#,
+            r#"{"id": "strings", "version": "2018-10-10", "value": "tenth"}"#,
+            r#"{"id": "strings", "version": "2018-10-09", "value": "ninth"}"#,
+            r#"{"id": "mixed", "version": 2, "value": "number"}"#,
+            r#"{"id": "mixed", "version": "3", "value": "string"}"#,
+            r#"{"id": "unversioned", "value": "skipped"}"#,
+        ]);
+
+        assert_eq!(get_strings(&items, "value"), vec!["10".to_string(), "tenth".to_string(), "number".to_string()]);
+    }
+
+    #[test]
+    fn compares_versions_of_the_same_type_only() {
+        let number = |number: &str| AttributeValue { n: Some(number.to_string()), ..Default::default() };
+        let string = |text: &str| AttributeValue { s: Some(text.to_string()), ..Default::default() };
+
+        assert!(is_newer_version(&number("10"), &number("9")));
+        assert!(!is_newer_version(&number("9"), &number("10")));
+        assert!(!is_newer_version(&number("10"), &number("10")));
+        assert!(is_newer_version(&string("b"), &string("a")));
+        assert!(!is_newer_version(&string("10"), &string("9")));
+        assert!(!is_newer_version(&number("10"), &string("9")));
+    }
+}
@@ -0,0 +1,342 @@
+use hyper::*;
+use hyper::header::HeaderValue;
+use hyper::client::HttpConnector;
+use hyper::rt::{Future, Stream};
+use tokio::runtime::{Builder, Runtime};
+use chrono::{DateTime, Utc};
+use config::env_config::{get_env_var_or, get_parsed_env_var_or};
+use sink::record_sink::{get_documents, RecordSink};
+use rusoto_kinesis::Record;
+use retry::retry_policy::{ClassifiedError, ErrorClass, RetryPolicy};
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub struct ElasticSearchSinkConfig {
+    pub bulk_url: String,
+    pub index_prefix: String,
+    pub max_retries: u32,
+}
+
+impl ElasticSearchSinkConfig {
+    /// KCL_ELASTIC_SEARCH_BULK_URL, KCL_ELASTIC_SEARCH_INDEX_PREFIX and KCL_ELASTIC_SEARCH_MAX_RETRIES.
+    pub fn from_env() -> ElasticSearchSinkConfig {
+        ElasticSearchSinkConfig {
+            bulk_url: get_env_var_or("KCL_ELASTIC_SEARCH_BULK_URL", "http://localhost:8081/_bulk"),
+            index_prefix: get_env_var_or("KCL_ELASTIC_SEARCH_INDEX_PREFIX", "index_name"),
+            max_retries: get_parsed_env_var_or("KCL_ELASTIC_SEARCH_MAX_RETRIES", 5)
+        }
+    }
+}
+
+/// Hourly index.
+pub fn get_index_name(index_prefix: &String, date: &DateTime<Utc>) -> String {
+    return format!("{}_{}", index_prefix, date.format("%Y_%m_%d_%H"));
+}
+
+/// The bulk body indexing the documents into the current hourly index, and the number of documents
+/// left out as they aren't JSON. Each document is written on one line, a line break inside a
+/// pretty-printed one would shift the bulk's action and document pairs.
+pub fn get_bulk_body(index_prefix: &String, documents: &Vec<String>) -> (String, usize) {
+    let index_name = get_index_name(index_prefix, &Utc::now());
+    let mut batch: Vec<String> = vec![];
+    let mut number_of_rejected_documents = 0;
+
+    for document in documents {
+        let parsed: Result<Value, _> = ::serde_json::from_str(document);
+        if parsed.is_err() {
+            eprintln!("Skipping a document Elastic search can't index. - {}", parsed.unwrap_err());
+            number_of_rejected_documents = number_of_rejected_documents + 1;
+            continue;
+        }
+
+        batch.push(format!("{{\"index\": {{\"_index\": \"{}\", \"_type\": \"_doc\"}} }}", index_name).to_string());
+        batch.push(parsed.unwrap().to_string());
+    }
+
+    if batch.is_empty() {
+        return (String::new(), number_of_rejected_documents);
+    }
+
+    return (batch.join("\n") + "\n", number_of_rejected_documents);
+}
+
+/// Why a bulk request failed.
+#[derive(Debug)]
+pub enum BulkPushError {
+    Connection(String),
+    Status(StatusCode),
+    /// Some documents were throttled or hit a server error, the rest of the bulk was indexed.
+    FailedItems(usize),
+    /// Elasticsearch refused some documents for another reason than their content, e.g. a read-only index.
+    RejectedItems(String),
+    /// The response can't be matched to the documents, so it's unknown which of them were indexed.
+    InvalidResponse(String),
+}
+
+impl fmt::Display for BulkPushError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BulkPushError::Connection(ref message) => write!(formatter, "{}", message),
+            BulkPushError::Status(status) => write!(formatter, "Elastic search responded with {}", status),
+            BulkPushError::FailedItems(number_of_items) =>
+                write!(formatter, "{} documents weren't indexed", number_of_items),
+            BulkPushError::RejectedItems(ref message) => write!(formatter, "Documents were rejected - {}", message),
+            BulkPushError::InvalidResponse(ref message) => write!(formatter, "Invalid bulk response - {}", message),
+        }
+    }
+}
+
+impl ClassifiedError for BulkPushError {
+    fn get_error_class(&self) -> ErrorClass {
+        match *self {
+            BulkPushError::Connection(_) | BulkPushError::FailedItems(_) => ErrorClass::Transient,
+            BulkPushError::Status(status) if status == StatusCode::TOO_MANY_REQUESTS => ErrorClass::Throttled,
+            BulkPushError::Status(status) if status.is_server_error() => ErrorClass::Transient,
+            BulkPushError::Status(_) | BulkPushError::RejectedItems(_) | BulkPushError::InvalidResponse(_) =>
+                ErrorClass::Permanent,
+        }
+    }
+}
+
+/// One client, pooling its connections on a small runtime of its own, serves every push.
+pub struct ElasticSearchSink {
+    config: ElasticSearchSinkConfig,
+    client: Client<HttpConnector, Body>,
+    _runtime: Runtime,
+    retry_policy: RetryPolicy,
+    is_debug_enabled: bool,
+}
+
+impl ElasticSearchSink {
+    pub fn new(config: ElasticSearchSinkConfig, is_debug_enabled: bool) -> ElasticSearchSink {
+        let runtime = Builder::new().core_threads(1).build().expect("Can't start the Elastic search runtime.");
+        let client = Client::builder().executor(runtime.executor()).build_http();
+
+        // KCL_ELASTIC_SEARCH_MAX_RETRIES is kept, the first push isn't a retry.
+        let retry_policy = RetryPolicy::from_env().with_max_attempts(config.max_retries + 1);
+
+        ElasticSearchSink { config, client, _runtime: runtime, retry_policy, is_debug_enabled }
+    }
+
+    /// Hourly index.
+    pub fn get_index_name(&self, date: &DateTime<Utc>) -> String {
+        return get_index_name(&self.config.index_prefix, date);
+    }
+
+    pub fn get_bulk_body(&self, documents: &Vec<String>) -> (String, usize) {
+        return get_bulk_body(&self.config.index_prefix, documents);
+    }
+
+    /// True once every document is indexed, or rejected for its content. The documents that didn't
+    /// make it into the bulk count as rejected.
+    pub fn push_bulk(&self, bulk: &String, number_of_skipped_documents: usize) -> bool {
+        let indexed = self.index_bulk(bulk);
+        if indexed.is_err() {
+            eprintln!("Error while pushing to Elastic search. - {}", indexed.unwrap_err());
+            return false;
+        }
+
+        let number_of_rejected_documents = indexed.unwrap() + number_of_skipped_documents;
+        if number_of_rejected_documents > 0 {
+            eprintln!("{} documents were rejected by Elastic search.", number_of_rejected_documents);
+        }
+
+        return true;
+    }
+
+    /// Indexes the bulk, retrying the documents that were throttled or hit a server error.
+    /// Returns the number of documents rejected for their content (status 400), which are
+    /// skipped as they would be rejected again.
+    pub fn index_bulk(&self, bulk: &String) -> Result<usize, BulkPushError> {
+        // Elastic search refuses an empty bulk.
+        if bulk.trim().is_empty() {
+            return Ok(0);
+        }
+
+        let mut pending_bulk = bulk.clone();
+        let mut number_of_rejected_documents = 0;
+        self.retry_policy.retry("Pushing to Elastic search", || {
+            self.push_bulk_once(&mut pending_bulk, &mut number_of_rejected_documents)
+        })?;
+
+        return Ok(number_of_rejected_documents);
+    }
+
+    /// Leaves the documents worth retrying in the pending bulk.
+    fn push_bulk_once(&self, pending_bulk: &mut String,
+                      number_of_rejected_documents: &mut usize) -> Result<(), BulkPushError> {
+        let uri: hyper::Uri = self.config.bulk_url.parse().unwrap();
+        let mut req = hyper::Request::new(Body::from(pending_bulk.clone()));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = uri.clone();
+        req.headers_mut().insert("content-type", HeaderValue::from_str("application/json").unwrap());
+        let ret = self.client.request(req).wait();
+
+        if self.is_debug_enabled {
+            eprintln!("pushing to elastic search. - {}", format!("{:?}", ret));
+        }
+
+        if ret.is_err() {
+            return Err(BulkPushError::Connection(ret.unwrap_err().to_string()));
+        }
+
+        let response = ret.unwrap();
+        let status = response.status();
+        if !status.is_success() {
+            return Err(BulkPushError::Status(status));
+        }
+
+        let body = response.into_body().concat2().wait();
+        if body.is_err() {
+            return Err(BulkPushError::Connection(body.unwrap_err().to_string()));
+        }
+
+        let bulk_response: Result<Value, _> = ::serde_json::from_slice(&body.unwrap());
+        if bulk_response.is_err() {
+            return Err(BulkPushError::InvalidResponse(bulk_response.unwrap_err().to_string()));
+        }
+
+        return ElasticSearchSink::handle_bulk_response(
+            &bulk_response.unwrap(), pending_bulk, number_of_rejected_documents
+        );
+    }
+
+    /// Each item of the response answers one action and document pair of the bulk, in order.
+    fn handle_bulk_response(bulk_response: &Value, pending_bulk: &mut String,
+                            number_of_rejected_documents: &mut usize) -> Result<(), BulkPushError> {
+        if !bulk_response["errors"].as_bool().unwrap_or(true) {
+            pending_bulk.clear();
+            return Ok(());
+        }
+
+        let items = bulk_response["items"].as_array();
+        let (retry_bulk, number_of_failed_documents) = {
+            let lines: Vec<&str> = pending_bulk.lines().collect();
+            if items.is_none() || items.unwrap().len() * 2 != lines.len() {
+                return Err(BulkPushError::InvalidResponse(
+                    format!("{} lines in the bulk for the response items", lines.len())
+                ));
+            }
+
+            let mut failed_lines: Vec<&str> = vec![];
+            for (item, action_and_document) in items.unwrap().iter().zip(lines.chunks(2)) {
+                // The item is keyed by its action, e.g. {"index": {"status": 201}}.
+                let result = item.as_object().and_then(|item| item.values().next());
+                let status = result.and_then(|result| result["status"].as_u64()).unwrap_or(0);
+                let error = result.map(|result| result["error"].to_string()).unwrap_or(String::new());
+                if status >= 200 && status < 300 {
+                    continue;
+                }
+
+                if status == 429 || status >= 500 {
+                    failed_lines.extend_from_slice(action_and_document);
+                } else if status == 400 {
+                    eprintln!("Skipping a document Elastic search can't index. - {}", error);
+                    *number_of_rejected_documents = *number_of_rejected_documents + 1;
+                } else {
+                    return Err(BulkPushError::RejectedItems(format!("{} - {}", status, error)));
+                }
+            }
+
+            (failed_lines.join("\n") + "\n", failed_lines.len() / 2)
+        };
+
+        *pending_bulk = retry_bulk;
+        if number_of_failed_documents > 0 {
+            return Err(BulkPushError::FailedItems(number_of_failed_documents));
+        }
+
+        return Ok(());
+    }
+}
+
+impl RecordSink for ElasticSearchSink {
+    fn get_name(&self) -> String {
+        return "elastic_search".to_string();
+    }
+
+    fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+        let (bulk, number_of_skipped_documents) = self.get_bulk_body(&get_documents(records));
+
+        return self.push_bulk(&bulk, number_of_skipped_documents);
+    }
+
+    fn push_records_to_index(&self, _shard_id: &String, records: &Vec<Record>, index: &String) -> bool {
+        let (bulk, number_of_skipped_documents) = get_bulk_body(index, &get_documents(records));
+
+        return self.push_bulk(&bulk, number_of_skipped_documents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_documents_worth_retrying() {
+        let mut pending_bulk = "{\"index\": {}}\n{\"a\": 1}\n{\"index\": {}}\n{\"a\": 2}\n{\"index\": {}}\n{\"a\": 3}\n".to_string();
+        let bulk_response: Value = ::serde_json::from_str(r#"{"errors": true, "items": [
+            {"index": {"status": 201}},
+            {"index": {"status": 429, "error": {"type": "es_rejected_execution_exception"}}},
+            {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}}
+        ]}"#).unwrap();
+        let mut number_of_rejected_documents = 0;
+
+        let handled = ElasticSearchSink::handle_bulk_response(
+            &bulk_response, &mut pending_bulk, &mut number_of_rejected_documents
+        );
+
+        assert_eq!(handled.unwrap_err().get_error_class(), ErrorClass::Transient);
+        assert_eq!(pending_bulk, "{\"index\": {}}\n{\"a\": 2}\n");
+        assert_eq!(number_of_rejected_documents, 1);
+    }
+
+    #[test]
+    fn fails_the_bulk_on_other_rejections() {
+        let mut pending_bulk = "{\"index\": {}}\n{\"a\": 1}\n".to_string();
+        let bulk_response: Value = ::serde_json::from_str(r#"{"errors": true, "items": [
+            {"index": {"status": 403, "error": {"type": "cluster_block_exception"}}}
+        ]}"#).unwrap();
+        let mut number_of_rejected_documents = 0;
+
+        let handled = ElasticSearchSink::handle_bulk_response(
+            &bulk_response, &mut pending_bulk, &mut number_of_rejected_documents
+        );
+
+        assert_eq!(handled.unwrap_err().get_error_class(), ErrorClass::Permanent);
+        assert_eq!(number_of_rejected_documents, 0);
+    }
+
+    #[test]
+    fn writes_each_document_on_one_line() {
+        let documents = vec![
+            "{\n  \"message\": \"multi\\nline\",\n  \"level\": \"info\"\n}".to_string(),
+            "not json".to_string(),
+            "{\"a\": 1}".to_string(),
+        ];
+
+        let (bulk, number_of_rejected_documents) = get_bulk_body(&"logs".to_string(), &documents);
+
+        let lines: Vec<&str> = bulk.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("{\"index\": {\"_index\": \"logs_"));
+        assert_eq!(lines[1], "{\"level\":\"info\",\"message\":\"multi\\nline\"}");
+        assert_eq!(lines[3], "{\"a\":1}");
+        assert_eq!(number_of_rejected_documents, 1);
+        assert_eq!(get_bulk_body(&"logs".to_string(), &vec!["not json".to_string()]), (String::new(), 1));
+    }
+
+    #[test]
+    fn fails_on_a_response_without_items() {
+        let mut pending_bulk = "{\"index\": {}}\n{\"a\": 1}\n".to_string();
+        let bulk_response: Value = ::serde_json::from_str("{}").unwrap();
+        let mut number_of_rejected_documents = 0;
+
+        let handled = ElasticSearchSink::handle_bulk_response(
+            &bulk_response, &mut pending_bulk, &mut number_of_rejected_documents
+        );
+
+        assert!(handled.is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use libflate::gzip::Encoder;
+use chrono::Utc;
+use chrono::format::{Item, StrftimeItems};
+use uuid::Uuid;
+use config::env_config::{get_bool_env_var, get_env_var_or, get_optional_env_var, get_parsed_env_var_or};
+use sink::record_sink::{get_documents, RecordSink};
+use rusoto_kinesis::Record;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct FileSinkConfig {
+    pub directory: String,
+    pub path_template: String,
+    pub max_file_bytes: u64,
+    pub max_file_age: Duration,
+    pub compress: bool,
+}
+
+impl FileSinkConfig {
+    /// KCL_FILE_SINK_DIRECTORY enables the sink. KCL_FILE_SINK_PATH_TEMPLATE, a chrono format
+    /// where {uuid} is replaced by a random id (the S3 key layout by default),
+    /// KCL_FILE_SINK_MAX_FILE_BYTES, KCL_FILE_SINK_MAX_FILE_SECONDS and KCL_FILE_SINK_GZIP.
+    pub fn from_env() -> Option<FileSinkConfig> {
+        let directory = get_optional_env_var("KCL_FILE_SINK_DIRECTORY");
+        if directory.is_none() {
+            return None;
+        }
+
+        let path_template = get_env_var_or("KCL_FILE_SINK_PATH_TEMPLATE", "%Y/%m/%d/%H/%M/%S_{uuid}.json");
+        if !is_valid_path_template(&path_template) {
+            eprintln!("KCL_FILE_SINK_PATH_TEMPLATE {} isn't a valid chrono format.", path_template);
+            return None;
+        }
+
+        return Some(FileSinkConfig {
+            directory: directory.unwrap(),
+            path_template,
+            max_file_bytes: get_parsed_env_var_or("KCL_FILE_SINK_MAX_FILE_BYTES", 128 * 1024 * 1024),
+            max_file_age: Duration::from_secs(get_parsed_env_var_or("KCL_FILE_SINK_MAX_FILE_SECONDS", 3600)),
+            compress: get_bool_env_var("KCL_FILE_SINK_GZIP")
+        });
+    }
+}
+
+/// chrono panics while formatting an invalid specifier, so it's checked once upfront.
+fn is_valid_path_template(path_template: &str) -> bool {
+    return StrftimeItems::new(path_template).all(|item| match item {
+        Item::Error => false,
+        _ => true,
+    });
+}
+
+struct OpenFile {
+    path: PathBuf,
+    file: File,
+    opened_at: Instant,
+    size: u64,
+}
+
+/// Writes the records as JSON lines to rolling files on disk. Every push is synced to disk
+/// before it returns, so a checkpoint never gets ahead of the files.
+pub struct FileSink {
+    config: FileSinkConfig,
+    open_file: Mutex<Option<OpenFile>>,
+}
+
+impl FileSink {
+    pub fn new(config: FileSinkConfig) -> FileSink {
+        FileSink { config, open_file: Mutex::new(None) }
+    }
+
+    pub fn push_documents(&self, documents: &Vec<String>) -> bool {
+        if documents.is_empty() {
+            return true;
+        }
+
+        let lines = documents.join("\n") + "\n";
+        let mut bytes = lines.into_bytes();
+
+        // Every push is a gzip member of its own, concatenated members are still one valid gzip file.
+        if self.config.compress {
+            let compressed = FileSink::compress(&bytes);
+            if compressed.is_none() {
+                return false;
+            }
+
+            bytes = compressed.unwrap();
+        }
+
+        let mut open_file_guard = self.open_file.lock().unwrap();
+        let should_rotate = match *open_file_guard {
+            Some(ref open_file) => open_file.size >= self.config.max_file_bytes ||
+                open_file.opened_at.elapsed() >= self.config.max_file_age,
+            None => true,
+        };
+
+        if should_rotate {
+            if open_file_guard.is_some() {
+                eprintln!("Rotating {:?}.", open_file_guard.as_ref().unwrap().path);
+            }
+
+            *open_file_guard = self.open_new_file();
+            if open_file_guard.is_none() {
+                return false;
+            }
+        }
+
+        let written = {
+            let open_file = open_file_guard.as_mut().unwrap();
+            let written = open_file.file.write_all(&bytes).and_then(|_| open_file.file.sync_data());
+            if written.is_err() {
+                eprintln!("Can't write to {:?}. - {:?}", open_file.path, written.unwrap_err());
+                false
+            } else {
+                open_file.size = open_file.size + bytes.len() as u64;
+                true
+            }
+        };
+
+        // A failed file is left behind, the next push starts a new one.
+        if !written {
+            *open_file_guard = None;
+        }
+
+        return written;
+    }
+
+    fn open_new_file(&self) -> Option<OpenFile> {
+        let mut relative_path = Utc::now().format(&self.config.path_template).to_string()
+            .replace("{uuid}", &Uuid::new_v4().to_string());
+        if self.config.compress {
+            relative_path = relative_path + ".gz";
+        }
+
+        let path = PathBuf::from(&self.config.directory).join(relative_path);
+        if path.parent().is_some() {
+            let created = fs::create_dir_all(path.parent().unwrap());
+            if created.is_err() {
+                eprintln!("Can't create the directory of {:?}. - {:?}", path, created.unwrap_err());
+                return None;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path);
+        if file.is_err() {
+            eprintln!("Can't open {:?}. - {:?}", path, file.unwrap_err());
+            return None;
+        }
+
+        return Some(OpenFile { path, file: file.unwrap(), opened_at: Instant::now(), size: 0 });
+    }
+
+    fn compress(bytes: &Vec<u8>) -> Option<Vec<u8>> {
+        let encoder = Encoder::new(Vec::new());
+        if encoder.is_err() {
+            return None;
+        }
+
+        let mut encoder = encoder.unwrap();
+        if encoder.write_all(bytes).is_err() {
+            return None;
+        }
+
+        let compressed = encoder.finish().into_result();
+        if compressed.is_err() {
+            eprintln!("Can't compress the records. - {:?}", compressed.unwrap_err());
+            return None;
+        }
+
+        return Some(compressed.unwrap());
+    }
+}
+
+impl RecordSink for FileSink {
+    fn get_name(&self) -> String {
+        return "file".to_string();
+    }
+
+    fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+        return self.push_documents(&get_documents(records));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libflate::gzip::MultiDecoder;
+    use std::env;
+    use std::io::Read;
+
+    fn get_file_sink(max_file_bytes: u64, max_file_age: Duration, compress: bool) -> FileSink {
+        let directory = env::temp_dir().join(format!("kcl_file_sink_test_{}", Uuid::new_v4()));
+        return FileSink::new(FileSinkConfig {
+            directory: directory.to_str().unwrap().to_string(),
+            path_template: "{uuid}.json".to_string(),
+            max_file_bytes,
+            max_file_age,
+            compress
+        });
+    }
+
+    fn get_file_paths(file_sink: &FileSink) -> Vec<PathBuf> {
+        return fs::read_dir(&file_sink.config.directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+    }
+
+    fn read_file(file_path: &PathBuf) -> String {
+        let mut contents = String::new();
+        File::open(file_path).unwrap().read_to_string(&mut contents).unwrap();
+        return contents;
+    }
+
+    fn push(file_sink: &FileSink, documents: Vec<&str>) {
+        let documents: Vec<String> = documents.iter().map(|document| document.to_string()).collect();
+        assert!(file_sink.push_documents(&documents));
+    }
+
+    #[test]
+    fn rejects_invalid_path_templates() {
+        assert!(is_valid_path_template("%Y/%m/%d/%H/%M/%S_{uuid}.json"));
+        assert!(is_valid_path_template("records_{uuid}.json"));
+        assert!(!is_valid_path_template("%Y/%Q_{uuid}.json"));
+        assert!(!is_valid_path_template("%Y/{uuid}.json%"));
+    }
+
+    #[test]
+    fn appends_to_the_open_file_under_the_thresholds() {
+        let file_sink = get_file_sink(1024, Duration::from_secs(3600), false);
+
+        push(&file_sink, vec![r#"{"id": 1}"#]);
+        push(&file_sink, vec![r#"{"id": 2}"#, r#"{"id": 3}"#]);
+
+        let file_paths = get_file_paths(&file_sink);
+        assert_eq!(file_paths.len(), 1);
+        assert_eq!(read_file(&file_paths[0]), "{\"id\": 1}\n{\"id\": 2}\n{\"id\": 3}\n");
+    }
+
+    #[test]
+    fn rotates_files_by_size() {
+        let file_sink = get_file_sink(20, Duration::from_secs(3600), false);
+
+        // 20 bytes are only reached after the second push, the third one opens a new file.
+        push(&file_sink, vec![r#"{"id": 1}"#]);
+        push(&file_sink, vec![r#"{"id": 2}"#]);
+        push(&file_sink, vec![r#"{"id": 3}"#]);
+
+        let mut contents: Vec<String> = get_file_paths(&file_sink).iter()
+            .map(|file_path| read_file(file_path))
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["{\"id\": 1}\n{\"id\": 2}\n".to_string(), "{\"id\": 3}\n".to_string()]);
+    }
+
+    #[test]
+    fn rotates_files_by_age() {
+        let file_sink = get_file_sink(1024, Duration::from_secs(0), false);
+
+        push(&file_sink, vec![r#"{"id": 1}"#]);
+        push(&file_sink, vec![r#"{"id": 2}"#]);
+
+        assert_eq!(get_file_paths(&file_sink).len(), 2);
+    }
+
+    #[test]
+    fn concatenates_gzip_members_into_one_file() {
+        let file_sink = get_file_sink(1024, Duration::from_secs(3600), true);
+
+        push(&file_sink, vec![r#"{"id": 1}"#]);
+        push(&file_sink, vec![r#"{"id": 2}"#]);
+
+        let file_paths = get_file_paths(&file_sink);
+        assert_eq!(file_paths.len(), 1);
+        assert_eq!(file_paths[0].extension().unwrap(), "gz");
+
+        let mut decoder = MultiDecoder::new(File::open(&file_paths[0]).unwrap()).unwrap();
+        let mut lines = String::new();
+        decoder.read_to_string(&mut lines).unwrap();
+        assert_eq!(lines, "{\"id\": 1}\n{\"id\": 2}\n");
+    }
+}
@@ -0,0 +1,380 @@
+use rusoto_s3::S3Client;
+use rusoto_glacier::{AbortMultipartUploadInput, CompleteMultipartUploadInput, DeleteArchiveInput, Glacier,
+                     GlacierClient, InitiateMultipartUploadInput, UploadArchiveInput, UploadMultipartPartInput};
+use rusoto_core::Region;
+use dynamo_db::dynamo_db_library::DynamoDbLibrary;
+use kinesis_stream::kcl_error::KclError;
+use retry::retry_policy::RetryPolicy;
+use sink::s3_sink::{S3Sink, S3SinkConfig};
+use config::env_config::{get_bool_env_var, get_env_var_or, get_parsed_env_var_or};
+use chrono::{DateTime, Duration, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use zip::ZipWriter;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+use std::io::{Cursor, Write};
+use std::thread;
+use std::time;
+
+const GLACIER_TREE_HASH_CHUNK_SIZE: usize = 1024 * 1024;
+/// Larger archives are uploaded in parts of this size, a power of two megabytes as Glacier requires.
+const GLACIER_PART_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct GlacierSinkConfig {
+    pub vault_name: String,
+    pub account_id: String,
+    pub inventory_table_name: String,
+    pub delay_hours: i64,
+    pub lookback_hours: i64,
+    pub claim_timeout_minutes: i64,
+    pub delete_from_s3: bool,
+}
+
+impl GlacierSinkConfig {
+    /// KCL_GLACIER_VAULT_NAME enables the cold archive, KCL_GLACIER_ACCOUNT_ID ("-" is the
+    /// caller's account), KCL_GLACIER_INVENTORY_TABLE, KCL_GLACIER_DELAY_HOURS (hours to wait
+    /// before an hour is considered complete), KCL_GLACIER_LOOKBACK_HOURS, KCL_GLACIER_CLAIM_TIMEOUT_MINUTES
+    /// (after which the hour claimed by a worker that crashed is archived again) and KCL_GLACIER_DELETE_FROM_S3.
+    pub fn from_env() -> Option<GlacierSinkConfig> {
+        let vault_name = get_env_var_or("KCL_GLACIER_VAULT_NAME", "");
+        if vault_name.is_empty() {
+            return None;
+        }
+
+        return Some(GlacierSinkConfig {
+            vault_name,
+            account_id: get_env_var_or("KCL_GLACIER_ACCOUNT_ID", "-"),
+            inventory_table_name: get_env_var_or("KCL_GLACIER_INVENTORY_TABLE", "kcl_glacier_inventory"),
+            delay_hours: get_parsed_env_var_or("KCL_GLACIER_DELAY_HOURS", 2),
+            lookback_hours: get_parsed_env_var_or("KCL_GLACIER_LOOKBACK_HOURS", 24),
+            claim_timeout_minutes: get_parsed_env_var_or("KCL_GLACIER_CLAIM_TIMEOUT_MINUTES", 60),
+            delete_from_s3: get_bool_env_var("KCL_GLACIER_DELETE_FROM_S3")
+        });
+    }
+}
+
+/// Rolls the hourly S3 batches into one zip archive per hour and uploads it to a Glacier vault.
+/// Every archived hour has an inventory record, which is also the lock between workers.
+pub struct GlacierSink {
+    s3_sink: S3Sink,
+    glacier_client: GlacierClient,
+    inventory: DynamoDbLibrary,
+    retry_policy: RetryPolicy,
+    config: GlacierSinkConfig,
+}
+
+impl GlacierSink {
+    pub fn new(s3_config: S3SinkConfig, config: GlacierSinkConfig) -> GlacierSink {
+        let region = Region::EuWest1;
+        GlacierSink {
+            s3_sink: S3Sink::new(S3Client::new(region.clone()), s3_config),
+            glacier_client: GlacierClient::new(region),
+            inventory: DynamoDbLibrary::new(config.inventory_table_name.to_string()),
+            retry_policy: RetryPolicy::from_env(),
+            config
+        }
+    }
+
+    /// Check the complete hours every 10 minutes, forever.
+    pub fn run(&self, worker_id: &String) {
+        loop {
+            let now = Utc::now();
+            for hours_ago in self.config.delay_hours..(self.config.delay_hours + self.config.lookback_hours) {
+                self.archive_hour(&(now - Duration::hours(hours_ago)), worker_id);
+            }
+
+            let sleep_time = time::Duration::from_millis(600000);
+            thread::sleep(sleep_time);
+        }
+    }
+
+    fn archive_hour(&self, date: &DateTime<Utc>, worker_id: &String) {
+        let prefix = self.s3_sink.config.get_hour_prefix(date);
+        let status = self.inventory.get_archive_inventory_status(&prefix);
+        if status.is_err() {
+            eprintln!("Can't read the inventory of {}. {}", prefix, status.unwrap_err());
+            return;
+        }
+
+        if status.unwrap() == Some("ARCHIVED".to_string()) {
+            return;
+        }
+
+        let keys = self.s3_sink.list_object_keys(&prefix);
+        if keys.is_none() || keys.clone().unwrap().is_empty() {
+            return;
+        }
+
+        let claim_expires_at = (Utc::now() + Duration::minutes(self.config.claim_timeout_minutes)).timestamp();
+        let claim = self.inventory.add_archive_inventory_claim(&prefix, worker_id, claim_expires_at);
+        match claim {
+            Ok(true) => {}
+            // Another worker archives it, or just did.
+            Ok(false) => return,
+            Err(error) => {
+                eprintln!("Archive {} can't be claimed. {}", prefix, error);
+                return;
+            }
+        }
+
+        let keys = keys.unwrap();
+        let archive = self.build_archive(&keys);
+        if archive.is_none() {
+            self.release_claim(&prefix);
+            return;
+        }
+
+        let archive = archive.unwrap();
+        let checksum = GlacierSink::get_tree_hash(&archive);
+        let upload_result = self.upload_archive(&prefix, &archive, &checksum);
+        if upload_result.is_err() {
+            eprintln!("Can't upload the archive {} to Glacier. {}", prefix, upload_result.unwrap_err());
+            self.release_claim(&prefix);
+            return;
+        }
+
+        // The archive id is recorded before anything else, an archive missing from the inventory is lost.
+        let archive_id = upload_result.unwrap();
+        let recorded = self.inventory.complete_archive_inventory_record(
+            &prefix, &archive_id, &self.config.vault_name, keys.len(), archive.len(), &checksum
+        );
+
+        if recorded.is_err() {
+            eprintln!("Can't record the archive {} of {} - deleting it. {}", archive_id, prefix, recorded.unwrap_err());
+            self.delete_archive(&prefix, &archive_id);
+            self.release_claim(&prefix);
+            return;
+        }
+
+        eprintln!("Archived {} objects of {} to Glacier - {}.", keys.len(), prefix, archive_id);
+        if self.config.delete_from_s3 {
+            for key in &keys {
+                self.s3_sink.delete_object(key);
+            }
+        }
+    }
+
+    /// Uploads the archive at once, or in parts past GLACIER_PART_SIZE, and returns its id.
+    fn upload_archive(&self, prefix: &String, archive: &Vec<u8>, checksum: &String) -> Result<String, KclError> {
+        let archive_description = Some(format!("{}{}", self.s3_sink.config.bucket_name, prefix));
+        if archive.len() <= GLACIER_PART_SIZE {
+            let upload_archive_input = UploadArchiveInput {
+                account_id: self.config.account_id.to_string(),
+                archive_description,
+                body: Some(archive.clone()),
+                checksum: Some(checksum.to_string()),
+                vault_name: self.config.vault_name.to_string()
+            };
+
+            let output = self.retry_policy.retry("Uploading to Glacier", || {
+                self.glacier_client.upload_archive(upload_archive_input.clone()).sync().map_err(KclError::from)
+            })?;
+            return Ok(output.archive_id.unwrap_or(String::new()));
+        }
+
+        let initiate_multipart_upload_input = InitiateMultipartUploadInput {
+            account_id: self.config.account_id.to_string(),
+            archive_description,
+            part_size: Some(GLACIER_PART_SIZE.to_string()),
+            vault_name: self.config.vault_name.to_string()
+        };
+
+        let initiated = self.retry_policy.retry("Starting a Glacier upload", || {
+            self.glacier_client.initiate_multipart_upload(initiate_multipart_upload_input.clone()).sync()
+                .map_err(KclError::from)
+        })?;
+        let upload_id = initiated.upload_id.unwrap_or(String::new());
+
+        let complete_multipart_upload_input = CompleteMultipartUploadInput {
+            account_id: self.config.account_id.to_string(),
+            archive_size: Some(archive.len().to_string()),
+            checksum: Some(checksum.to_string()),
+            upload_id: upload_id.to_string(),
+            vault_name: self.config.vault_name.to_string()
+        };
+
+        let completed = self.upload_parts(&upload_id, archive).and_then(|_| {
+            self.retry_policy.retry("Completing a Glacier upload", || {
+                self.glacier_client.complete_multipart_upload(complete_multipart_upload_input.clone()).sync()
+                    .map_err(KclError::from)
+            })
+        });
+
+        if completed.is_err() {
+            // Glacier keeps the parts of an upload until it's aborted.
+            let aborted = self.glacier_client.abort_multipart_upload(AbortMultipartUploadInput {
+                account_id: self.config.account_id.to_string(),
+                upload_id: upload_id.to_string(),
+                vault_name: self.config.vault_name.to_string()
+            }).sync();
+
+            if aborted.is_err() {
+                eprintln!("Can't abort the Glacier upload {} of {}. - {:?}", upload_id, prefix, aborted.unwrap_err());
+            }
+        }
+
+        return completed.map(|output| output.archive_id.unwrap_or(String::new()));
+    }
+
+    fn upload_parts(&self, upload_id: &String, archive: &Vec<u8>) -> Result<(), KclError> {
+        let part_ranges = GlacierSink::get_part_ranges(archive.len(), GLACIER_PART_SIZE);
+        for (part, range) in archive.chunks(GLACIER_PART_SIZE).zip(part_ranges) {
+            let upload_multipart_part_input = UploadMultipartPartInput {
+                account_id: self.config.account_id.to_string(),
+                body: Some(part.to_vec()),
+                checksum: Some(GlacierSink::get_tree_hash(part)),
+                range: Some(range),
+                upload_id: upload_id.to_string(),
+                vault_name: self.config.vault_name.to_string()
+            };
+
+            self.retry_policy.retry("Uploading a part to Glacier", || {
+                self.glacier_client.upload_multipart_part(upload_multipart_part_input.clone()).sync()
+                    .map_err(KclError::from)
+            })?;
+        }
+
+        return Ok(());
+    }
+
+    /// The Content-Range of every part, inclusive and with an unknown total as Glacier expects.
+    fn get_part_ranges(archive_size: usize, part_size: usize) -> Vec<String> {
+        let mut part_ranges = vec![];
+        let mut start = 0;
+        while start < archive_size {
+            let end = ::std::cmp::min(start + part_size, archive_size);
+            part_ranges.push(format!("bytes {}-{}/*", start, end - 1));
+            start = end;
+        }
+
+        return part_ranges;
+    }
+
+    fn delete_archive(&self, prefix: &String, archive_id: &String) {
+        let deleted = self.glacier_client.delete_archive(DeleteArchiveInput {
+            account_id: self.config.account_id.to_string(),
+            archive_id: archive_id.to_string(),
+            vault_name: self.config.vault_name.to_string()
+        }).sync();
+
+        if deleted.is_err() {
+            eprintln!("Can't delete the archive {} of {} from {}, it's missing from the inventory. - {:?}",
+                     archive_id, prefix, self.config.vault_name, deleted.unwrap_err());
+        }
+    }
+
+    fn release_claim(&self, prefix: &String) {
+        let released = self.inventory.release_archive_inventory_claim(prefix);
+        if released.is_err() {
+            eprintln!("Error while writing to Dynamo. {}", released.unwrap_err());
+        }
+    }
+
+    fn build_archive(&self, keys: &Vec<String>) -> Option<Vec<u8>> {
+        let mut zip_writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        for key in keys {
+            let object = self.s3_sink.get_object(key);
+            if object.is_none() {
+                return None;
+            }
+
+            let started = zip_writer.start_file(key.to_string(), options);
+            if started.is_err() {
+                eprintln!("Can't add {} to the archive. - {:?}", key, started.unwrap_err());
+                return None;
+            }
+
+            let written = zip_writer.write_all(&object.unwrap());
+            if written.is_err() {
+                eprintln!("Can't add {} to the archive. - {:?}", key, written.unwrap_err());
+                return None;
+            }
+        }
+
+        let finished = zip_writer.finish();
+        if finished.is_err() {
+            eprintln!("Can't finish the archive. - {:?}", finished.unwrap_err());
+            return None;
+        }
+
+        return Some(finished.unwrap().into_inner());
+    }
+
+    /// The SHA256 tree hash Glacier expects for an upload, over 1 MB chunks.
+    fn get_tree_hash(data: &[u8]) -> String {
+        let mut hashes: Vec<Vec<u8>> = data.chunks(GLACIER_TREE_HASH_CHUNK_SIZE)
+            .map(|chunk| GlacierSink::get_sha256(&[chunk]))
+            .collect();
+
+        if hashes.is_empty() {
+            hashes.push(GlacierSink::get_sha256(&[data]));
+        }
+
+        while hashes.len() > 1 {
+            hashes = hashes.chunks(2).map(|pair| {
+                if pair.len() == 1 {
+                    return pair[0].clone();
+                }
+
+                GlacierSink::get_sha256(&[pair[0].as_slice(), pair[1].as_slice()])
+            }).collect();
+        }
+
+        return hashes[0].iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join("");
+    }
+
+    fn get_sha256(parts: &[&[u8]]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.input(part);
+        }
+
+        let mut hash = vec![0; hasher.output_bytes()];
+        hasher.result(&mut hash);
+        return hash;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_data(size: usize) -> Vec<u8> {
+        return (0..size).map(|index| (index % 251) as u8).collect();
+    }
+
+    #[test]
+    fn hashes_data_under_a_chunk_as_its_sha256() {
+        assert_eq!(GlacierSink::get_tree_hash(&[]),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(GlacierSink::get_tree_hash(b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn hashes_multi_megabyte_data_as_a_tree() {
+        assert_eq!(GlacierSink::get_tree_hash(&get_data(4 * GLACIER_TREE_HASH_CHUNK_SIZE)),
+                   "c7d749cd55cefa8ed8fbc208202e18d019b7701f29886f7c5d662d77b3903833");
+
+        // Six chunks, the odd node of the second level is carried up as is.
+        let data = get_data(5 * GLACIER_TREE_HASH_CHUNK_SIZE + 512);
+        assert_eq!(GlacierSink::get_tree_hash(&data),
+                   "166f42f0982e892e2b4a78c4d71fd8145cf1bded0ac4623aae13406b69714994");
+        assert_ne!(GlacierSink::get_tree_hash(&data),
+                   "6d68497298d207b71c217bce7f24f2e715e7f08a136d35d6bab0adb9f5686fff");
+    }
+
+    #[test]
+    fn gets_the_inclusive_range_of_every_part() {
+        assert_eq!(GlacierSink::get_part_ranges(10, 4),
+                   vec!["bytes 0-3/*".to_string(), "bytes 4-7/*".to_string(), "bytes 8-9/*".to_string()]);
+        assert_eq!(GlacierSink::get_part_ranges(8, 4),
+                   vec!["bytes 0-3/*".to_string(), "bytes 4-7/*".to_string()]);
+        assert_eq!(GlacierSink::get_part_ranges(GLACIER_PART_SIZE + 1, GLACIER_PART_SIZE),
+                   vec!["bytes 0-67108863/*".to_string(), "bytes 67108864-67108864/*".to_string()]);
+    }
+}
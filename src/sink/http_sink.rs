@@ -0,0 +1,337 @@
+use hyper::*;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::client::HttpConnector;
+use hyper::rt::Future;
+use hyper_tls::HttpsConnector;
+use futures::sync::oneshot;
+use tokio::runtime::{Builder, Runtime};
+use tokio::timer::Timeout;
+use chrono::Utc;
+use b64::{ToBase64, STANDARD};
+use serde_json::Value;
+use config::env_config::{get_env_var_or, get_optional_env_var, get_parsed_env_var_or};
+use sink::record_sink::{get_documents, RecordSink};
+use rusoto_kinesis::Record;
+use std::thread;
+use std::time;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HttpBatchFormat {
+    JsonArray,
+    NdJson,
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpSinkConfig {
+    pub url: Uri,
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub batch_format: HttpBatchFormat,
+    pub max_batch_size: usize,
+    pub body_template: Option<String>,
+    pub authorization: Option<HeaderValue>,
+    pub max_retries: u32,
+    pub timeout: time::Duration,
+}
+
+impl HttpSinkConfig {
+    /// KCL_HTTP_SINK_URL enables the sink. KCL_HTTP_SINK_METHOD (default POST),
+    /// KCL_HTTP_SINK_HEADERS (a JSON object), KCL_HTTP_SINK_BATCH_FORMAT (json_array | ndjson),
+    /// KCL_HTTP_SINK_MAX_BATCH_SIZE, KCL_HTTP_SINK_BODY_TEMPLATE, KCL_HTTP_SINK_MAX_RETRIES,
+    /// KCL_HTTP_SINK_TIMEOUT_SECONDS (default 30) and either KCL_HTTP_SINK_BEARER_TOKEN or
+    /// KCL_HTTP_SINK_BASIC_AUTH (user:password). Any invalid setting leaves the sink disabled.
+    ///
+    /// The body template wraps every batch, {{records}} is replaced by the batch,
+    /// {{count}} by its size and {{timestamp}} by the current time.
+    pub fn from_env() -> Option<HttpSinkConfig> {
+        let url_option = get_optional_env_var("KCL_HTTP_SINK_URL");
+        if url_option.is_none() {
+            return None;
+        }
+
+        let url = url_option.clone().unwrap().parse::<Uri>();
+        if url.is_err() {
+            eprintln!("Invalid KCL_HTTP_SINK_URL {}.", url_option.unwrap());
+            return None;
+        }
+
+        let method_name = get_env_var_or("KCL_HTTP_SINK_METHOD", "POST").to_uppercase();
+        let method = Method::from_bytes(method_name.as_bytes());
+        if method.is_err() {
+            eprintln!("Invalid KCL_HTTP_SINK_METHOD {}.", method_name);
+            return None;
+        }
+
+        let max_batch_size: usize = get_parsed_env_var_or("KCL_HTTP_SINK_MAX_BATCH_SIZE", 500);
+        if max_batch_size == 0 {
+            eprintln!("KCL_HTTP_SINK_MAX_BATCH_SIZE should be at least 1.");
+            return None;
+        }
+
+        let mut headers = HeaderMap::new();
+        let headers_option = get_optional_env_var("KCL_HTTP_SINK_HEADERS");
+        if headers_option.is_some() {
+            let headers_json: Result<Value, _> = serde_json::from_str(&headers_option.unwrap());
+            let headers_map = match headers_json {
+                Ok(Value::Object(headers_map)) => headers_map,
+                _ => {
+                    eprintln!("KCL_HTTP_SINK_HEADERS should be a JSON object of strings.");
+                    return None;
+                }
+            };
+
+            for (name, value) in headers_map {
+                let header_name = HeaderName::from_bytes(name.as_bytes());
+                let header_value = value.as_str().map(HeaderValue::from_str);
+                match (header_name, header_value) {
+                    (Ok(header_name), Some(Ok(header_value))) => {
+                        headers.insert(header_name, header_value);
+                    }
+                    _ => {
+                        eprintln!("Invalid KCL_HTTP_SINK_HEADERS header {}, it should be a valid name with a string value.", name);
+                        return None;
+                    }
+                }
+            }
+        }
+
+        let mut batch_format = HttpBatchFormat::JsonArray;
+        if get_env_var_or("KCL_HTTP_SINK_BATCH_FORMAT", "json_array").to_lowercase() == "ndjson" {
+            batch_format = HttpBatchFormat::NdJson;
+        }
+
+        let mut authorization = None;
+        let bearer_token = get_optional_env_var("KCL_HTTP_SINK_BEARER_TOKEN");
+        let basic_auth = get_optional_env_var("KCL_HTTP_SINK_BASIC_AUTH");
+        if bearer_token.is_some() {
+            authorization = Some(format!("Bearer {}", bearer_token.unwrap()));
+        } else if basic_auth.is_some() {
+            authorization = Some(format!("Basic {}", basic_auth.unwrap().as_bytes().to_base64(STANDARD)));
+        }
+
+        let authorization_value = authorization.map(|authorization| HeaderValue::from_str(&authorization));
+        if authorization_value.as_ref().map(|authorization| authorization.is_err()).unwrap_or(false) {
+            eprintln!("Invalid KCL_HTTP_SINK_BEARER_TOKEN or KCL_HTTP_SINK_BASIC_AUTH, it can't be sent as a header.");
+            return None;
+        }
+
+        return Some(HttpSinkConfig {
+            url: url.unwrap(),
+            method: method.unwrap(),
+            headers,
+            batch_format,
+            max_batch_size,
+            body_template: get_optional_env_var("KCL_HTTP_SINK_BODY_TEMPLATE"),
+            authorization: authorization_value.map(|authorization| authorization.unwrap()),
+            max_retries: get_parsed_env_var_or("KCL_HTTP_SINK_MAX_RETRIES", 5),
+            timeout: time::Duration::from_secs(get_parsed_env_var_or("KCL_HTTP_SINK_TIMEOUT_SECONDS", 30))
+        });
+    }
+
+    fn get_body(&self, documents: &[String]) -> String {
+        let records = match self.batch_format {
+            HttpBatchFormat::JsonArray => format!("[{}]", documents.join(",")),
+            HttpBatchFormat::NdJson => documents.join("\n") + "\n",
+        };
+
+        if self.body_template.is_none() {
+            return records;
+        }
+
+        return self.body_template.clone().unwrap()
+            .replace("{{count}}", &documents.len().to_string())
+            .replace("{{timestamp}}", &Utc::now().to_rfc3339())
+            .replace("{{records}}", &records);
+    }
+}
+
+/// How a response status is handled.
+#[derive(Debug, PartialEq)]
+enum HttpPushResult {
+    Pushed,
+    /// Timeouts, throttling and server errors, the same batch is sent again.
+    Retryable,
+    /// The collector refused the payload itself (400, 422), sending the same batch again won't help.
+    Rejected,
+    /// Any other status, e.g. an expired token or a wrong url: the push fails and the records are
+    /// read again later.
+    Failed,
+}
+
+fn get_push_result(status: StatusCode) -> HttpPushResult {
+    if status.is_success() {
+        return HttpPushResult::Pushed;
+    }
+
+    if status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS {
+        return HttpPushResult::Retryable;
+    }
+
+    if status == StatusCode::BAD_REQUEST || status == StatusCode::UNPROCESSABLE_ENTITY {
+        return HttpPushResult::Rejected;
+    }
+
+    return HttpPushResult::Failed;
+}
+
+/// Forwards the records to an HTTP or HTTPS collector.
+pub struct HttpSink {
+    config: HttpSinkConfig,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    runtime: Runtime,
+    is_debug_enabled: bool,
+}
+
+impl HttpSink {
+    pub fn new(config: HttpSinkConfig, is_debug_enabled: bool) -> HttpSink {
+        let runtime = Builder::new().core_threads(1).build().expect("Can't start the HTTP sink runtime.");
+        let connector = HttpsConnector::new(1).expect("Can't initialize TLS for the HTTP sink.");
+        let client = Client::builder().executor(runtime.executor()).build(connector);
+
+        HttpSink { config, client, runtime, is_debug_enabled }
+    }
+
+    pub fn push_documents(&self, documents: &Vec<String>) -> bool {
+        for batch in documents.chunks(self.config.max_batch_size) {
+            let body = self.config.get_body(batch);
+            let mut number_of_retrials = 0;
+            let mut push_result = HttpPushResult::Retryable;
+
+            while push_result == HttpPushResult::Retryable && number_of_retrials <= self.config.max_retries {
+                if number_of_retrials > 0 {
+                    let sleep_time = time::Duration::from_millis(1000 * number_of_retrials as u64);
+                    thread::sleep(sleep_time);
+                }
+
+                number_of_retrials = number_of_retrials + 1;
+                push_result = self.push_body(&body);
+            }
+
+            if push_result == HttpPushResult::Rejected {
+                eprintln!("{} records were rejected by {} - skipping them.", batch.len(), self.config.url);
+            } else if push_result != HttpPushResult::Pushed {
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    fn push_body(&self, body: &String) -> HttpPushResult {
+        let mut req = hyper::Request::new(Body::from(body.clone()));
+        *req.method_mut() = self.config.method.clone();
+        *req.uri_mut() = self.config.url.clone();
+
+        let content_type = match self.config.batch_format {
+            HttpBatchFormat::JsonArray => "application/json",
+            HttpBatchFormat::NdJson => "application/x-ndjson",
+        };
+        req.headers_mut().insert("content-type", HeaderValue::from_static(content_type));
+
+        if self.config.authorization.is_some() {
+            req.headers_mut().insert("authorization", self.config.authorization.clone().unwrap());
+        }
+
+        for (name, value) in &self.config.headers {
+            req.headers_mut().insert(name.clone(), value.clone());
+        }
+
+        // The timer only runs on the sink's runtime, a hung collector then counts as a network error.
+        let timeout = self.config.timeout;
+        let request = Timeout::new(self.client.request(req), timeout).map_err(move |error| {
+            if error.is_elapsed() {
+                return format!("no answer within {}s", timeout.as_secs());
+            }
+
+            return error.into_inner().map(|error| error.to_string()).unwrap_or("the timer failed".to_string());
+        });
+        let ret = oneshot::spawn(request, &self.runtime.executor()).wait();
+        if self.is_debug_enabled {
+            eprintln!("pushing to {}. - {}", self.config.url, format!("{:?}", ret));
+        }
+
+        if ret.is_err() {
+            eprintln!("Error while pushing to {}. - {}", self.config.url, ret.unwrap_err());
+            return HttpPushResult::Retryable;
+        }
+
+        let status = ret.unwrap().status();
+        if !status.is_success() {
+            eprintln!("{} responded with {}.", self.config.url, status);
+        }
+
+        return get_push_result(status);
+    }
+}
+
+impl RecordSink for HttpSink {
+    fn get_name(&self) -> String {
+        return "http".to_string();
+    }
+
+    fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+        return self.push_documents(&get_documents(records));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config(batch_format: HttpBatchFormat, body_template: Option<&str>) -> HttpSinkConfig {
+        return HttpSinkConfig {
+            url: "http://localhost:8080/events".parse::<Uri>().unwrap(),
+            method: Method::POST,
+            headers: HeaderMap::new(),
+            batch_format,
+            max_batch_size: 500,
+            body_template: body_template.map(|body_template| body_template.to_string()),
+            authorization: None,
+            max_retries: 5,
+            timeout: time::Duration::from_secs(30)
+        };
+    }
+
+    fn get_documents() -> Vec<String> {
+        return vec![r#"{"id":1}"#.to_string(), r#"{"id":2}"#.to_string()];
+    }
+
+    #[test]
+    fn builds_json_array_and_ndjson_bodies() {
+        let json_array = get_config(HttpBatchFormat::JsonArray, None);
+        let nd_json = get_config(HttpBatchFormat::NdJson, None);
+
+        assert_eq!(json_array.get_body(&get_documents()), r#"[{"id":1},{"id":2}]"#);
+        assert_eq!(nd_json.get_body(&get_documents()), "{\"id\":1}\n{\"id\":2}\n");
+    }
+
+    #[test]
+    fn fills_the_body_template() {
+        let config = get_config(HttpBatchFormat::JsonArray, Some(r#"{"count": {{count}}, "sent_at": "{{timestamp}}", "events": {{records}}}"#));
+
+        let body: Value = serde_json::from_str(&config.get_body(&get_documents())).unwrap();
+
+        assert_eq!(body["count"], Value::from(2));
+        assert_eq!(body["events"][1]["id"], Value::from(2));
+        assert!(body["sent_at"].as_str().unwrap().starts_with(&Utc::now().format("%Y-").to_string()));
+    }
+
+    #[test]
+    fn classifies_the_response_statuses() {
+        assert_eq!(get_push_result(StatusCode::OK), HttpPushResult::Pushed);
+        assert_eq!(get_push_result(StatusCode::ACCEPTED), HttpPushResult::Pushed);
+        for status in vec![StatusCode::REQUEST_TIMEOUT, StatusCode::TOO_MANY_REQUESTS,
+                           StatusCode::INTERNAL_SERVER_ERROR, StatusCode::SERVICE_UNAVAILABLE] {
+            assert_eq!(get_push_result(status), HttpPushResult::Retryable);
+        }
+
+        for status in vec![StatusCode::BAD_REQUEST, StatusCode::UNPROCESSABLE_ENTITY] {
+            assert_eq!(get_push_result(status), HttpPushResult::Rejected);
+        }
+
+        for status in vec![StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN, StatusCode::NOT_FOUND,
+                           StatusCode::METHOD_NOT_ALLOWED, StatusCode::PAYLOAD_TOO_LARGE, StatusCode::MOVED_PERMANENTLY] {
+            assert_eq!(get_push_result(status), HttpPushResult::Failed);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use rusoto_core::Region;
+use rusoto_core::request::HttpClient;
+use rusoto_credential::AutoRefreshingProvider;
+use rusoto_kinesis::*;
+use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use serde_json::Value;
+use uuid::Uuid;
+use config::env_config::{get_bool_env_var, get_env_var_or, get_optional_env_var, get_parsed_env_var_or};
+use sink::record_sink::RecordSink;
+use kinesis_stream::kcl_error::KclError;
+use retry::retry_policy::{ClassifiedError, RetryPolicy};
+use std::thread;
+
+const PUT_RECORDS_MAX_ENTRIES: usize = 500;
+const PUT_RECORDS_MAX_BYTES: usize = 5 * 1024 * 1024;
+/// Kinesis allows 1 MB per record, leave room for the partition keys.
+const AGGREGATED_RECORD_MAX_BYTES: usize = 1000 * 1024;
+const KPL_MAGIC_NUMBER: [u8; 4] = [0xF3, 0x89, 0x9A, 0xC2];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PartitionKeyStrategy {
+    /// Keep the partition key of the source record.
+    Preserve,
+    Random,
+    /// The MD5 of the given JSON field value, records without it get a random key.
+    JsonFieldHash(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct KinesisSinkConfig {
+    pub stream_name: String,
+    pub iam_role_arn: Option<String>,
+    pub partition_key_strategy: PartitionKeyStrategy,
+    pub aggregate: bool,
+    pub max_retries: u32,
+}
+
+impl KinesisSinkConfig {
+    /// KCL_KINESIS_SINK_STREAM_NAME enables the sink. KCL_KINESIS_SINK_IAM_ROLE_ARN for a stream
+    /// in another account, KCL_KINESIS_SINK_PARTITION_KEY (preserve | random | field:<name>),
+    /// KCL_KINESIS_SINK_AGGREGATE for KPL aggregated records and KCL_KINESIS_SINK_MAX_RETRIES.
+    pub fn from_env() -> Option<KinesisSinkConfig> {
+        let stream_name = get_optional_env_var("KCL_KINESIS_SINK_STREAM_NAME");
+        if stream_name.is_none() {
+            return None;
+        }
+
+        let partition_key = get_env_var_or("KCL_KINESIS_SINK_PARTITION_KEY", "preserve");
+        let partition_key_strategy = if partition_key == "random" {
+            PartitionKeyStrategy::Random
+        } else if partition_key.starts_with("field:") {
+            PartitionKeyStrategy::JsonFieldHash(partition_key["field:".len()..].to_string())
+        } else {
+            PartitionKeyStrategy::Preserve
+        };
+
+        return Some(KinesisSinkConfig {
+            stream_name: stream_name.unwrap(),
+            iam_role_arn: get_optional_env_var("KCL_KINESIS_SINK_IAM_ROLE_ARN"),
+            partition_key_strategy,
+            aggregate: get_bool_env_var("KCL_KINESIS_SINK_AGGREGATE"),
+            max_retries: get_parsed_env_var_or("KCL_KINESIS_SINK_MAX_RETRIES", 5)
+        });
+    }
+}
+
+/// Forwards the records to a second stream with PutRecords.
+pub struct KinesisSink {
+    kinesis_client: KinesisClient,
+    config: KinesisSinkConfig,
+    retry_policy: RetryPolicy,
+}
+
+impl KinesisSink {
+    pub fn new(config: KinesisSinkConfig) -> KinesisSink {
+        let region = Region::EuWest1;
+        let kinesis_client = if config.iam_role_arn.is_some() {
+            let sts = StsClient::new(region.clone());
+            let provider =
+                StsAssumeRoleSessionCredentialsProvider::new(
+                    sts,
+                    config.iam_role_arn.clone().unwrap(),
+                    "default".to_owned(),
+                    None, None, None, None
+                );
+
+            let auto_refreshing_provider = AutoRefreshingProvider::new(provider);
+            KinesisClient::new_with(HttpClient::new().unwrap(),
+                                    auto_refreshing_provider.unwrap(),
+                                    region.clone())
+        } else {
+            KinesisClient::new(region.clone())
+        };
+
+        KinesisSink { kinesis_client, config, retry_policy: RetryPolicy::from_env() }
+    }
+
+    pub fn forward_records(&self, records: &Vec<Record>) -> bool {
+        let mut entries: Vec<PutRecordsRequestEntry> = records.iter().map(|record| {
+            PutRecordsRequestEntry {
+                data: record.data.clone(),
+                explicit_hash_key: None,
+                partition_key: self.get_partition_key(record)
+            }
+        }).collect();
+
+        if self.config.aggregate {
+            let can_mix_partition_keys = self.config.partition_key_strategy == PartitionKeyStrategy::Random;
+            entries = KinesisSink::aggregate_entries(entries, can_mix_partition_keys);
+        }
+
+        let mut batch: Vec<PutRecordsRequestEntry> = vec![];
+        let mut batch_size = 0;
+        for entry in entries {
+            let entry_size = entry.data.len() + entry.partition_key.len();
+            if !batch.is_empty() &&
+                (batch.len() == PUT_RECORDS_MAX_ENTRIES || batch_size + entry_size > PUT_RECORDS_MAX_BYTES) {
+                if !self.put_records_with_retries(batch) {
+                    return false;
+                }
+
+                batch = vec![];
+                batch_size = 0;
+            }
+
+            batch_size = batch_size + entry_size;
+            batch.push(entry);
+        }
+
+        if !batch.is_empty() {
+            return self.put_records_with_retries(batch);
+        }
+
+        return true;
+    }
+
+    fn get_partition_key(&self, record: &Record) -> String {
+        match self.config.partition_key_strategy {
+            PartitionKeyStrategy::Preserve => record.partition_key.to_string(),
+            PartitionKeyStrategy::Random => Uuid::new_v4().to_string(),
+            PartitionKeyStrategy::JsonFieldHash(ref field) => {
+                let document: Result<Value, _> = serde_json::from_slice(&record.data);
+                let field_value = document.ok().and_then(|document| document.get(field).cloned());
+                if field_value.is_none() {
+                    return Uuid::new_v4().to_string();
+                }
+
+                let field_string = match field_value.unwrap() {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+
+                let mut md5 = Md5::new();
+                md5.input_str(&field_string);
+                md5.result_str()
+            }
+        }
+    }
+
+    /// Only the failed entries of a partially failed request are sent again.
+    fn put_records_with_retries(&self, mut entries: Vec<PutRecordsRequestEntry>) -> bool {
+        let mut number_of_retries = 0;
+
+        while number_of_retries <= self.config.max_retries {
+            if number_of_retries > 0 {
+                thread::sleep(self.retry_policy.get_delay(number_of_retries));
+            }
+
+            number_of_retries = number_of_retries + 1;
+            let put_records_result = self.kinesis_client.put_records(
+                PutRecordsInput {
+                    records: entries.clone(),
+                    stream_name: self.config.stream_name.to_string()
+                }
+            ).sync().map_err(KclError::from);
+
+            if put_records_result.is_err() {
+                let error = put_records_result.unwrap_err();
+                eprintln!("Error while writing to stream {}. - {}", self.config.stream_name, error);
+                if !error.get_error_class().is_retryable() {
+                    return false;
+                }
+
+                continue;
+            }
+
+            let put_records_output = put_records_result.unwrap();
+            if put_records_output.failed_record_count.unwrap_or(0) == 0 {
+                return true;
+            }
+
+            eprintln!("{} of {} records failed for stream {}.",
+                     put_records_output.failed_record_count.unwrap(), entries.len(),
+                     self.config.stream_name);
+
+            entries = entries.into_iter()
+                .zip(put_records_output.records.into_iter())
+                .filter(|&(_, ref result)| result.error_code.is_some())
+                .map(|(entry, _)| entry)
+                .collect();
+        }
+
+        return false;
+    }
+
+    /// Packs the entries into KPL aggregated records, so consumers using the KPL
+    /// deaggregation get the original records back. An aggregated record is routed by the
+    /// partition key of its first entry, so only the entries sharing a key are packed together
+    /// and each key keeps its shard and its order. Random keys carry no order, they're mixed.
+    fn aggregate_entries(entries: Vec<PutRecordsRequestEntry>,
+                         can_mix_partition_keys: bool) -> Vec<PutRecordsRequestEntry> {
+        if can_mix_partition_keys {
+            return KinesisSink::pack_entries(entries);
+        }
+
+        let mut entries_by_partition_key: Vec<(String, Vec<PutRecordsRequestEntry>)> = vec![];
+        for entry in entries {
+            let position = entries_by_partition_key.iter()
+                .position(|&(ref partition_key, _)| *partition_key == entry.partition_key);
+            match position {
+                Some(position) => entries_by_partition_key[position].1.push(entry),
+                None => entries_by_partition_key.push((entry.partition_key.to_string(), vec![entry])),
+            }
+        }
+
+        let mut aggregated_entries = vec![];
+        for (_, partition_key_entries) in entries_by_partition_key {
+            aggregated_entries.extend(KinesisSink::pack_entries(partition_key_entries));
+        }
+
+        return aggregated_entries;
+    }
+
+    fn pack_entries(entries: Vec<PutRecordsRequestEntry>) -> Vec<PutRecordsRequestEntry> {
+        let mut aggregated_entries = vec![];
+        let mut partition_keys: Vec<String> = vec![];
+        let mut records: Vec<u8> = vec![];
+        let mut partition_keys_size = 0;
+
+        for entry in entries {
+            let entry_size = entry.data.len() + entry.partition_key.len() + 16;
+
+            if !partition_keys.is_empty() &&
+                records.len() + partition_keys_size + entry_size > AGGREGATED_RECORD_MAX_BYTES {
+                aggregated_entries.push(KinesisSink::get_aggregated_entry(&partition_keys, &records));
+                partition_keys = vec![];
+                records = vec![];
+                partition_keys_size = 0;
+            }
+
+            let partition_key_index = partition_keys.iter().position(|key| *key == entry.partition_key);
+            let index = match partition_key_index {
+                Some(index) => index,
+                None => {
+                    partition_keys_size = partition_keys_size + entry.partition_key.len() + 4;
+                    partition_keys.push(entry.partition_key.to_string());
+                    partition_keys.len() - 1
+                }
+            };
+
+            let mut record = vec![];
+            KinesisSink::write_varint_field(&mut record, 1, index as u64);
+            KinesisSink::write_bytes_field(&mut record, 3, &entry.data);
+            KinesisSink::write_bytes_field(&mut records, 3, &record);
+        }
+
+        if !partition_keys.is_empty() {
+            aggregated_entries.push(KinesisSink::get_aggregated_entry(&partition_keys, &records));
+        }
+
+        return aggregated_entries;
+    }
+
+    fn get_aggregated_entry(partition_keys: &Vec<String>, records: &Vec<u8>) -> PutRecordsRequestEntry {
+        let mut message = vec![];
+        for partition_key in partition_keys {
+            KinesisSink::write_bytes_field(&mut message, 1, partition_key.as_bytes());
+        }
+        message.extend_from_slice(records);
+
+        let mut md5 = Md5::new();
+        md5.input(&message);
+        let mut digest = vec![0; md5.output_bytes()];
+        md5.result(&mut digest);
+
+        let mut data = KPL_MAGIC_NUMBER.to_vec();
+        data.extend_from_slice(&message);
+        data.extend_from_slice(&digest);
+
+        PutRecordsRequestEntry {
+            data,
+            explicit_hash_key: None,
+            partition_key: partition_keys[0].to_string()
+        }
+    }
+
+    fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buffer.push((value as u8 & 0x7F) | 0x80);
+            value = value >> 7;
+        }
+        buffer.push(value as u8);
+    }
+
+    fn write_varint_field(buffer: &mut Vec<u8>, field_number: u64, value: u64) {
+        KinesisSink::write_varint(buffer, field_number << 3);
+        KinesisSink::write_varint(buffer, value);
+    }
+
+    fn write_bytes_field(buffer: &mut Vec<u8>, field_number: u64, value: &[u8]) {
+        KinesisSink::write_varint(buffer, (field_number << 3) | 2);
+        KinesisSink::write_varint(buffer, value.len() as u64);
+        buffer.extend_from_slice(value);
+    }
+}
+
+impl RecordSink for KinesisSink {
+    fn get_name(&self) -> String {
+        return "kinesis".to_string();
+    }
+
+    fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+        return self.forward_records(records);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_entry(partition_key: &str, data: &str) -> PutRecordsRequestEntry {
+        PutRecordsRequestEntry {
+            data: data.as_bytes().to_vec(),
+            explicit_hash_key: None,
+            partition_key: partition_key.to_string()
+        }
+    }
+
+    #[test]
+    fn aggregates_only_the_entries_sharing_a_partition_key() {
+        let entries = vec![get_entry("a", "1"), get_entry("b", "2"), get_entry("a", "3")];
+
+        let aggregated_entries = KinesisSink::aggregate_entries(entries, false);
+
+        let partition_keys: Vec<String> =
+            aggregated_entries.iter().map(|entry| entry.partition_key.to_string()).collect();
+        assert_eq!(partition_keys, vec!["a".to_string(), "b".to_string()]);
+        assert!(aggregated_entries.iter().all(|entry| entry.data.starts_with(&KPL_MAGIC_NUMBER)));
+    }
+
+    #[test]
+    fn mixes_random_partition_keys() {
+        let entries = vec![get_entry("a", "1"), get_entry("b", "2"), get_entry("c", "3")];
+
+        let aggregated_entries = KinesisSink::aggregate_entries(entries, true);
+
+        assert_eq!(aggregated_entries.len(), 1);
+    }
+}
@@ -0,0 +1,11 @@
+pub mod s3_sink;
+pub mod parquet_writer;
+pub mod glacier_sink;
+pub mod elastic_search_sink;
+pub mod http_sink;
+pub mod kinesis_sink;
+pub mod dynamo_db_sink;
+pub mod file_sink;
+pub mod stdout_sink;
+pub mod record_sink;
+pub mod sink_fan_out;
@@ -0,0 +1,446 @@
+use parquet::basic::{Type as PhysicalType, LogicalType, Repetition};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use parquet::schema::types::{SchemaDescriptor, Type};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use uuid::Uuid;
+
+/// Converts a buffered flush of JSON documents into a single Parquet row group.
+/// The schema is either given as a Parquet message type or inferred from the
+/// top-level fields of the batch (nested values are kept as JSON strings).
+/// Field names are sanitized into valid column names, e.g. `user-id` becomes `user_id`.
+pub struct ParquetWriter {
+    message_type: Option<String>,
+}
+
+impl ParquetWriter {
+    pub fn new(message_type: Option<String>) -> ParquetWriter {
+        ParquetWriter { message_type }
+    }
+
+    /// None when no document fits the schema. Documents that aren't JSON objects, or miss or mistype
+    /// a required column, are skipped. An error means the file can't be written, e.g. a full disk or
+    /// an invalid schema, the batch is pushed again later.
+    pub fn write_documents(&self, documents: &Vec<String>) -> Result<Option<Vec<u8>>, String> {
+        let mut rows: Vec<Map<String, Value>> = vec![];
+        let mut number_of_skipped_documents = 0;
+        for document in documents {
+            let parsed: Result<Value, _> = serde_json::from_str(document);
+            match parsed {
+                Ok(Value::Object(row)) => rows.push(ParquetWriter::sanitize_row(row)),
+                _ => number_of_skipped_documents = number_of_skipped_documents + 1,
+            }
+        }
+
+        if number_of_skipped_documents > 0 {
+            eprintln!("Skipping {} of {} documents for the Parquet output, they aren't JSON objects.",
+                      number_of_skipped_documents, documents.len());
+        }
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let schema = self.get_schema(&rows)?;
+        let rows = ParquetWriter::get_valid_rows(&SchemaDescriptor::new(schema.clone()), rows);
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let file_path = env::temp_dir().join(format!("{}.parquet", Uuid::new_v4()));
+        let written = ParquetWriter::write_row_group(&file_path, schema, &rows);
+        let mut bytes = vec![];
+        if written.is_ok() {
+            let file = File::open(&file_path);
+            if file.is_err() || file.unwrap().read_to_end(&mut bytes).is_err() {
+                bytes.clear();
+            }
+        }
+
+        let _ = fs::remove_file(&file_path);
+        written?;
+        if bytes.is_empty() {
+            return Err("Can't read the Parquet file back.".to_string());
+        }
+
+        return Ok(Some(bytes));
+    }
+
+    /// The rows with a value of the right type in every required column.
+    fn get_valid_rows(schema_descriptor: &SchemaDescriptor, rows: Vec<Map<String, Value>>) -> Vec<Map<String, Value>> {
+        let number_of_rows = rows.len();
+        let mut first_error = None;
+        let valid_rows: Vec<Map<String, Value>> = rows.into_iter().filter(|row| {
+            for column in schema_descriptor.columns() {
+                if column.self_type().get_basic_info().repetition() != Repetition::REQUIRED {
+                    continue;
+                }
+
+                let is_valid = row.get(column.name())
+                    .map(|value| ParquetWriter::is_column_type(value, column.physical_type()))
+                    .unwrap_or(false);
+                if !is_valid {
+                    if first_error.is_none() {
+                        first_error = Some(format!("required column {} is missing or of another type", column.name()));
+                    }
+
+                    return false;
+                }
+            }
+
+            return true;
+        }).collect();
+
+        if first_error.is_some() {
+            eprintln!("Skipping {} of {} documents for the Parquet output, they don't fit the schema. - {}",
+                      number_of_rows - valid_rows.len(), number_of_rows, first_error.unwrap());
+        }
+
+        return valid_rows;
+    }
+
+    fn is_column_type(value: &Value, physical_type: PhysicalType) -> bool {
+        return match physical_type {
+            PhysicalType::BOOLEAN => to_bool(value).is_some(),
+            PhysicalType::INT32 => to_int32(value).is_some(),
+            PhysicalType::INT64 => to_int64(value).is_some(),
+            PhysicalType::FLOAT => to_float(value).is_some(),
+            PhysicalType::DOUBLE => to_double(value).is_some(),
+            PhysicalType::BYTE_ARRAY => to_byte_array(value).is_some(),
+            // The writer rejects the other types for the whole file.
+            _ => true,
+        };
+    }
+
+    /// Renames the fields to valid column names, the first field wins when two collide.
+    fn sanitize_row(row: Map<String, Value>) -> Map<String, Value> {
+        let mut sanitized_row = Map::new();
+        for (field, value) in row {
+            let column_name = ParquetWriter::get_column_name(&field);
+            if !sanitized_row.contains_key(&column_name) {
+                sanitized_row.insert(column_name, value);
+            }
+        }
+
+        return sanitized_row;
+    }
+
+    /// Letters, digits and underscores, not starting with a digit.
+    fn get_column_name(field: &str) -> String {
+        let mut column_name: String = field.chars()
+            .map(|character| if character.is_ascii_alphanumeric() { character } else { '_' })
+            .collect();
+
+        if column_name.is_empty() || column_name.starts_with(|character: char| character.is_ascii_digit()) {
+            column_name.insert(0, '_');
+        }
+
+        return column_name;
+    }
+
+    fn get_schema(&self, rows: &Vec<Map<String, Value>>) -> Result<Rc<Type>, String> {
+        let message_type = match self.message_type {
+            Some(ref message_type) => message_type.to_string(),
+            None => ParquetWriter::infer_message_type(rows),
+        };
+
+        let schema = parse_message_type(&message_type);
+        if schema.is_err() {
+            return Err(format!("Invalid Parquet schema. - {:?}", schema.unwrap_err()));
+        }
+
+        return Ok(Rc::new(schema.unwrap()));
+    }
+
+    /// Every inferred column is optional; fields with mixed types fall back to strings.
+    fn infer_message_type(rows: &Vec<Map<String, Value>>) -> String {
+        let mut columns: BTreeMap<String, &str> = BTreeMap::new();
+        for row in rows {
+            for (field, value) in row {
+                let column_type = match *value {
+                    Value::Null => continue,
+                    Value::Bool(_) => "boolean",
+                    Value::Number(ref number) if number.is_i64() => "int64",
+                    Value::Number(_) => "double",
+                    _ => "binary",
+                };
+
+                let current = columns.get(field).cloned();
+                let merged = match current {
+                    None => column_type,
+                    Some(current) if current == column_type => current,
+                    Some("int64") if column_type == "double" => "double",
+                    Some("double") if column_type == "int64" => "double",
+                    Some(_) => "binary",
+                };
+
+                columns.insert(field.to_string(), merged);
+            }
+        }
+
+        let mut fields = vec![];
+        for (field, column_type) in columns {
+            if column_type == "binary" {
+                fields.push(format!("optional binary {} (UTF8);", field));
+            } else {
+                fields.push(format!("optional {} {};", column_type, field));
+            }
+        }
+
+        return format!("message record {{ {} }}", fields.join(" "));
+    }
+
+    fn write_row_group(file_path: &::std::path::PathBuf, schema: Rc<Type>,
+                       rows: &Vec<Map<String, Value>>) -> Result<(), String> {
+        let file = File::create(file_path);
+        if file.is_err() {
+            return Err(format!("Can't create the Parquet file. - {:?}", file.unwrap_err()));
+        }
+
+        let schema_descriptor = SchemaDescriptor::new(schema.clone());
+        let properties = Rc::new(WriterProperties::builder().build());
+        let writer = SerializedFileWriter::new(file.unwrap(), schema, properties);
+        if writer.is_err() {
+            return Err(format!("Can't create the Parquet writer. - {:?}", writer.unwrap_err()));
+        }
+
+        let result = ParquetWriter::write_rows(&mut writer.unwrap(), &schema_descriptor, rows);
+        if result.is_err() {
+            return Err(format!("Error while writing the Parquet file. - {:?}", result.unwrap_err()));
+        }
+
+        return Ok(());
+    }
+
+    fn write_rows(writer: &mut SerializedFileWriter, schema_descriptor: &SchemaDescriptor,
+                  rows: &Vec<Map<String, Value>>) -> ::parquet::errors::Result<()> {
+        let mut row_group_writer = writer.next_row_group()?;
+        let mut column_index = 0;
+        while let Some(mut column_writer) = row_group_writer.next_column()? {
+            let column = schema_descriptor.column(column_index);
+            ParquetWriter::write_column(
+                &mut column_writer, column.name(), column.physical_type(),
+                column.logical_type(), column.self_type().get_basic_info().repetition(),
+                rows
+            )?;
+
+            row_group_writer.close_column(column_writer)?;
+            column_index = column_index + 1;
+        }
+
+        writer.close_row_group(row_group_writer)?;
+        writer.close()
+    }
+
+    fn write_column(column_writer: &mut ColumnWriter, name: &str, physical_type: PhysicalType,
+                    logical_type: LogicalType, repetition: Repetition,
+                    rows: &Vec<Map<String, Value>>) -> ::parquet::errors::Result<()> {
+        let is_optional = repetition == Repetition::OPTIONAL;
+        match *column_writer {
+            ColumnWriter::BoolColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_bool);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            ColumnWriter::Int32ColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_int32);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            ColumnWriter::Int64ColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_int64);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            ColumnWriter::FloatColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_float);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            ColumnWriter::DoubleColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_double);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            ColumnWriter::ByteArrayColumnWriter(ref mut typed) => {
+                let (batch, definition_levels) = ParquetWriter::get_batch(name, rows, to_byte_array);
+                typed.write_batch(&batch, if is_optional { Some(&definition_levels[..]) } else { None }, None)?;
+            }
+            _ => {
+                return Err(::parquet::errors::ParquetError::General(
+                    format!("Unsupported Parquet column {} ({} / {}).", name, physical_type, logical_type)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The column's values and definition levels. A value of another type is written as a null,
+    /// the rows were checked for the required columns before.
+    fn get_batch<T>(name: &str, rows: &Vec<Map<String, Value>>, convert: fn(&Value) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+        let mut batch = vec![];
+        let mut definition_levels = vec![];
+        let mut number_of_mistyped_values = 0;
+        for row in rows {
+            let value = row.get(name).filter(|value| !value.is_null());
+            let converted = value.and_then(convert);
+            if value.is_some() && converted.is_none() {
+                number_of_mistyped_values = number_of_mistyped_values + 1;
+            }
+
+            match converted {
+                Some(converted) => {
+                    definition_levels.push(1);
+                    batch.push(converted);
+                }
+                None => definition_levels.push(0),
+            }
+        }
+
+        if number_of_mistyped_values > 0 {
+            eprintln!("{} values of Parquet column {} are of another type, they're written as nulls.",
+                      number_of_mistyped_values, name);
+        }
+
+        return (batch, definition_levels);
+    }
+}
+
+fn to_bool(value: &Value) -> Option<bool> {
+    return value.as_bool();
+}
+
+fn to_int32(value: &Value) -> Option<i32> {
+    return value.as_i64()
+        .filter(|integer| *integer >= i32::min_value() as i64 && *integer <= i32::max_value() as i64)
+        .map(|integer| integer as i32);
+}
+
+fn to_int64(value: &Value) -> Option<i64> {
+    return value.as_i64();
+}
+
+fn to_float(value: &Value) -> Option<f32> {
+    return value.as_f64().map(|float| float as f32);
+}
+
+fn to_double(value: &Value) -> Option<f64> {
+    return value.as_f64();
+}
+
+/// Strings as they are, the other values as JSON.
+fn to_byte_array(value: &Value) -> Option<ByteArray> {
+    return match *value {
+        Value::Null => None,
+        Value::String(ref text) => Some(ByteArray::from(text.as_bytes().to_vec())),
+        ref other => Some(ByteArray::from(other.to_string().into_bytes())),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::io::Write;
+
+    /// The column names and the number of rows of a written file.
+    fn read_back(parquet_file: &Vec<u8>) -> (Vec<String>, i64) {
+        let file_path = env::temp_dir().join(format!("{}.parquet", Uuid::new_v4()));
+        File::create(&file_path).unwrap().write_all(parquet_file).unwrap();
+        let reader = SerializedFileReader::new(File::open(&file_path).unwrap()).unwrap();
+        let metadata = reader.metadata();
+        let file_metadata = metadata.file_metadata();
+        let column_names = file_metadata.schema_descr().columns().iter()
+            .map(|column| column.name().to_string())
+            .collect();
+        let number_of_rows = file_metadata.num_rows();
+
+        let _ = fs::remove_file(&file_path);
+        return (column_names, number_of_rows);
+    }
+
+    #[test]
+    fn skips_non_object_documents_and_sanitizes_field_names() {
+        let parquet_writer = ParquetWriter::new(None);
+        let documents = vec![
+            "42".to_string(),
+            r#"{"user-id": 7, "first name": "Ada", "2fa": true}"#.to_string(),
+        ];
+
+        let parquet_file = parquet_writer.write_documents(&documents).unwrap().unwrap();
+
+        assert!(parquet_file.starts_with(b"PAR1"));
+        let expected_column_names: Vec<String> =
+            vec!["_2fa", "first_name", "user_id"].iter().map(|name| name.to_string()).collect();
+        assert_eq!(read_back(&parquet_file), (expected_column_names, 1));
+    }
+
+    #[test]
+    fn skips_only_the_documents_that_do_not_fit_the_required_columns() {
+        let parquet_writer = ParquetWriter::new(Some(
+            "message record { required int64 id; optional int64 count; optional binary name (UTF8); }".to_string()
+        ));
+        let documents = vec![
+            r#"{"id": 1, "count": 2, "name": "a"}"#.to_string(),
+            r#"{"name": "missing id"}"#.to_string(),
+            r#"{"id": "mistyped id"}"#.to_string(),
+            r#"{"id": 2, "count": "mistyped count"}"#.to_string(),
+        ];
+
+        let parquet_file = parquet_writer.write_documents(&documents).unwrap().unwrap();
+
+        assert_eq!(read_back(&parquet_file).1, 2);
+    }
+
+    #[test]
+    fn writes_mistyped_optional_values_as_nulls() {
+        let rows: Vec<Map<String, Value>> = vec![r#"{"count": 2}"#, r#"{"count": "two"}"#, r#"{"count": null}"#, "{}"]
+            .iter()
+            .map(|row| serde_json::from_str(row).unwrap())
+            .collect();
+
+        let (batch, definition_levels) = ParquetWriter::get_batch("count", &rows, to_int64);
+
+        assert_eq!(batch, vec![2]);
+        assert_eq!(definition_levels, vec![1, 0, 0, 0]);
+        assert_eq!(to_int32(&Value::from(1i64 << 40)), None);
+        assert_eq!(to_double(&Value::from(3)), Some(3.0));
+    }
+
+    #[test]
+    fn fails_on_an_invalid_schema_and_writes_nothing_without_valid_documents() {
+        let invalid_schema = ParquetWriter::new(Some("message record { required nothing id; }".to_string()));
+        let required_id = ParquetWriter::new(Some("message record { required int64 id; }".to_string()));
+
+        assert!(invalid_schema.write_documents(&vec![r#"{"id": 1}"#.to_string()]).is_err());
+        assert_eq!(required_id.write_documents(&vec![r#"{"name": "a"}"#.to_string()]), Ok(None));
+    }
+
+    #[test]
+    fn infers_a_parseable_message_type() {
+        let row = ParquetWriter::sanitize_row(
+            serde_json::from_str(r#"{"user-id": 7, "first name": "Ada", "2fa": true}"#).unwrap()
+        );
+
+        let message_type = ParquetWriter::infer_message_type(&vec![row]);
+
+        assert!(message_type.contains("optional int64 user_id;"));
+        assert!(message_type.contains("optional binary first_name (UTF8);"));
+        assert!(message_type.contains("optional boolean _2fa;"));
+        assert!(parse_message_type(&message_type).is_ok());
+    }
+
+    #[test]
+    fn writes_nothing_without_object_documents() {
+        let parquet_writer = ParquetWriter::new(None);
+
+        let parquet_file = parquet_writer.write_documents(&vec!["42".to_string(), "null".to_string()]);
+
+        assert_eq!(parquet_file, Ok(None));
+    }
+}
@@ -0,0 +1,31 @@
+use rusoto_kinesis::Record;
+
+/// A destination for the records read from a shard.
+/// Pushing returns true once the records are durably delivered.
+pub trait RecordSink: Send + Sync {
+    /// Unique per sink, it names the sink's checkpoint in the lease table.
+    fn get_name(&self) -> String;
+
+    fn push_records(&self, shard_id: &String, records: &Vec<Record>) -> bool;
+
+    /// Sinks writing to Elasticsearch indices use the routed index prefix, the others ignore it.
+    fn push_records_to_index(&self, shard_id: &String, records: &Vec<Record>, _index: &String) -> bool {
+        return self.push_records(shard_id, records);
+    }
+}
+
+/// The records' data as UTF-8 documents.
+pub fn get_documents(records: &Vec<Record>) -> Vec<String> {
+    return records.iter()
+        .map(|record| String::from_utf8_lossy(&record.data).to_string())
+        .collect();
+}
+
+/// Sequence numbers are decimal strings of up to 128 bits, longer is greater.
+pub fn is_sequence_number_after(sequence_number: &String, other_sequence_number: &String) -> bool {
+    if sequence_number.len() != other_sequence_number.len() {
+        return sequence_number.len() > other_sequence_number.len();
+    }
+
+    return sequence_number > other_sequence_number;
+}
@@ -0,0 +1,335 @@
+use rusoto_s3::*;
+use hyper::rt::{Future, Stream};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use sink::parquet_writer::ParquetWriter;
+use config::env_config::{get_env_var_or, get_optional_env_var};
+use sink::elastic_search_sink::get_bulk_body;
+use sink::record_sink::{get_documents, RecordSink};
+use rusoto_kinesis::Record;
+use kinesis_stream::kcl_error::KclError;
+use retry::retry_policy::RetryPolicy;
+
+const DEFAULT_S3_BUCKET_NAME: &str = "s3_bucket_name";
+const KMS_SERVER_SIDE_ENCRYPTION: &str = "aws:kms";
+const STORAGE_CLASSES: [&str; 6] =
+    ["STANDARD", "REDUCED_REDUNDANCY", "STANDARD_IA", "ONEZONE_IA", "INTELLIGENT_TIERING", "GLACIER"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum S3OutputFormat {
+    /// The Elasticsearch bulk body, as newline-delimited JSON.
+    Json,
+    /// One Parquet file per flush, under Hive style partitions.
+    Parquet,
+}
+
+/// S3 object settings, read from the environment so compliance settings
+/// can change per deployment without a rebuild.
+#[derive(Clone, Debug)]
+pub struct S3SinkConfig {
+    pub bucket_name: String,
+    pub server_side_encryption: Option<String>,
+    pub ssekms_key_id: Option<String>,
+    pub acl: Option<String>,
+    pub tagging: Option<String>,
+    pub storage_class: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+    pub output_format: S3OutputFormat,
+    pub parquet_schema: Option<String>,
+    /// The json archive is an Elasticsearch bulk body, so it can be replayed as is.
+    pub index_prefix: String,
+}
+
+impl S3SinkConfig {
+    /// KCL_S3_BUCKET_NAME, KCL_S3_SERVER_SIDE_ENCRYPTION (AES256 | aws:kms),
+    /// KCL_S3_SSE_KMS_KEY_ID, KCL_S3_ACL (e.g. bucket-owner-full-control),
+    /// KCL_S3_TAGGING (key1=value1&key2=value2), KCL_S3_STORAGE_CLASS (e.g. STANDARD_IA)
+    /// and KCL_S3_METADATA (key1=value1,key2=value2).
+    /// KCL_S3_OUTPUT_FORMAT (json | parquet) and KCL_S3_PARQUET_SCHEMA_FILE, a Parquet
+    /// message type; the schema is inferred per flush when it's not given.
+    pub fn from_env() -> S3SinkConfig {
+        let bucket_name = get_env_var_or("KCL_S3_BUCKET_NAME", DEFAULT_S3_BUCKET_NAME);
+
+        let ssekms_key_id = get_optional_env_var("KCL_S3_SSE_KMS_KEY_ID");
+        let mut server_side_encryption =
+            get_optional_env_var("KCL_S3_SERVER_SIDE_ENCRYPTION");
+
+        // A KMS key is only honoured by S3 together with aws:kms encryption.
+        if ssekms_key_id.is_some() && server_side_encryption.is_none() {
+            server_side_encryption = Some(KMS_SERVER_SIDE_ENCRYPTION.to_string());
+        }
+
+        let storage_class = get_optional_env_var("KCL_S3_STORAGE_CLASS");
+        if storage_class.is_some() &&
+            !STORAGE_CLASSES.contains(&storage_class.clone().unwrap().as_str()) {
+            eprintln!("Unknown S3 storage class {} - S3 may reject the upload.",
+                     storage_class.clone().unwrap());
+        }
+
+        let metadata_option = get_optional_env_var("KCL_S3_METADATA");
+        let mut metadata = None;
+        if metadata_option.is_some() {
+            let mut metadata_map = HashMap::new();
+            for pair in metadata_option.unwrap().split(',') {
+                let mut key_value = pair.splitn(2, '=');
+                let key = key_value.next();
+                let value = key_value.next();
+                if key.is_some() && value.is_some() {
+                    metadata_map.insert(
+                        key.unwrap().trim().to_string(),
+                        value.unwrap().trim().to_string()
+                    );
+                }
+            }
+
+            metadata = Some(metadata_map);
+        }
+
+        let output_format_option = get_optional_env_var("KCL_S3_OUTPUT_FORMAT");
+        let mut output_format = S3OutputFormat::Json;
+        if output_format_option.is_some() {
+            let output_format_string = output_format_option.unwrap().to_lowercase();
+            if output_format_string == "parquet" {
+                output_format = S3OutputFormat::Parquet;
+            } else if output_format_string != "json" {
+                eprintln!("Unknown S3 output format {} - using json.", output_format_string);
+            }
+        }
+
+        let parquet_schema_file = get_optional_env_var("KCL_S3_PARQUET_SCHEMA_FILE");
+        let mut parquet_schema = None;
+        if parquet_schema_file.is_some() {
+            let mut schema = String::new();
+            let file = File::open(parquet_schema_file.clone().unwrap());
+            if file.is_err() || file.unwrap().read_to_string(&mut schema).is_err() {
+                eprintln!("Can't read the Parquet schema file {} - the schema will be inferred.",
+                         parquet_schema_file.unwrap());
+            } else {
+                parquet_schema = Some(schema);
+            }
+        }
+
+        S3SinkConfig {
+            bucket_name,
+            server_side_encryption,
+            ssekms_key_id,
+            acl: get_optional_env_var("KCL_S3_ACL"),
+            tagging: get_optional_env_var("KCL_S3_TAGGING"),
+            storage_class,
+            metadata,
+            output_format,
+            parquet_schema,
+            index_prefix: get_env_var_or("KCL_ELASTIC_SEARCH_INDEX_PREFIX", "index_name")
+        }
+    }
+
+    /// The key prefix holding all the objects flushed during the given hour.
+    pub fn get_hour_prefix(&self, date: &DateTime<Utc>) -> String {
+        if self.output_format == S3OutputFormat::Parquet {
+            return date.format("year=%Y/month=%m/day=%d/hour=%H/").to_string();
+        }
+
+        return date.format("%Y/%m/%d/%H/").to_string();
+    }
+}
+
+pub struct S3Sink {
+    s3_client: S3Client,
+    pub config: S3SinkConfig,
+    parquet_writer: ParquetWriter,
+    retry_policy: RetryPolicy,
+}
+
+impl S3Sink {
+    pub fn new(s3_client: S3Client, config: S3SinkConfig) -> S3Sink {
+        let parquet_writer = ParquetWriter::new(config.parquet_schema.clone());
+        S3Sink { s3_client, config, parquet_writer, retry_policy: RetryPolicy::from_env() }
+    }
+
+    /// Save the logs to S3 to a second granularity, either the bulk body as is
+    /// or the documents converted to Parquet.
+    pub fn push_logs_to_s3(&self, log_messages: String, documents: &Vec<String>) -> bool {
+        if self.config.output_format == S3OutputFormat::Parquet {
+            return self.push_documents_as_parquet(documents);
+        }
+
+        // None of the documents is JSON, there's nothing to archive.
+        if log_messages.is_empty() {
+            return true;
+        }
+
+        let vector = log_messages.as_bytes().to_vec();
+        let date = Utc::now();
+        let file_path = format!("{}_{}.json", date.format("%Y/%m/%d/%H/%M/%S"), Uuid::new_v4());
+
+        return self.put_object(file_path, vector);
+    }
+
+    /// Partitioned as year=/month=/day=/hour= so Athena can prune by time.
+    /// Documents that can't be converted are skipped by the writer, retrying them would block the
+    /// shard. Failing to write the file fails the push, e.g. a full temp directory.
+    fn push_documents_as_parquet(&self, documents: &Vec<String>) -> bool {
+        let parquet_file = match self.parquet_writer.write_documents(documents) {
+            Ok(Some(parquet_file)) => parquet_file,
+            Ok(None) => return true,
+            Err(error) => {
+                eprintln!("Can't write {} documents as Parquet. - {}", documents.len(), error);
+                return false;
+            }
+        };
+
+        let date = Utc::now();
+        let file_path = format!(
+            "{}_{}.parquet",
+            date.format("year=%Y/month=%m/day=%d/hour=%H/%M%S"),
+            Uuid::new_v4()
+        );
+
+        return self.put_object(file_path, parquet_file);
+    }
+
+    fn put_object(&self, file_path: String, vector: Vec<u8>) -> bool {
+        let response = self.retry_policy.retry("Saving to S3", || {
+            self.s3_client.put_object(
+                self.get_put_object_request(file_path.clone(), vector.clone())
+            ).sync().map_err(KclError::from)
+        });
+
+        if response.is_err() {
+            eprintln!("Can't save the data to S3. - {}", response.unwrap_err());
+            return false;
+        }
+
+        return true;
+    }
+
+    /// All the object keys under the given prefix.
+    pub fn list_object_keys(&self, prefix: &String) -> Option<Vec<String>> {
+        let mut keys = vec![];
+        let mut continuation_token = None;
+
+        loop {
+            let list_result = self.s3_client.list_objects_v2(
+                ListObjectsV2Request {
+                    bucket: self.config.bucket_name.to_string(),
+                    continuation_token: continuation_token.clone(),
+                    prefix: Some(prefix.to_string()),
+                    ..Default::default()
+                }
+            ).sync();
+
+            if list_result.is_err() {
+                eprintln!("Can't list the objects of {}. - {:?}", prefix, list_result.unwrap_err());
+                return None;
+            }
+
+            let list_output = list_result.unwrap();
+            for object in list_output.contents.unwrap_or(vec![]) {
+                if object.key.is_some() {
+                    keys.push(object.key.unwrap());
+                }
+            }
+
+            continuation_token = list_output.next_continuation_token;
+            if !list_output.is_truncated.unwrap_or(false) || continuation_token.is_none() {
+                return Some(keys);
+            }
+        }
+    }
+
+    pub fn get_object(&self, key: &String) -> Option<Vec<u8>> {
+        let get_result = self.s3_client.get_object(
+            GetObjectRequest {
+                bucket: self.config.bucket_name.to_string(),
+                key: key.to_string(),
+                ..Default::default()
+            }
+        ).sync();
+
+        if get_result.is_err() {
+            eprintln!("Can't read {} from S3. - {:?}", key, get_result.unwrap_err());
+            return None;
+        }
+
+        let body = get_result.unwrap().body;
+        if body.is_none() {
+            return Some(vec![]);
+        }
+
+        let bytes = body.unwrap().concat2().wait();
+        if bytes.is_err() {
+            eprintln!("Can't read {} from S3. - {:?}", key, bytes.unwrap_err());
+            return None;
+        }
+
+        return Some(bytes.unwrap());
+    }
+
+    pub fn delete_object(&self, key: &String) {
+        let delete_result = self.s3_client.delete_object(
+            DeleteObjectRequest {
+                bucket: self.config.bucket_name.to_string(),
+                key: key.to_string(),
+                ..Default::default()
+            }
+        ).sync();
+
+        if delete_result.is_err() {
+            eprintln!("Can't delete {} from S3. - {:?}", key, delete_result.unwrap_err());
+        }
+    }
+
+    fn get_put_object_request(&self, key: String, body: Vec<u8>) -> PutObjectRequest {
+        return PutObjectRequest {
+            acl: self.config.acl.clone(),
+            body: Some(StreamingBody::from(body)),
+            bucket: self.config.bucket_name.to_string(),
+            cache_control: None,
+            content_disposition: None,
+            content_encoding: None,
+            content_language: None,
+            content_length: None,
+            content_md5: None,
+            content_type: None,
+            expires: None,
+            grant_full_control: None,
+            grant_read: None,
+            grant_read_acp: None,
+            grant_write_acp: None,
+            metadata: self.config.metadata.clone(),
+            request_payer: None,
+            sse_customer_algorithm: None,
+            sse_customer_key: None,
+            sse_customer_key_md5: None,
+            ssekms_key_id: self.config.ssekms_key_id.clone(),
+            server_side_encryption: self.config.server_side_encryption.clone(),
+            storage_class: self.config.storage_class.clone(),
+            tagging: self.config.tagging.clone(),
+            website_redirect_location: None,
+            key
+        };
+    }
+}
+
+impl RecordSink for S3Sink {
+    fn get_name(&self) -> String {
+        return "s3".to_string();
+    }
+
+    fn push_records(&self, shard_id: &String, records: &Vec<Record>) -> bool {
+        let index_prefix = self.config.index_prefix.to_string();
+        return self.push_records_to_index(shard_id, records, &index_prefix);
+    }
+
+    /// The upload is retried with the retry policy, once it gives up the batch fails and
+    /// the fan out decides whether the shard waits for the archive (KCL_OPTIONAL_SINKS).
+    fn push_records_to_index(&self, _shard_id: &String, records: &Vec<Record>, index: &String) -> bool {
+        let documents = get_documents(records);
+        let (bulk, _) = get_bulk_body(index, &documents);
+
+        return self.push_logs_to_s3(bulk, &documents);
+    }
+}
@@ -0,0 +1,260 @@
+use sink::record_sink::{is_sequence_number_after, RecordSink};
+use routing::record_router::{RecordRouter, Route};
+use rusoto_kinesis::Record;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+
+pub struct SinkEntry {
+    pub sink: Arc<RecordSink>,
+    /// The shard checkpoint never gets ahead of a required sink, an optional sink can lag or miss records.
+    pub is_required: bool,
+}
+
+/// Delivers every batch to all the sinks in parallel, tracking each sink's progress.
+/// With a router, each record only goes to the sinks (and index) its route names.
+pub struct SinkFanOut {
+    sink_entries: Vec<SinkEntry>,
+    record_router: Option<RecordRouter>,
+}
+
+impl SinkFanOut {
+    pub fn new(sink_entries: Vec<SinkEntry>, record_router: Option<RecordRouter>) -> SinkFanOut {
+        SinkFanOut { sink_entries, record_router }
+    }
+
+    pub fn get_sink_names(&self) -> Vec<String> {
+        return self.sink_entries.iter().map(|sink_entry| sink_entry.sink.get_name()).collect();
+    }
+
+    /// Push the records each sink hasn't acknowledged yet, and move the acknowledged sinks'
+    /// checkpoints forward. Returns the sequence number the shard can be checkpointed at,
+    /// the minimum acknowledged by the required sinks, or None if a required sink failed.
+    pub fn push_records(&self, shard_id: &String, records: &Vec<Record>,
+                        sink_sequence_numbers: &mut HashMap<String, String>) -> Option<String> {
+        let last_sequence_number = records.last().map(|record| record.sequence_number.to_string());
+        if last_sequence_number.is_none() {
+            return None;
+        }
+
+        let routes = match self.record_router {
+            Some(ref record_router) => record_router.route_records(records),
+            None => vec![Route::Deliver { sinks: None, index: None }; records.len()],
+        };
+
+        let mut sink_threads = vec![];
+        for sink_entry in &self.sink_entries {
+            let sink = sink_entry.sink.clone();
+            let sink_sequence_number = sink_sequence_numbers.get(&sink.get_name()).cloned();
+            let pending_runs =
+                SinkFanOut::get_pending_runs(&sink.get_name(), records, &routes, &sink_sequence_number);
+
+            // Whether every run was pushed, and the last record of the runs pushed before one failed.
+            let thread_shard_id = shard_id.to_string();
+            sink_threads.push(thread::spawn(move || {
+                let mut pushed_sequence_number: Option<String> = None;
+                for (index, pending_records) in pending_runs {
+                    let pushed = match index {
+                        Some(index) => sink.push_records_to_index(&thread_shard_id, &pending_records, &index),
+                        None => sink.push_records(&thread_shard_id, &pending_records),
+                    };
+
+                    if !pushed {
+                        return (false, pushed_sequence_number);
+                    }
+
+                    pushed_sequence_number = pending_records.last().map(|record| record.sequence_number.to_string());
+                }
+
+                (true, pushed_sequence_number)
+            }));
+        }
+
+        let mut all_required_pushed = true;
+        for (sink_entry, sink_thread) in self.sink_entries.iter().zip(sink_threads.into_iter()) {
+            let sink_name = sink_entry.sink.get_name();
+            let (pushed, pushed_sequence_number) = sink_thread.join().unwrap_or((false, None));
+            if pushed {
+                sink_sequence_numbers.insert(sink_name, last_sequence_number.clone().unwrap());
+                continue;
+            }
+
+            // The runs already pushed aren't sent again with the batch.
+            if pushed_sequence_number.is_some() {
+                sink_sequence_numbers.insert(sink_name.to_string(), pushed_sequence_number.unwrap());
+            }
+
+            if sink_entry.is_required {
+                eprintln!("Required sink {} failed for shard {}.", sink_name, shard_id);
+                all_required_pushed = false;
+            } else {
+                eprintln!("Optional sink {} failed for shard {} - skipping.", sink_name, shard_id);
+            }
+        }
+
+        if !all_required_pushed {
+            return None;
+        }
+
+        return self.get_shard_sequence_number(sink_sequence_numbers).or(last_sequence_number);
+    }
+
+    /// The records routed to the sink and not acknowledged by it yet, in runs of consecutive records
+    /// routed to the same index, so the sink gets them in the shard's order.
+    fn get_pending_runs(sink_name: &String, records: &Vec<Record>, routes: &Vec<Route>,
+                        sink_sequence_number: &Option<String>) -> Vec<(Option<String>, Vec<Record>)> {
+        let mut pending_runs: Vec<(Option<String>, Vec<Record>)> = vec![];
+        for (record, route) in records.iter().zip(routes.iter()) {
+            if sink_sequence_number.is_some() &&
+                !is_sequence_number_after(&record.sequence_number, sink_sequence_number.as_ref().unwrap()) {
+                continue;
+            }
+
+            let index = match *route {
+                Route::Drop => continue,
+                Route::Deliver { ref sinks, ref index } => {
+                    if sinks.is_some() && !sinks.as_ref().unwrap().contains(sink_name) {
+                        continue;
+                    }
+
+                    index.clone()
+                }
+            };
+
+            let is_same_run = pending_runs.last().map(|&(ref run_index, _)| *run_index == index).unwrap_or(false);
+            if is_same_run {
+                pending_runs.last_mut().unwrap().1.push(record.clone());
+            } else {
+                pending_runs.push((index, vec![record.clone()]));
+            }
+        }
+
+        return pending_runs;
+    }
+
+    fn get_shard_sequence_number(&self, sink_sequence_numbers: &HashMap<String, String>) -> Option<String> {
+        let mut shard_sequence_number: Option<String> = None;
+        for sink_entry in &self.sink_entries {
+            if !sink_entry.is_required {
+                continue;
+            }
+
+            let sink_sequence_number = sink_sequence_numbers.get(&sink_entry.sink.get_name());
+            if sink_sequence_number.is_none() {
+                return None;
+            }
+
+            if shard_sequence_number.is_none() ||
+                is_sequence_number_after(shard_sequence_number.as_ref().unwrap(), sink_sequence_number.unwrap()) {
+                shard_sequence_number = sink_sequence_number.cloned();
+            }
+        }
+
+        return shard_sequence_number;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// Records what it's sent, and fails the pushes to the given index.
+    struct RecordingSink {
+        failing_index: Mutex<Option<String>>,
+        pushes: Mutex<Vec<(Option<String>, Vec<String>)>>,
+    }
+
+    impl RecordSink for RecordingSink {
+        fn get_name(&self) -> String {
+            return "recording".to_string();
+        }
+
+        fn push_records(&self, _shard_id: &String, records: &Vec<Record>) -> bool {
+            let sequence_numbers = records.iter().map(|record| record.sequence_number.to_string()).collect();
+            self.pushes.lock().unwrap().push((None, sequence_numbers));
+            return true;
+        }
+
+        fn push_records_to_index(&self, _shard_id: &String, records: &Vec<Record>, index: &String) -> bool {
+            let sequence_numbers = records.iter().map(|record| record.sequence_number.to_string()).collect();
+            self.pushes.lock().unwrap().push((Some(index.to_string()), sequence_numbers));
+            return *self.failing_index.lock().unwrap() != Some(index.to_string());
+        }
+    }
+
+    fn get_records(indices: &[&str]) -> Vec<Record> {
+        return indices.iter().enumerate().map(|(position, index)| Record {
+            data: format!("{{\"index\":\"{}\"}}", index).into_bytes(),
+            sequence_number: (position + 1).to_string(),
+            ..Default::default()
+        }).collect();
+    }
+
+    fn get_route(index: Option<&str>) -> Route {
+        return Route::Deliver { sinks: None, index: index.map(|index| index.to_string()) };
+    }
+
+    fn get_runs(pending_runs: Vec<(Option<String>, Vec<Record>)>) -> Vec<(Option<String>, Vec<String>)> {
+        return pending_runs.into_iter()
+            .map(|(index, records)| (index, records.into_iter().map(|record| record.sequence_number).collect()))
+            .collect();
+    }
+
+    #[test]
+    fn keeps_the_shard_order_across_indices() {
+        let records = get_records(&["a", "b", "c", "a"]);
+        let routes = vec![get_route(Some("a")), get_route(Some("b")), Route::Drop, get_route(Some("a"))];
+
+        let pending_runs = SinkFanOut::get_pending_runs(&"sink".to_string(), &records, &routes, &None);
+
+        assert_eq!(get_runs(pending_runs), vec![
+            (Some("a".to_string()), vec!["1".to_string()]),
+            (Some("b".to_string()), vec!["2".to_string()]),
+            (Some("a".to_string()), vec!["4".to_string()])
+        ]);
+    }
+
+    #[test]
+    fn skips_the_records_the_sink_acknowledged() {
+        let records = get_records(&["a", "a", "a"]);
+        let routes = vec![get_route(None); 3];
+
+        let pending_runs = SinkFanOut::get_pending_runs(&"sink".to_string(), &records, &routes, &Some("2".to_string()));
+
+        assert_eq!(get_runs(pending_runs), vec![(None, vec!["3".to_string()])]);
+    }
+
+    #[test]
+    fn does_not_push_the_runs_before_a_failed_one_again() {
+        let rules_file_path = env::temp_dir().join("kcl_sink_fan_out_test_rules.json");
+        fs::write(&rules_file_path, r#"{"rules": [
+            {"field": "index", "equals": "a", "index": "a"},
+            {"field": "index", "equals": "b", "index": "b"},
+            {"field": "index", "equals": "c", "index": "c"}
+        ]}"#).unwrap();
+
+        let sink = Arc::new(RecordingSink { failing_index: Mutex::new(Some("b".to_string())), pushes: Mutex::new(vec![]) });
+        let record_router =
+            RecordRouter::new(rules_file_path.to_string_lossy().to_string(), vec!["recording".to_string()]).unwrap();
+        let sink_fan_out = SinkFanOut::new(vec![SinkEntry { sink: sink.clone(), is_required: true }], Some(record_router));
+        let records = get_records(&["a", "b", "c"]);
+        let mut sink_sequence_numbers = HashMap::new();
+
+        assert_eq!(sink_fan_out.push_records(&"shard-1".to_string(), &records, &mut sink_sequence_numbers), None);
+        assert_eq!(sink_sequence_numbers.get("recording"), Some(&"1".to_string()));
+
+        *sink.failing_index.lock().unwrap() = None;
+        assert_eq!(sink_fan_out.push_records(&"shard-1".to_string(), &records, &mut sink_sequence_numbers),
+                   Some("3".to_string()));
+
+        let pushed_sequence_numbers: Vec<Vec<String>> =
+            sink.pushes.lock().unwrap().iter().map(|&(_, ref sequence_numbers)| sequence_numbers.clone()).collect();
+        assert_eq!(pushed_sequence_numbers, vec![
+            vec!["1".to_string()], vec!["2".to_string()], vec!["2".to_string()], vec!["3".to_string()]
+        ]);
+        let _ = fs::remove_file(&rules_file_path);
+    }
+}
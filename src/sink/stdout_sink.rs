@@ -0,0 +1,135 @@
+use rusoto_kinesis::Record;
+use sink::record_sink::RecordSink;
+use chrono::{TimeZone, Utc};
+use b64::{ToBase64, STANDARD};
+use serde_json::{Map, Number, Value};
+use std::io;
+use std::io::Write;
+
+/// Prints one JSON line per record with its metadata, for piping into jq and friends.
+/// The data is embedded as JSON when it's a JSON document, as a string when it's UTF-8
+/// text, and base64 encoded otherwise or when asked to.
+pub struct StdoutSink {
+    is_base64_enabled: bool,
+}
+
+impl StdoutSink {
+    pub fn new(is_base64_enabled: bool) -> StdoutSink {
+        StdoutSink { is_base64_enabled }
+    }
+
+    fn get_record_line(&self, shard_id: &String, record: &Record) -> String {
+        let mut line = Map::new();
+        line.insert("shard_id".to_string(), Value::String(shard_id.to_string()));
+        line.insert("sequence_number".to_string(), Value::String(record.sequence_number.to_string()));
+        line.insert("partition_key".to_string(), Value::String(record.partition_key.to_string()));
+
+        let arrival_timestamp = record.approximate_arrival_timestamp.map(|timestamp| {
+            let seconds = timestamp.trunc() as i64;
+            let nanoseconds = (timestamp.fract() * 1_000_000_000.0) as u32;
+            Value::String(Utc.timestamp(seconds, nanoseconds).to_rfc3339())
+        });
+        line.insert("approximate_arrival_timestamp".to_string(), arrival_timestamp.unwrap_or(Value::Null));
+        line.insert("size".to_string(), Value::Number(Number::from(record.data.len())));
+        line.insert("data".to_string(), self.get_data_value(&record.data));
+
+        return Value::Object(line).to_string();
+    }
+
+    fn get_data_value(&self, data: &Vec<u8>) -> Value {
+        if !self.is_base64_enabled {
+            let document: Result<Value, _> = serde_json::from_slice(data);
+            if document.is_ok() {
+                return document.unwrap();
+            }
+
+            let text = String::from_utf8(data.clone());
+            if text.is_ok() {
+                return Value::String(text.unwrap());
+            }
+        }
+
+        return Value::String(data.to_base64(STANDARD));
+    }
+}
+
+impl RecordSink for StdoutSink {
+    fn get_name(&self) -> String {
+        return "stdout".to_string();
+    }
+
+    fn push_records(&self, shard_id: &String, records: &Vec<Record>) -> bool {
+        let mut lines = String::new();
+        for record in records {
+            lines.push_str(&self.get_record_line(shard_id, record));
+            lines.push('\n');
+        }
+
+        // One locked write per batch, so lines of two shards never interleave.
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let written = handle.write_all(lines.as_bytes()).and_then(|_| handle.flush());
+        if written.is_err() {
+            eprintln!("Can't write to stdout. - {:?}", written.unwrap_err());
+            return false;
+        }
+
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_record(data: &[u8]) -> Record {
+        return Record {
+            approximate_arrival_timestamp: Some(1538398800.5),
+            data: data.to_vec(),
+            partition_key: "key".to_string(),
+            sequence_number: "49590338271490256608559692538361571095921575989136588898".to_string(),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn prints_the_record_with_its_metadata() {
+        let stdout_sink = StdoutSink::new(false);
+
+        let line = stdout_sink.get_record_line(&"shardId-000000000000".to_string(), &get_record(b"{\"id\": 1}"));
+
+        assert_eq!(line, "{\"approximate_arrival_timestamp\":\"2018-10-01T13:00:00.500+00:00\",\
+                          \"data\":{\"id\":1},\"partition_key\":\"key\",\
+                          \"sequence_number\":\"49590338271490256608559692538361571095921575989136588898\",\
+                          \"shard_id\":\"shardId-000000000000\",\"size\":9}");
+    }
+
+    #[test]
+    fn prints_a_record_without_arrival_timestamp_as_null() {
+        let stdout_sink = StdoutSink::new(false);
+        let mut record = get_record(b"text");
+        record.approximate_arrival_timestamp = None;
+
+        let line: Value = serde_json::from_str(&stdout_sink.get_record_line(&"shardId-000000000000".to_string(), &record)).unwrap();
+
+        assert_eq!(line["approximate_arrival_timestamp"], Value::Null);
+        assert_eq!(line["data"], Value::String("text".to_string()));
+    }
+
+    #[test]
+    fn embeds_json_and_text_data_and_encodes_binary_data() {
+        let stdout_sink = StdoutSink::new(false);
+
+        assert_eq!(stdout_sink.get_data_value(&b"[1, 2]".to_vec()), serde_json::from_str::<Value>("[1,2]").unwrap());
+        assert_eq!(stdout_sink.get_data_value(&b"plain text".to_vec()), Value::String("plain text".to_string()));
+        assert_eq!(stdout_sink.get_data_value(&vec![0xff, 0xfe, 0x00]), Value::String("//4A".to_string()));
+    }
+
+    #[test]
+    fn encodes_every_data_with_base64_when_asked_to() {
+        let stdout_sink = StdoutSink::new(true);
+
+        assert_eq!(stdout_sink.get_data_value(&b"{\"id\": 1}".to_vec()), Value::String("eyJpZCI6IDF9".to_string()));
+        assert_eq!(stdout_sink.get_data_value(&b"plain text".to_vec()), Value::String("cGxhaW4gdGV4dA==".to_string()));
+    }
+}
@@ -0,0 +1,274 @@
+use transform::decode_transform::{DecodeError, PayloadDecoder};
+use avro_rs::{from_avro_datum, Schema};
+use avro_rs::types::Value as AvroValue;
+use b64::{ToBase64, STANDARD};
+use hyper::*;
+use hyper::rt::{Future, Stream};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Timeout;
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The registry wire format: a zero magic byte and the big-endian schema id before the datum.
+const REGISTRY_MAGIC_BYTE: u8 = 0;
+const REGISTRY_HEADER_LENGTH: usize = 5;
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(10);
+/// A schema the registry doesn't have is answered from the cache for a while, so it doesn't cost a
+/// request per record. The registry being unavailable isn't cached, the batch is read again later.
+const REGISTRY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// The registry's error code for an unknown schema id, a 404 without it is a wrong registry url.
+const REGISTRY_SCHEMA_NOT_FOUND: i64 = 40403;
+
+/// Decodes Avro datums, with a fixed schema or the registry schema their header points to.
+pub struct AvroDecoder {
+    schema: Option<Schema>,
+    registry_url: Option<String>,
+    registry_schemas: Mutex<HashMap<u32, Result<Arc<Schema>, (String, Instant)>>>,
+}
+
+impl AvroDecoder {
+    pub fn new(schema_file: Option<String>, registry_url: Option<String>) -> Option<AvroDecoder> {
+        if schema_file.is_none() && registry_url.is_none() {
+            eprintln!("The avro decoder needs a schema_file or a registry_url.");
+            return None;
+        }
+
+        let mut schema = None;
+        if schema_file.is_some() {
+            let schema_file = schema_file.unwrap();
+            let mut content = String::new();
+            let file = File::open(&schema_file);
+            if file.is_err() || file.unwrap().read_to_string(&mut content).is_err() {
+                eprintln!("Can't read the avro schema {}.", schema_file);
+                return None;
+            }
+
+            let parsed_schema = Schema::parse_str(&content);
+            if parsed_schema.is_err() {
+                eprintln!("Invalid avro schema {}. - {}", schema_file, parsed_schema.unwrap_err());
+                return None;
+            }
+
+            schema = Some(parsed_schema.unwrap());
+        }
+
+        return Some(AvroDecoder {
+            schema,
+            registry_url: registry_url.map(|registry_url| registry_url.trim_right_matches('/').to_string()),
+            registry_schemas: Mutex::new(HashMap::new())
+        });
+    }
+
+    /// The schemas are immutable per id, so each one is fetched once. The lock isn't held while
+    /// fetching, the other shards keep decoding with the schemas already known.
+    fn get_registry_schema(&self, schema_id: u32) -> Result<Arc<Schema>, DecodeError> {
+        {
+            let registry_schemas = self.registry_schemas.lock().unwrap();
+            match registry_schemas.get(&schema_id) {
+                Some(&Ok(ref schema)) => return Ok(schema.clone()),
+                Some(&Err((ref error, failed_at))) if failed_at.elapsed() < REGISTRY_RETRY_INTERVAL =>
+                    return Err(DecodeError::Invalid(error.to_string())),
+                _ => {}
+            }
+        }
+
+        let schema = self.fetch_registry_schema(schema_id).map(Arc::new);
+        let mut registry_schemas = self.registry_schemas.lock().unwrap();
+        match schema {
+            Ok(ref schema) => {
+                registry_schemas.insert(schema_id, Ok(schema.clone()));
+            }
+            Err(DecodeError::Invalid(ref error)) => {
+                registry_schemas.insert(schema_id, Err((error.to_string(), Instant::now())));
+            }
+            Err(DecodeError::Unavailable(_)) => {}
+        }
+
+        return schema;
+    }
+
+    fn fetch_registry_schema(&self, schema_id: u32) -> Result<Schema, DecodeError> {
+        let url = format!("{}/schemas/ids/{}", self.registry_url.as_ref().unwrap(), schema_id);
+        let uri = url.parse::<hyper::Uri>()
+            .map_err(|error| DecodeError::Unavailable(format!("invalid registry url {} - {}", url, error)))?;
+
+        // The decoders run on the blocking pool of the shard runtime, which can't run another
+        // executor on the same thread.
+        let (status, body) = thread::spawn(move || {
+            let mut runtime = Runtime::new().map_err(|error| error.to_string())?;
+            let client = Client::new();
+            let request = client.get(uri)
+                .and_then(|response| {
+                    let status = response.status();
+                    response.into_body().concat2().map(move |body| (status, body))
+                })
+                .map_err(|error| error.to_string());
+            return runtime.block_on(Timeout::new(request, REGISTRY_TIMEOUT)).map_err(|error| {
+                if error.is_elapsed() {
+                    return format!("no answer within {}s", REGISTRY_TIMEOUT.as_secs());
+                }
+
+                return error.into_inner().unwrap_or("the timer failed".to_string());
+            });
+        }).join()
+            .unwrap_or(Err("the fetching thread panicked".to_string()))
+            .map_err(|error| {
+                DecodeError::Unavailable(format!("can't fetch the schema {} from the registry - {}", schema_id, error))
+            })?;
+
+        // {"schema": "<the schema as a JSON string>"}, or {"error_code": 40403, "message": "..."}
+        let response: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        if status == StatusCode::NOT_FOUND && response["error_code"].as_i64() == Some(REGISTRY_SCHEMA_NOT_FOUND) {
+            return Err(DecodeError::Invalid(format!("the registry has no schema {}", schema_id)));
+        }
+
+        if !status.is_success() || !response["schema"].is_string() {
+            return Err(DecodeError::Unavailable(
+                format!("the registry answered {} for the schema {} - {}", status, schema_id, String::from_utf8_lossy(&body))
+            ));
+        }
+
+        eprintln!("Loaded avro schema {} from {}.", schema_id, self.registry_url.as_ref().unwrap());
+        return Schema::parse_str(response["schema"].as_str().unwrap())
+            .map_err(|error| DecodeError::Invalid(format!("invalid registry schema {} - {}", schema_id, error)));
+    }
+}
+
+impl PayloadDecoder for AvroDecoder {
+    fn decode(&self, data: &[u8]) -> Result<Value, DecodeError> {
+        if self.registry_url.is_none() {
+            let mut datum = data;
+            let avro_value = from_avro_datum(self.schema.as_ref().unwrap(), &mut datum, None)
+                .map_err(|error| DecodeError::Invalid(error.to_string()))?;
+            return Ok(to_json_value(avro_value));
+        }
+
+        let schema_id = get_registry_schema_id(data)?;
+        let writer_schema = self.get_registry_schema(schema_id)?;
+
+        // A fixed schema is the reader schema the registry versions are resolved to.
+        let mut datum = &data[REGISTRY_HEADER_LENGTH..];
+        let avro_value = from_avro_datum(&writer_schema, &mut datum, self.schema.as_ref())
+            .map_err(|error| DecodeError::Invalid(format!("schema {} - {}", schema_id, error)))?;
+        return Ok(to_json_value(avro_value));
+    }
+}
+
+fn get_registry_schema_id(data: &[u8]) -> Result<u32, DecodeError> {
+    if data.len() < REGISTRY_HEADER_LENGTH || data[0] != REGISTRY_MAGIC_BYTE {
+        return Err(DecodeError::Invalid("the payload has no registry header".to_string()));
+    }
+
+    return Ok((data[1] as u32) << 24 | (data[2] as u32) << 16 | (data[3] as u32) << 8 | data[4] as u32);
+}
+
+fn to_json_value(avro_value: AvroValue) -> Value {
+    match avro_value {
+        AvroValue::Null => Value::Null,
+        AvroValue::Boolean(boolean) => Value::Bool(boolean),
+        AvroValue::Int(integer) => Value::Number(Number::from(integer)),
+        AvroValue::Long(long) => Value::Number(Number::from(long)),
+        AvroValue::Float(float) => Number::from_f64(float as f64).map(Value::Number).unwrap_or(Value::Null),
+        AvroValue::Double(double) => Number::from_f64(double).map(Value::Number).unwrap_or(Value::Null),
+        AvroValue::Bytes(bytes) | AvroValue::Fixed(_, bytes) => Value::String(bytes.to_base64(STANDARD)),
+        AvroValue::String(text) => Value::String(text),
+        AvroValue::Enum(_, symbol) => Value::String(symbol),
+        AvroValue::Union(value) => to_json_value(*value),
+        AvroValue::Array(values) => Value::Array(values.into_iter().map(to_json_value).collect()),
+        AvroValue::Map(values) =>
+            Value::Object(values.into_iter().map(|(key, value)| (key, to_json_value(value))).collect()),
+        AvroValue::Record(fields) => {
+            let mut document = Map::new();
+            for (name, value) in fields {
+                document.insert(name, to_json_value(value));
+            }
+
+            Value::Object(document)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_decoder(schema: Option<&str>, registry_url: Option<&str>) -> AvroDecoder {
+        return AvroDecoder {
+            schema: schema.map(|schema| Schema::parse_str(schema).unwrap()),
+            registry_url: registry_url.map(|registry_url| registry_url.to_string()),
+            registry_schemas: Mutex::new(HashMap::new())
+        };
+    }
+
+    #[test]
+    fn decodes_datums_with_a_fixed_schema() {
+        let decoder = get_decoder(Some(r#"{"type": "record", "name": "Order", "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "name", "type": "string"},
+            {"name": "tags", "type": {"type": "array", "items": "string"}},
+            {"name": "kind", "type": ["null", "string"]}]}"#), None);
+        // 1, "ab", ["x"], the second union branch "y"
+        let data = [0x02, 0x04, b'a', b'b', 0x02, 0x02, b'x', 0x00, 0x02, 0x02, b'y'];
+
+        let document = decoder.decode(&data).unwrap();
+
+        assert_eq!(document.to_string(), r#"{"id":1,"kind":"y","name":"ab","tags":["x"]}"#);
+        assert!(decoder.decode(&[0x02]).is_err());
+    }
+
+    #[test]
+    fn parses_the_registry_header() {
+        assert_eq!(get_registry_schema_id(&[0x00, 0x00, 0x00, 0x01, 0x02, 0x02]), Ok(258));
+        assert_eq!(get_registry_schema_id(&[0x00, 0x00, 0x00, 0x01, 0x02]), Ok(258));
+        assert!(get_registry_schema_id(&[0x01, 0x00, 0x00, 0x01, 0x02]).is_err());
+        assert!(get_registry_schema_id(&[0x00, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn fails_without_caching_when_the_registry_is_unreachable() {
+        let decoder = get_decoder(None, Some("http://127.0.0.1:1"));
+
+        match decoder.decode(&[0x00, 0x00, 0x00, 0x00, 0x07, 0x02]) {
+            Err(DecodeError::Unavailable(_)) => {}
+            result => panic!("expected an unavailable registry, got {:?}", result),
+        }
+
+        assert!(decoder.registry_schemas.lock().unwrap().is_empty());
+        match decoder.decode(&[0x07]) {
+            Err(DecodeError::Invalid(_)) => {}
+            result => panic!("expected an invalid payload, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn converts_avro_values_to_json() {
+        let mut map = HashMap::new();
+        map.insert("key".to_string(), AvroValue::Union(Box::new(AvroValue::Null)));
+        let avro_value = AvroValue::Record(vec![
+            ("bytes".to_string(), AvroValue::Bytes(vec![0xff, 0xfe, 0x00])),
+            ("fixed".to_string(), AvroValue::Fixed(2, vec![0x61, 0x62])),
+            ("nan".to_string(), AvroValue::Double(::std::f64::NAN)),
+            ("float".to_string(), AvroValue::Float(1.5)),
+            ("int".to_string(), AvroValue::Int(-3)),
+            ("status".to_string(), AvroValue::Enum(1, "PAID".to_string())),
+            ("map".to_string(), AvroValue::Map(map)),
+            ("flag".to_string(), AvroValue::Boolean(true)),
+        ]);
+
+        let document = to_json_value(avro_value);
+
+        assert_eq!(document["bytes"], Value::String("//4A".to_string()));
+        assert_eq!(document["fixed"], Value::String("YWI=".to_string()));
+        assert_eq!(document["nan"], Value::Null);
+        assert_eq!(document["float"], Number::from_f64(1.5).map(Value::Number).unwrap());
+        assert_eq!(document["int"], Value::Number(Number::from(-3)));
+        assert_eq!(document["status"], Value::String("PAID".to_string()));
+        assert!(document["map"].as_object().unwrap()["key"].is_null());
+        assert_eq!(document["flag"], Value::Bool(true));
+    }
+}
@@ -0,0 +1,149 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use transform::avro_decoder::AvroDecoder;
+use transform::protobuf_decoder::ProtobufDecoder;
+use rusoto_kinesis::Record;
+use serde_json::Value;
+use std::fmt;
+
+/// Why a payload wasn't decoded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The payload itself is undecodable, it fails the same way every time.
+    Invalid(String),
+    /// The schemas can't be fetched right now, e.g. the registry doesn't answer.
+    Unavailable(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Invalid(ref message) => write!(formatter, "Invalid payload - {}", message),
+            DecodeError::Unavailable(ref message) => write!(formatter, "Schemas unavailable - {}", message),
+        }
+    }
+}
+
+/// Turns a binary payload into a JSON document.
+pub trait PayloadDecoder: Send + Sync {
+    fn decode(&self, data: &[u8]) -> Result<Value, DecodeError>;
+}
+
+/// A decoding step, e.g.
+/// {"decode": {"format": "avro", "registry_url": "http://localhost:8081"}}
+/// {"decode": {"format": "avro", "schema_file": "/etc/kcl/order.avsc"}}
+/// {"decode": {"format": "protobuf", "schema_file": "/etc/kcl/order.proto.json", "message": "Order"}}
+#[derive(Deserialize, Debug, Clone)]
+pub struct DecodeConfig {
+    pub format: String,
+    pub schema_file: Option<String>,
+    /// A schema-registry-compatible endpoint, the payloads then start with the registry header.
+    pub registry_url: Option<String>,
+    /// The top-level Protobuf message.
+    pub message: Option<String>,
+    /// drop (default) drops an undecodable record, keep passes it through unchanged. Unavailable
+    /// schemas fail the batch either way, it's read again later.
+    pub on_error: Option<String>,
+}
+
+pub struct DecodeTransform {
+    format: String,
+    decoder: Box<PayloadDecoder>,
+    is_drop_on_error: bool,
+}
+
+impl DecodeTransform {
+    pub fn new(config: DecodeConfig) -> Option<DecodeTransform> {
+        let decoder: Option<Box<PayloadDecoder>> = match config.format.as_str() {
+            "avro" => AvroDecoder::new(config.schema_file.clone(), config.registry_url.clone())
+                .map(|decoder| Box::new(decoder) as Box<PayloadDecoder>),
+            "protobuf" => {
+                if config.schema_file.is_none() || config.message.is_none() {
+                    eprintln!("The protobuf decoder needs a schema_file and a message.");
+                    None
+                } else {
+                    ProtobufDecoder::new(config.schema_file.as_ref().unwrap(), config.message.clone().unwrap())
+                        .map(|decoder| Box::new(decoder) as Box<PayloadDecoder>)
+                }
+            }
+            format => {
+                eprintln!("Unknown decode format {}, expected avro or protobuf.", format);
+                None
+            }
+        };
+
+        if decoder.is_none() {
+            return None;
+        }
+
+        return Some(DecodeTransform {
+            format: config.format,
+            decoder: decoder.unwrap(),
+            is_drop_on_error: config.on_error.unwrap_or("drop".to_string()) != "keep"
+        });
+    }
+}
+
+impl RecordTransform for DecodeTransform {
+    fn transform(&self, _context: &RecordContext, record: Record) -> Result<Vec<Record>, String> {
+        let document = match self.decoder.decode(&record.data) {
+            Ok(document) => document,
+            Err(DecodeError::Unavailable(message)) =>
+                return Err(format!("can't decode record {} as {} - {}", record.sequence_number, self.format, message)),
+            Err(error) => {
+                eprintln!("Can't decode record {} as {}. - {}", record.sequence_number, self.format, error);
+                if self.is_drop_on_error {
+                    return Ok(vec![]);
+                }
+
+                return Ok(vec![record]);
+            }
+        };
+
+        let mut decoded_record = record;
+        decoded_record.data = document.to_string().into_bytes();
+        return Ok(vec![decoded_record]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes "{...}" payloads as JSON, fails the others with the error the payload names.
+    struct TestDecoder;
+
+    impl PayloadDecoder for TestDecoder {
+        fn decode(&self, data: &[u8]) -> Result<Value, DecodeError> {
+            if data == b"unavailable" {
+                return Err(DecodeError::Unavailable("the registry is down".to_string()));
+            }
+
+            return serde_json::from_slice(data).map_err(|error| DecodeError::Invalid(error.to_string()));
+        }
+    }
+
+    fn transform(is_drop_on_error: bool, data: &str) -> Result<Vec<Record>, String> {
+        let decode_transform = DecodeTransform {
+            format: "test".to_string(),
+            decoder: Box::new(TestDecoder),
+            is_drop_on_error
+        };
+        let context = RecordContext { stream_name: "stream".to_string(), shard_id: "shard-1".to_string() };
+        let record = Record { data: data.as_bytes().to_vec(), sequence_number: "1".to_string(), ..Default::default() };
+
+        return decode_transform.transform(&context, record);
+    }
+
+    #[test]
+    fn drops_or_keeps_undecodable_records() {
+        assert_eq!(transform(true, "{\"id\": 1}").unwrap()[0].data, b"{\"id\":1}".to_vec());
+        assert!(transform(true, "not decodable").unwrap().is_empty());
+        assert_eq!(transform(false, "not decodable").unwrap()[0].data, b"not decodable".to_vec());
+    }
+
+    #[test]
+    fn fails_the_batch_when_the_schemas_are_unavailable() {
+        assert!(transform(true, "unavailable").is_err());
+        assert!(transform(false, "unavailable").is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use transform::json_transform::{get_path, insert_path};
+use rusoto_kinesis::Record;
+use regex::{Captures, Regex};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+
+const MAX_PATTERN_DEPTH: usize = 20;
+
+/// Grok patterns, simplified where the regex crate has no look-around.
+const BUILT_IN_PATTERNS: [(&str, &str); 40] = [
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    ("INT", r"[+-]?[0-9]+"),
+    ("BASE10NUM", r"[+-]?(?:[0-9]+(?:\.[0-9]+)?|\.[0-9]+)"),
+    ("NUMBER", r"%{BASE10NUM}"),
+    ("POSINT", r"[1-9][0-9]*"),
+    ("NONNEGINT", r"[0-9]+"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*'"#),
+    ("QS", r"%{QUOTEDSTRING}"),
+    ("UUID", r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}"),
+    ("IPV4", r"(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)"),
+    ("IPV6", r"[0-9A-Fa-f:]*:[0-9A-Fa-f:.]+"),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    ("HOSTNAME", r"\b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b"),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("PATH", r"(?:/[^\s?#]*)+"),
+    ("URIPARAM", r"\?[^\s#]*"),
+    ("URIPATHPARAM", r"%{PATH}(?:%{URIPARAM})?"),
+    ("MONTH", r"\b(?:[Jj]an(?:uary)?|[Ff]eb(?:ruary)?|[Mm]ar(?:ch)?|[Aa]pr(?:il)?|[Mm]ay|[Jj]un(?:e)?|[Jj]ul(?:y)?|[Aa]ug(?:ust)?|[Ss]ep(?:tember)?|[Oo]ct(?:ober)?|[Nn]ov(?:ember)?|[Dd]ec(?:ember)?)\b"),
+    ("MONTHNUM", r"(?:0?[1-9]|1[0-2])"),
+    ("MONTHDAY", r"(?:(?:0[1-9])|(?:[12][0-9])|(?:3[01])|[1-9])"),
+    ("YEAR", r"[0-9]{2,4}"),
+    ("HOUR", r"(?:2[0123]|[01]?[0-9])"),
+    ("MINUTE", r"(?:[0-5][0-9])"),
+    ("SECOND", r"(?:[0-5]?[0-9]|60)(?:[:.,][0-9]+)?"),
+    ("TIME", r"%{HOUR}:%{MINUTE}(?::%{SECOND})?"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    ("TIMESTAMP_ISO8601", r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?"),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} %{INT}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    ("LOGLEVEL", r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|severe|emerg(?:ency)?|alert)"),
+    ("COMMONAPACHELOG", r#"%{IPORHOST:clientip} %{USER:ident} %{USER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:verb} %{NOTSPACE:request}(?: HTTP/%{NUMBER:httpversion})?|%{DATA:rawrequest})" %{NUMBER:response:int} (?:%{NUMBER:bytes:int}|-)"#),
+    ("COMBINEDAPACHELOG", r#"%{COMMONAPACHELOG} %{QS:referrer} %{QS:agent}"#),
+    ("SYSLOGLINE", r"%{SYSLOGTIMESTAMP:timestamp} %{IPORHOST:logsource} %{DATA:program}(?:\[%{POSINT:pid:int}\])?: %{GREEDYDATA:message}"),
+];
+
+/// A grok / regex parsing step, e.g.
+/// {"grok": {"patterns": ["%{COMBINEDAPACHELOG}", "(?P<level>\\w+): (?P<message>.*)"],
+///           "custom_patterns": {"SERVICE": "[a-z-]+"}, "fallback_field": "message"}}
+/// Patterns are tried in order on every line of a text record (or on `source_field` of a JSON
+/// record), %{PATTERN:field:int|float} captures a field and named groups are captured as is.
+/// Lines matching no pattern are kept under the fallback field.
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrokConfig {
+    pub patterns: Vec<String>,
+    pub custom_patterns: Option<HashMap<String, String>>,
+    pub source_field: Option<String>,
+    pub fallback_field: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CaptureType {
+    Text,
+    Integer,
+    Float,
+}
+
+struct CompiledPattern {
+    regex: Regex,
+    /// The regex group names and the field and type each one is captured as.
+    captures: Vec<(String, String, CaptureType)>,
+}
+
+pub struct GrokTransform {
+    patterns: Vec<CompiledPattern>,
+    source_field: Option<String>,
+    fallback_field: String,
+}
+
+impl GrokTransform {
+    pub fn new(config: GrokConfig) -> Option<GrokTransform> {
+        let mut definitions: HashMap<String, String> = BUILT_IN_PATTERNS.iter()
+            .map(|&(name, pattern)| (name.to_string(), pattern.to_string()))
+            .collect();
+
+        if config.custom_patterns.is_some() {
+            definitions.extend(config.custom_patterns.unwrap());
+        }
+
+        let mut patterns = vec![];
+        for pattern in &config.patterns {
+            let compiled_pattern = GrokTransform::compile_pattern(pattern, &definitions);
+            if compiled_pattern.is_none() {
+                return None;
+            }
+
+            patterns.push(compiled_pattern.unwrap());
+        }
+
+        return Some(GrokTransform {
+            patterns,
+            source_field: config.source_field,
+            fallback_field: config.fallback_field.unwrap_or("message".to_string())
+        });
+    }
+
+    fn compile_pattern(pattern: &String, definitions: &HashMap<String, String>) -> Option<CompiledPattern> {
+        let mut captures = vec![];
+        let expanded = GrokTransform::expand_pattern(pattern, definitions, &mut captures, 0);
+        if expanded.is_none() {
+            eprintln!("Invalid grok pattern {} - unknown or recursive pattern.", pattern);
+            return None;
+        }
+
+        let regex = Regex::new(&format!("^{}$", expanded.unwrap()));
+        if regex.is_err() {
+            eprintln!("Invalid grok pattern {}. - {}", pattern, regex.unwrap_err());
+            return None;
+        }
+
+        let regex = regex.unwrap();
+        // Plain named groups of the regex are captured as text fields.
+        for group_name in regex.capture_names() {
+            if group_name.is_some() && !group_name.unwrap().starts_with("grok_") {
+                let group_name = group_name.unwrap().to_string();
+                captures.push((group_name.to_string(), group_name, CaptureType::Text));
+            }
+        }
+
+        return Some(CompiledPattern { regex, captures });
+    }
+
+    /// Replaces every %{NAME:field:type} with its definition, a named group when a field is given.
+    fn expand_pattern(pattern: &String, definitions: &HashMap<String, String>,
+                      captures: &mut Vec<(String, String, CaptureType)>, depth: usize) -> Option<String> {
+        if depth > MAX_PATTERN_DEPTH {
+            return None;
+        }
+
+        let reference = Regex::new(r"%\{(\w+)(?::([\w.@\[\]-]+))?(?::(int|float))?\}").unwrap();
+        let mut expanded = String::new();
+        let mut last_end = 0;
+
+        for reference_captures in reference.captures_iter(pattern) {
+            let whole = reference_captures.get(0).unwrap();
+            expanded.push_str(&pattern[last_end..whole.start()]);
+            last_end = whole.end();
+
+            let definition = definitions.get(&reference_captures[1]);
+            if definition.is_none() {
+                return None;
+            }
+
+            let inner = GrokTransform::expand_pattern(definition.unwrap(), definitions, captures, depth + 1);
+            if inner.is_none() {
+                return None;
+            }
+
+            match reference_captures.get(2) {
+                Some(field) => {
+                    let group_name = format!("grok_{}", captures.len());
+                    let capture_type = match reference_captures.get(3).map(|capture_type| capture_type.as_str()) {
+                        Some("int") => CaptureType::Integer,
+                        Some("float") => CaptureType::Float,
+                        _ => CaptureType::Text,
+                    };
+
+                    expanded.push_str(&format!("(?P<{}>{})", group_name, inner.unwrap()));
+                    captures.push((group_name, field.as_str().to_string(), capture_type));
+                }
+                None => expanded.push_str(&format!("(?:{})", inner.unwrap())),
+            }
+        }
+
+        expanded.push_str(&pattern[last_end..]);
+        return Some(expanded);
+    }
+
+    fn parse_line(&self, line: &str) -> Map<String, Value> {
+        let mut document = Map::new();
+        for pattern in &self.patterns {
+            let line_captures = pattern.regex.captures(line);
+            if line_captures.is_some() {
+                GrokTransform::insert_captures(&mut document, pattern, &line_captures.unwrap());
+                return document;
+            }
+        }
+
+        document.insert(self.fallback_field.to_string(), Value::String(line.to_string()));
+        return document;
+    }
+
+    /// The captured objects are merged into the existing ones, a captured `http.status` keeps the
+    /// record's other `http` fields.
+    fn merge_fields(document: &mut Map<String, Value>, fields: Map<String, Value>) {
+        for (field, value) in fields {
+            if let Value::Object(nested_fields) = value {
+                let existing = document.entry(field).or_insert(Value::Object(Map::new()));
+                if !existing.is_object() {
+                    *existing = Value::Object(Map::new());
+                }
+
+                GrokTransform::merge_fields(existing.as_object_mut().unwrap(), nested_fields);
+                continue;
+            }
+
+            document.insert(field, value);
+        }
+    }
+
+    fn insert_captures(document: &mut Map<String, Value>, pattern: &CompiledPattern, line_captures: &Captures) {
+        for &(ref group_name, ref field, capture_type) in &pattern.captures {
+            let captured = line_captures.name(group_name);
+            if captured.is_none() {
+                continue;
+            }
+
+            let text = captured.unwrap().as_str();
+            let value = match capture_type {
+                CaptureType::Integer => text.parse::<i64>().ok().map(|integer| Value::Number(Number::from(integer))),
+                CaptureType::Float => text.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number),
+                CaptureType::Text => None,
+            };
+
+            insert_path(document, field, value.unwrap_or(Value::String(text.to_string())));
+        }
+    }
+}
+
+impl RecordTransform for GrokTransform {
+    fn transform(&self, _context: &RecordContext, record: Record) -> Result<Vec<Record>, String> {
+        // A JSON record gets the fields parsed out of its source field.
+        if self.source_field.is_some() {
+            let parsed: Result<Value, _> = serde_json::from_slice(&record.data);
+            if let Ok(Value::Object(mut document)) = parsed {
+                let source = get_path(&document, self.source_field.as_ref().unwrap())
+                    .and_then(|source| source.as_str())
+                    .map(|source| source.to_string());
+
+                if source.is_some() {
+                    GrokTransform::merge_fields(&mut document, self.parse_line(&source.unwrap()));
+
+                    let mut transformed_record = record.clone();
+                    transformed_record.data = Value::Object(document).to_string().into_bytes();
+                    return Ok(vec![transformed_record]);
+                }
+
+                return Ok(vec![record]);
+            }
+        }
+
+        // Any other JSON object is passed through untouched, a scalar line like `42` is still text.
+        if let Ok(Value::Object(_)) = serde_json::from_slice::<Value>(&record.data) {
+            return Ok(vec![record]);
+        }
+
+        let text = String::from_utf8_lossy(&record.data).to_string();
+
+        // Every line of a text record becomes a document.
+        return Ok(text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut transformed_record = record.clone();
+                transformed_record.data = Value::Object(self.parse_line(line)).to_string().into_bytes();
+                transformed_record
+            })
+            .collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_grok_transform(patterns: Vec<&str>, source_field: Option<&str>) -> GrokTransform {
+        return GrokTransform::new(GrokConfig {
+            patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            custom_patterns: None,
+            source_field: source_field.map(|source_field| source_field.to_string()),
+            fallback_field: None
+        }).unwrap();
+    }
+
+    fn transform(grok_transform: &GrokTransform, data: &str) -> Vec<Value> {
+        let context = RecordContext { stream_name: "stream".to_string(), shard_id: "shard-1".to_string() };
+        let record = Record { data: data.as_bytes().to_vec(), sequence_number: "1".to_string(), ..Default::default() };
+
+        return grok_transform.transform(&context, record).unwrap().iter()
+            .map(|record| serde_json::from_slice(&record.data).unwrap())
+            .collect();
+    }
+
+    #[test]
+    fn parses_json_scalar_lines_as_text() {
+        let grok_transform = get_grok_transform(vec!["%{INT:count:int}"], None);
+
+        let documents = transform(&grok_transform, "42");
+        assert_eq!(documents[0]["count"], Value::Number(Number::from(42)));
+
+        let documents = transform(&grok_transform, "null");
+        assert_eq!(documents[0]["message"], Value::String("null".to_string()));
+
+        let documents = transform(&grok_transform, r#"{"count": 1}"#);
+        assert_eq!(documents[0]["count"], Value::Number(Number::from(1)));
+        assert!(documents[0].get("message").is_none());
+    }
+
+    #[test]
+    fn merges_the_source_field_captures_into_nested_objects() {
+        let grok_transform = get_grok_transform(vec!["%{WORD:http.verb} %{INT:http.status:int}"], Some("log"));
+
+        let documents = transform(&grok_transform, r#"{"log": "GET 200", "http": {"host": "example.com"}}"#);
+
+        assert_eq!(documents[0]["http"]["host"], Value::String("example.com".to_string()));
+        assert_eq!(documents[0]["http"]["verb"], Value::String("GET".to_string()));
+        assert_eq!(documents[0]["http"]["status"], Value::Number(Number::from(200)));
+        assert_eq!(documents[0]["log"], Value::String("GET 200".to_string()));
+    }
+}
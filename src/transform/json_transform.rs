@@ -0,0 +1,281 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use rusoto_kinesis::Record;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// The built-in JSON operations. Field names are dotted paths, e.g. user.email.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonOperation {
+    /// Any of stream_name, shard_id, sequence_number, partition_key and arrival_timestamp.
+    AddMetadata(Vec<String>),
+    AddFields(Map<String, Value>),
+    RemoveFields(Vec<String>),
+    /// Old path to new path.
+    RenameFields(BTreeMap<String, String>),
+    /// Parses the field with a chrono format, or epoch_seconds / epoch_millis,
+    /// and writes it as RFC 3339 to the target field (the same field by default).
+    ParseTimestamp { field: String, format: String, target: Option<String> },
+}
+
+/// Applies JSON operations in order. Records that aren't JSON objects pass through as is.
+pub struct JsonTransform {
+    operations: Vec<JsonOperation>,
+}
+
+impl JsonTransform {
+    pub fn new(operations: Vec<JsonOperation>) -> JsonTransform {
+        JsonTransform { operations }
+    }
+
+    fn apply_operation(&self, context: &RecordContext, record: &Record,
+                       document: &mut Map<String, Value>, operation: &JsonOperation) {
+        match *operation {
+            JsonOperation::AddMetadata(ref fields) => {
+                for field in fields {
+                    let value = match field.as_str() {
+                        "stream_name" => Value::String(context.stream_name.to_string()),
+                        "shard_id" => Value::String(context.shard_id.to_string()),
+                        "sequence_number" => Value::String(record.sequence_number.to_string()),
+                        "partition_key" => Value::String(record.partition_key.to_string()),
+                        "arrival_timestamp" => record.approximate_arrival_timestamp
+                            .and_then(|timestamp| get_epoch_millis_time(timestamp * 1000.0))
+                            .map(|arrival_time| Value::String(arrival_time.to_rfc3339()))
+                            .unwrap_or(Value::Null),
+                        _ => {
+                            eprintln!("Unknown metadata field {}.", field);
+                            continue;
+                        }
+                    };
+
+                    insert_path(document, field, value);
+                }
+            }
+            JsonOperation::AddFields(ref fields) => {
+                for (field, value) in fields {
+                    insert_path(document, field, value.clone());
+                }
+            }
+            JsonOperation::RemoveFields(ref fields) => {
+                for field in fields {
+                    remove_path(document, field);
+                }
+            }
+            JsonOperation::RenameFields(ref fields) => {
+                for (field, new_field) in fields {
+                    let value = remove_path(document, field);
+                    if value.is_some() {
+                        insert_path(document, new_field, value.unwrap());
+                    }
+                }
+            }
+            JsonOperation::ParseTimestamp { ref field, ref format, ref target } => {
+                let value = get_path(document, field).cloned();
+                let timestamp = value.and_then(|value| parse_timestamp(&value, format));
+                if timestamp.is_some() {
+                    let target_field = target.clone().unwrap_or(field.to_string());
+                    insert_path(document, &target_field, Value::String(timestamp.unwrap().to_rfc3339()));
+                }
+            }
+        }
+    }
+}
+
+impl RecordTransform for JsonTransform {
+    fn transform(&self, context: &RecordContext, mut record: Record) -> Result<Vec<Record>, String> {
+        let parsed: Result<Value, _> = serde_json::from_slice(&record.data);
+        let mut document = match parsed {
+            Ok(Value::Object(document)) => document,
+            _ => return Ok(vec![record]),
+        };
+
+        for operation in &self.operations {
+            self.apply_operation(context, &record, &mut document, operation);
+        }
+
+        record.data = Value::Object(document).to_string().into_bytes();
+        return Ok(vec![record]);
+    }
+}
+
+pub fn get_path<'a>(document: &'a Map<String, Value>, path: &String) -> Option<&'a Value> {
+    let mut fields = path.split('.');
+    let mut value = document.get(fields.next().unwrap());
+    for field in fields {
+        value = value.and_then(|value| value.get(field));
+    }
+
+    return value;
+}
+
+/// Missing parents are created, a parent that isn't an object is replaced.
+pub fn insert_path(document: &mut Map<String, Value>, path: &String, value: Value) {
+    let fields: Vec<&str> = path.split('.').collect();
+    let mut current = document;
+    for field in &fields[..fields.len() - 1] {
+        let parent = current;
+        let child = parent.entry(field.to_string()).or_insert(Value::Object(Map::new()));
+        if !child.is_object() {
+            *child = Value::Object(Map::new());
+        }
+
+        current = child.as_object_mut().unwrap();
+    }
+
+    current.insert(fields[fields.len() - 1].to_string(), value);
+}
+
+pub fn remove_path(document: &mut Map<String, Value>, path: &String) -> Option<Value> {
+    let fields: Vec<&str> = path.split('.').collect();
+    let mut current = document;
+    for field in &fields[..fields.len() - 1] {
+        let parent = current;
+        let child = parent.get_mut(*field).and_then(|child| child.as_object_mut());
+        if child.is_none() {
+            return None;
+        }
+
+        current = child.unwrap();
+    }
+
+    return current.remove(fields[fields.len() - 1]);
+}
+
+/// None past the dates chrono can represent, e.g. a value in microseconds read as milliseconds.
+fn get_epoch_millis_time(milliseconds: f64) -> Option<DateTime<Utc>> {
+    if !milliseconds.is_finite() || milliseconds.abs() >= i64::max_value() as f64 {
+        return None;
+    }
+
+    return Utc.timestamp_millis_opt(milliseconds as i64).single();
+}
+
+fn parse_timestamp(value: &Value, format: &String) -> Option<DateTime<Utc>> {
+    if format == "epoch_seconds" || format == "epoch_millis" {
+        let number = match *value {
+            Value::Number(ref number) => number.as_f64(),
+            Value::String(ref text) => text.parse::<f64>().ok(),
+            _ => None,
+        };
+
+        if number.is_none() {
+            return None;
+        }
+
+        let milliseconds = if format == "epoch_seconds" { number.unwrap() * 1000.0 } else { number.unwrap() };
+        return get_epoch_millis_time(milliseconds);
+    }
+
+    let text = value.as_str();
+    if text.is_none() {
+        return None;
+    }
+
+    let date_time = DateTime::parse_from_str(text.unwrap(), format);
+    if date_time.is_ok() {
+        return Some(date_time.unwrap().with_timezone(&Utc));
+    }
+
+    // Formats without an offset are taken as UTC.
+    let naive_date_time = NaiveDateTime::parse_from_str(text.unwrap(), format);
+    if naive_date_time.is_ok() {
+        return Some(DateTime::<Utc>::from_utc(naive_date_time.unwrap(), Utc));
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(operations: &str, data: &str) -> Value {
+        let json_transform = JsonTransform::new(serde_json::from_str(operations).unwrap());
+        let context = RecordContext { stream_name: "stream".to_string(), shard_id: "shard-1".to_string() };
+        let record = Record {
+            data: data.as_bytes().to_vec(),
+            sequence_number: "42".to_string(),
+            partition_key: "key-1".to_string(),
+            approximate_arrival_timestamp: Some(1540000000.5),
+            ..Default::default()
+        };
+
+        let records = json_transform.transform(&context, record).unwrap();
+        return serde_json::from_slice(&records[0].data).unwrap();
+    }
+
+    fn parse(value: &str, format: &str) -> Option<String> {
+        return parse_timestamp(&serde_json::from_str(value).unwrap(), &format.to_string())
+            .map(|timestamp| timestamp.to_rfc3339());
+    }
+
+    #[test]
+    fn adds_the_record_metadata() {
+        let document = transform(
+            r#"[{"add_metadata": ["stream_name", "shard_id", "sequence_number", "partition_key", "arrival_timestamp", "unknown"]}]"#,
+            "{}"
+        );
+
+        assert_eq!(document["stream_name"], Value::from("stream"));
+        assert_eq!(document["shard_id"], Value::from("shard-1"));
+        assert_eq!(document["sequence_number"], Value::from("42"));
+        assert_eq!(document["partition_key"], Value::from("key-1"));
+        assert_eq!(document["arrival_timestamp"], Value::from("2018-10-20T01:46:40.500+00:00"));
+        assert!(document.get("unknown").is_none());
+    }
+
+    #[test]
+    fn removes_nested_and_missing_fields() {
+        let document = transform(
+            r#"[{"remove_fields": ["user.email", "missing.field", "token"]}]"#,
+            r#"{"user": {"email": "a@b.c", "name": "Ada"}, "token": "secret"}"#
+        );
+
+        assert_eq!(document.to_string(), r#"{"user":{"name":"Ada"}}"#);
+    }
+
+    #[test]
+    fn renames_fields_across_paths() {
+        let document = transform(
+            r#"[{"rename_fields": {"msg": "message", "user.id": "user_id", "missing": "other"}}]"#,
+            r#"{"msg": "hello", "user": {"id": 7}}"#
+        );
+
+        assert_eq!(document.to_string(), r#"{"message":"hello","user":{},"user_id":7}"#);
+    }
+
+    #[test]
+    fn parses_epoch_timestamps() {
+        assert_eq!(parse("1540000000", "epoch_seconds"), Some("2018-10-20T01:46:40+00:00".to_string()));
+        assert_eq!(parse("\"1540000000.25\"", "epoch_seconds"), Some("2018-10-20T01:46:40.250+00:00".to_string()));
+        assert_eq!(parse("1540000000250", "epoch_millis"), Some("2018-10-20T01:46:40.250+00:00".to_string()));
+        assert_eq!(parse("\"soon\"", "epoch_millis"), None);
+        assert_eq!(parse("true", "epoch_seconds"), None);
+    }
+
+    #[test]
+    fn leaves_out_of_range_epoch_timestamps_unparsed() {
+        assert_eq!(parse("1e20", "epoch_millis"), None);
+        assert_eq!(parse("-1e20", "epoch_seconds"), None);
+        assert_eq!(parse("1e300", "epoch_seconds"), None);
+
+        let document = transform(r#"[{"parse_timestamp": {"field": "time", "format": "epoch_millis"}}]"#, r#"{"time": 1e20}"#);
+        assert_eq!(document["time"], serde_json::from_str::<Value>("1e20").unwrap());
+    }
+
+    #[test]
+    fn parses_formatted_timestamps_into_the_target_field() {
+        assert_eq!(parse("\"20/Oct/2018:03:46:40 +0200\"", "%d/%b/%Y:%H:%M:%S %z"), Some("2018-10-20T01:46:40+00:00".to_string()));
+        assert_eq!(parse("\"2018-10-20 01:46:40\"", "%Y-%m-%d %H:%M:%S"), Some("2018-10-20T01:46:40+00:00".to_string()));
+        assert_eq!(parse("\"yesterday\"", "%Y-%m-%d %H:%M:%S"), None);
+        assert_eq!(parse("1540000000", "%Y-%m-%d %H:%M:%S"), None);
+
+        let document = transform(
+            r#"[{"parse_timestamp": {"field": "time", "format": "epoch_seconds", "target": "@timestamp"}}]"#,
+            r#"{"time": 1540000000}"#
+        );
+        assert_eq!(document["@timestamp"], Value::from("2018-10-20T01:46:40+00:00"));
+        assert_eq!(document["time"], Value::from(1540000000));
+    }
+}
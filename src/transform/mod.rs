@@ -0,0 +1,9 @@
+pub mod record_transform;
+pub mod json_transform;
+pub mod transform_pipeline;
+pub mod script_transform;
+pub mod grok_transform;
+pub mod schema_transform;
+pub mod decode_transform;
+pub mod avro_decoder;
+pub mod protobuf_decoder;
@@ -0,0 +1,356 @@
+use transform::decode_transform::{DecodeError, PayloadDecoder};
+use b64::{ToBase64, STANDARD};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+const MAX_MESSAGE_DEPTH: usize = 64;
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_64_BIT: u64 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_TYPE_32_BIT: u64 = 5;
+
+/// The messages and enums the payloads are decoded with, keyed by field number, e.g.
+/// {"messages": {
+///    "Order": {"1": {"name": "id", "type": "string"},
+///              "2": {"name": "status", "type": "Status"},
+///              "3": {"name": "items", "type": "Item", "repeated": true}},
+///    "Item": {"1": {"name": "sku", "type": "string"}, "2": {"name": "price", "type": "double"}}},
+///  "enums": {"Status": {"0": "UNKNOWN", "1": "PAID"}}}
+#[derive(Deserialize, Debug)]
+struct ProtobufSchema {
+    messages: HashMap<String, HashMap<String, ProtobufField>>,
+    enums: Option<HashMap<String, HashMap<String, String>>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProtobufField {
+    name: String,
+    #[serde(rename = "type")]
+    field_type: String,
+    repeated: Option<bool>,
+}
+
+/// Decodes the Protobuf wire format with the field map of a schema file, unknown fields are skipped.
+pub struct ProtobufDecoder {
+    schema: ProtobufSchema,
+    message: String,
+}
+
+impl ProtobufDecoder {
+    pub fn new(schema_file: &String, message: String) -> Option<ProtobufDecoder> {
+        let mut content = String::new();
+        let file = File::open(schema_file);
+        if file.is_err() || file.unwrap().read_to_string(&mut content).is_err() {
+            eprintln!("Can't read the protobuf schema {}.", schema_file);
+            return None;
+        }
+
+        let schema: Result<ProtobufSchema, _> = serde_json::from_str(&content);
+        if schema.is_err() {
+            eprintln!("Invalid protobuf schema {}. - {}", schema_file, schema.unwrap_err());
+            return None;
+        }
+
+        let schema = schema.unwrap();
+        if !schema.messages.contains_key(&message) {
+            eprintln!("The protobuf schema {} has no message {}.", schema_file, message);
+            return None;
+        }
+
+        return Some(ProtobufDecoder { schema, message });
+    }
+
+    fn decode_message(&self, message: &String, data: &[u8], depth: usize) -> Result<Map<String, Value>, String> {
+        if depth > MAX_MESSAGE_DEPTH {
+            return Err("the message nests too deep".to_string());
+        }
+
+        let fields = self.schema.messages.get(message).ok_or(format!("unknown message {}", message))?;
+        let mut document = Map::new();
+        let mut position = 0;
+
+        while position < data.len() {
+            let key = read_varint(data, &mut position)?;
+            let field_number = key >> 3;
+            let wire_type = key & 7;
+
+            let field_data = match wire_type {
+                WIRE_TYPE_VARINT => {
+                    let start = position;
+                    read_varint(data, &mut position)?;
+                    &data[start..position]
+                }
+                WIRE_TYPE_64_BIT => read_bytes(data, &mut position, 8)?,
+                WIRE_TYPE_LENGTH_DELIMITED => {
+                    let length = read_varint(data, &mut position)? as usize;
+                    read_bytes(data, &mut position, length)?
+                }
+                WIRE_TYPE_32_BIT => read_bytes(data, &mut position, 4)?,
+                _ => return Err(format!("unsupported wire type {} for field {}", wire_type, field_number)),
+            };
+
+            let field = fields.get(&field_number.to_string());
+            if field.is_none() {
+                continue;
+            }
+
+            let field = field.unwrap();
+            let is_repeated = field.repeated.unwrap_or(false);
+            let values = if wire_type == WIRE_TYPE_LENGTH_DELIMITED && is_repeated && self.is_packable(&field.field_type) {
+                self.decode_packed(field, field_data)?
+            } else {
+                vec![self.decode_value(field, wire_type, field_data, depth)?]
+            };
+
+            if is_repeated {
+                let repeated_values = document.entry(field.name.to_string()).or_insert(Value::Array(vec![]));
+                if let Value::Array(ref mut repeated_values) = *repeated_values {
+                    repeated_values.extend(values);
+                }
+            } else if !values.is_empty() {
+                // The last occurrence of a singular field wins.
+                document.insert(field.name.to_string(), values.into_iter().last().unwrap());
+            }
+        }
+
+        return Ok(document);
+    }
+
+    fn decode_packed(&self, field: &ProtobufField, data: &[u8]) -> Result<Vec<Value>, String> {
+        let mut values = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            let (wire_type, value_data) = match field.field_type.as_str() {
+                "double" | "fixed64" | "sfixed64" => (WIRE_TYPE_64_BIT, read_bytes(data, &mut position, 8)?),
+                "float" | "fixed32" | "sfixed32" => (WIRE_TYPE_32_BIT, read_bytes(data, &mut position, 4)?),
+                _ => {
+                    let start = position;
+                    read_varint(data, &mut position)?;
+                    (WIRE_TYPE_VARINT, &data[start..position])
+                }
+            };
+
+            values.push(self.decode_value(field, wire_type, value_data, 0)?);
+        }
+
+        return Ok(values);
+    }
+
+    fn decode_value(&self, field: &ProtobufField, wire_type: u64, data: &[u8], depth: usize) -> Result<Value, String> {
+        let field_type = field.field_type.as_str();
+        let value = match (wire_type, field_type) {
+            (WIRE_TYPE_VARINT, _) => {
+                let varint = read_varint(data, &mut 0)?;
+                match field_type {
+                    "int32" => Value::Number(Number::from(varint as i32)),
+                    "int64" => Value::Number(Number::from(varint as i64)),
+                    "uint32" => Value::Number(Number::from(varint as u32)),
+                    "uint64" => Value::Number(Number::from(varint)),
+                    "sint32" | "sint64" => Value::Number(Number::from((varint >> 1) as i64 ^ -((varint & 1) as i64))),
+                    "bool" => Value::Bool(varint != 0),
+                    _ => self.get_enum_value(field_type, varint as i32)
+                        .ok_or(format!("field {} of type {} isn't a varint", field.name, field_type))?,
+                }
+            }
+            (WIRE_TYPE_64_BIT, _) => {
+                let bits = data.iter().rev().fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+                match field_type {
+                    "fixed64" => Value::Number(Number::from(bits)),
+                    "sfixed64" => Value::Number(Number::from(bits as i64)),
+                    "double" => Number::from_f64(f64::from_bits(bits)).map(Value::Number).unwrap_or(Value::Null),
+                    _ => return Err(format!("field {} of type {} isn't 64-bit", field.name, field_type)),
+                }
+            }
+            (WIRE_TYPE_32_BIT, _) => {
+                let bits = data.iter().rev().fold(0u32, |bits, byte| bits << 8 | *byte as u32);
+                match field_type {
+                    "fixed32" => Value::Number(Number::from(bits)),
+                    "sfixed32" => Value::Number(Number::from(bits as i32)),
+                    "float" => Number::from_f64(f32::from_bits(bits) as f64).map(Value::Number).unwrap_or(Value::Null),
+                    _ => return Err(format!("field {} of type {} isn't 32-bit", field.name, field_type)),
+                }
+            }
+            (_, "string") => Value::String(String::from_utf8_lossy(data).to_string()),
+            (_, "bytes") => Value::String(data.to_base64(STANDARD)),
+            (_, message) if self.schema.messages.contains_key(message) =>
+                Value::Object(self.decode_message(&message.to_string(), data, depth + 1)?),
+            _ => return Err(format!("field {} of type {} isn't length-delimited", field.name, field_type)),
+        };
+
+        return Ok(value);
+    }
+
+    /// Repeated scalars and enums come packed in one length-delimited field.
+    fn is_packable(&self, field_type: &str) -> bool {
+        return field_type != "string" && field_type != "bytes" && !self.schema.messages.contains_key(field_type);
+    }
+
+    /// The enum symbol, or the number for a value the schema doesn't know.
+    fn get_enum_value(&self, enum_name: &str, number: i32) -> Option<Value> {
+        let symbols = self.schema.enums.as_ref().and_then(|enums| enums.get(enum_name));
+        if symbols.is_none() {
+            return None;
+        }
+
+        return Some(symbols.unwrap().get(&number.to_string())
+            .map(|symbol| Value::String(symbol.to_string()))
+            .unwrap_or(Value::Number(Number::from(number))));
+    }
+}
+
+impl PayloadDecoder for ProtobufDecoder {
+    fn decode(&self, data: &[u8]) -> Result<Value, DecodeError> {
+        return self.decode_message(&self.message, data, 0).map(Value::Object).map_err(DecodeError::Invalid);
+    }
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Result<u64, String> {
+    let mut varint = 0u64;
+    for shift in 0..10 {
+        if *position >= data.len() {
+            return Err("truncated varint".to_string());
+        }
+
+        let byte = data[*position];
+        *position = *position + 1;
+        varint = varint | ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Ok(varint);
+        }
+    }
+
+    return Err("varint longer than 10 bytes".to_string());
+}
+
+fn read_bytes<'a>(data: &'a [u8], position: &mut usize, length: usize) -> Result<&'a [u8], String> {
+    if data.len() - *position < length {
+        return Err("truncated field".to_string());
+    }
+
+    let bytes = &data[*position..*position + length];
+    *position = *position + length;
+    return Ok(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"{
+        "messages": {
+            "Order": {"1": {"name": "id", "type": "int64"},
+                      "2": {"name": "delta", "type": "sint32"},
+                      "3": {"name": "quantities", "type": "int32", "repeated": true},
+                      "4": {"name": "offset", "type": "int32"},
+                      "5": {"name": "status", "type": "Status"},
+                      "6": {"name": "item", "type": "Item"},
+                      "7": {"name": "note", "type": "string"}},
+            "Item": {"1": {"name": "sku", "type": "string"}, "2": {"name": "price", "type": "double"}},
+            "Node": {"1": {"name": "child", "type": "Node"}}},
+        "enums": {"Status": {"0": "UNKNOWN", "1": "PAID"}}}"#;
+
+    fn get_decoder(message: &str) -> ProtobufDecoder {
+        return ProtobufDecoder { schema: serde_json::from_str(SCHEMA).unwrap(), message: message.to_string() };
+    }
+
+    fn encode_varint(mut varint: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while varint >= 0x80 {
+            bytes.push((varint & 0x7f) as u8 | 0x80);
+            varint = varint >> 7;
+        }
+
+        bytes.push(varint as u8);
+        return bytes;
+    }
+
+    fn decode(message: &str, data: &[u8]) -> Result<Value, DecodeError> {
+        return get_decoder(message).decode(data);
+    }
+
+    #[test]
+    fn decodes_varints_and_zigzag_varints() {
+        let mut data = vec![0x08];
+        data.extend(encode_varint(300));
+        data.extend(vec![0x10, 0x03]);
+
+        let document = decode("Order", &data).unwrap();
+
+        assert_eq!(document["id"], Value::Number(Number::from(300)));
+        assert_eq!(document["delta"], Value::Number(Number::from(-2)));
+    }
+
+    #[test]
+    fn decodes_negative_int32_as_ten_byte_varints() {
+        let mut data = vec![0x20];
+        data.extend(encode_varint(-1i64 as u64));
+        assert_eq!(data.len(), 11);
+
+        let document = decode("Order", &data).unwrap();
+
+        assert_eq!(document["offset"], Value::Number(Number::from(-1)));
+    }
+
+    #[test]
+    fn decodes_packed_and_unpacked_repeated_fields() {
+        let mut packed_values = encode_varint(1);
+        packed_values.extend(encode_varint(150));
+        let mut data = vec![0x1a, packed_values.len() as u8];
+        data.extend(packed_values);
+        data.extend(vec![0x18, 0x07]);
+
+        let document = decode("Order", &data).unwrap();
+
+        let quantities: Vec<Value> = vec![1, 150, 7].into_iter().map(|quantity| Value::Number(Number::from(quantity))).collect();
+        assert_eq!(document["quantities"], Value::Array(quantities));
+    }
+
+    #[test]
+    fn decodes_enum_symbols_and_unknown_numbers() {
+        assert_eq!(decode("Order", &[0x28, 0x01]).unwrap()["status"], Value::String("PAID".to_string()));
+        assert_eq!(decode("Order", &[0x28, 0x07]).unwrap()["status"], Value::Number(Number::from(7)));
+    }
+
+    #[test]
+    fn decodes_nested_messages_and_skips_unknown_fields() {
+        let mut item = vec![0x0a, 0x01, b'a', 0x11];
+        let price_bits = 2.5f64.to_bits();
+        item.extend((0..8).map(|byte| (price_bits >> (byte * 8)) as u8));
+        let mut data = vec![0x32, item.len() as u8];
+        data.extend(item);
+        // Field 9 isn't in the schema.
+        data.extend(vec![0x4a, 0x02, b'x', b'y', 0x3a, 0x02, b'h', b'i']);
+
+        let document = decode("Order", &data).unwrap();
+
+        assert_eq!(document["item"]["sku"], Value::String("a".to_string()));
+        assert_eq!(document["item"]["price"], Number::from_f64(2.5).map(Value::Number).unwrap());
+        assert_eq!(document["note"], Value::String("hi".to_string()));
+        assert_eq!(document.as_object().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_messages_nesting_too_deep() {
+        let mut data = vec![];
+        for _ in 0..MAX_MESSAGE_DEPTH + 2 {
+            let mut node = vec![0x0a];
+            node.extend(encode_varint(data.len() as u64));
+            node.extend(data);
+            data = node;
+        }
+
+        let result = decode("Node", &data);
+
+        assert_eq!(result, Err(DecodeError::Invalid("the message nests too deep".to_string())));
+    }
+
+    #[test]
+    fn rejects_truncated_buffers() {
+        assert_eq!(decode("Order", &[0x08, 0x80]), Err(DecodeError::Invalid("truncated varint".to_string())));
+        assert_eq!(decode("Order", &[0x3a, 0x05, b'h', b'i']), Err(DecodeError::Invalid("truncated field".to_string())));
+        assert_eq!(decode("Order", &[0x31, 0x00, 0x00]), Err(DecodeError::Invalid("truncated field".to_string())));
+    }
+}
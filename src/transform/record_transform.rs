@@ -0,0 +1,16 @@
+use rusoto_kinesis::Record;
+
+/// Where the records being transformed come from.
+pub struct RecordContext {
+    pub stream_name: String,
+    pub shard_id: String,
+}
+
+/// A step of the transformation pipeline, implement it for custom Rust transforms.
+/// The transformed records keep the sequence number of their source record.
+pub trait RecordTransform: Send + Sync {
+    /// No records drops the record, several records split it. An error fails the whole batch, which
+    /// is read again later: it's for the failures that aren't the record's fault, e.g. an unreachable
+    /// schema registry, a record that can't be transformed is dropped or kept instead.
+    fn transform(&self, context: &RecordContext, record: Record) -> Result<Vec<Record>, String>;
+}
@@ -0,0 +1,493 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use transform::json_transform::get_path;
+use rusoto_kinesis::Record;
+use regex::Regex;
+use b64::{ToBase64, STANDARD};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const MAX_SCHEMA_DEPTH: usize = 64;
+const VIOLATION_REPORT_INTERVAL_SECONDS: u64 = 60;
+
+/// A JSON Schema validation step, e.g.
+/// {"validate": {"name": "orders", "schema_file": "/etc/kcl/orders.schema.json",
+///               "field": "type", "equals": "order", "on_invalid": "dead_letter"}}
+/// Only the records whose `field` equals `equals` are validated, all of them if no field is given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SchemaConfig {
+    pub name: String,
+    pub schema_file: String,
+    pub field: Option<String>,
+    pub equals: Option<Value>,
+    /// dead_letter (default) wraps an invalid record in a dead_letter document for the routing
+    /// rules to send on, drop drops it and keep passes it through.
+    pub on_invalid: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InvalidRecordAction {
+    DeadLetter,
+    Drop,
+    Keep,
+}
+
+/// Validates against the commonly used JSON Schema keywords: type, enum, const, properties,
+/// required, additionalProperties, items, the length, size and range bounds, pattern,
+/// allOf / anyOf / oneOf / not and local $ref.
+pub struct JsonSchemaValidator {
+    schema: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl JsonSchemaValidator {
+    pub fn new(schema: Value) -> Option<JsonSchemaValidator> {
+        let mut patterns = HashMap::new();
+        if !JsonSchemaValidator::compile_patterns(&schema, &mut patterns) {
+            return None;
+        }
+
+        return Some(JsonSchemaValidator { schema, patterns });
+    }
+
+    fn compile_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> bool {
+        match *schema {
+            Value::Object(ref keywords) => {
+                for (keyword, value) in keywords {
+                    if keyword == "pattern" && value.is_string() {
+                        let pattern = value.as_str().unwrap().to_string();
+                        let regex = Regex::new(&pattern);
+                        if regex.is_err() {
+                            eprintln!("Invalid schema pattern {}. - {}", pattern, regex.unwrap_err());
+                            return false;
+                        }
+
+                        patterns.insert(pattern, regex.unwrap());
+                    } else if !JsonSchemaValidator::compile_patterns(value, patterns) {
+                        return false;
+                    }
+                }
+
+                true
+            }
+            Value::Array(ref values) => values.iter().all(|value| JsonSchemaValidator::compile_patterns(value, patterns)),
+            _ => true,
+        }
+    }
+
+    /// The violations, empty for a valid document.
+    pub fn validate(&self, document: &Value) -> Vec<String> {
+        let mut errors = vec![];
+        self.validate_value(&self.schema, document, "$", &mut errors, 0);
+        return errors;
+    }
+
+    fn validate_value(&self, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>, depth: usize) {
+        if depth > MAX_SCHEMA_DEPTH {
+            errors.push(format!("{}: the schema nests too deep", path));
+            return;
+        }
+
+        let keywords = match *schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                errors.push(format!("{}: no value is allowed", path));
+                return;
+            }
+            Value::Object(ref keywords) => keywords,
+            _ => return,
+        };
+
+        if let Some(reference) = keywords.get("$ref").and_then(|reference| reference.as_str()) {
+            let referenced_schema = if reference.starts_with('#') { self.schema.pointer(&reference[1..]) } else { None };
+            match referenced_schema {
+                Some(referenced_schema) => self.validate_value(referenced_schema, value, path, errors, depth + 1),
+                None => errors.push(format!("{}: can't resolve {}", path, reference)),
+            }
+
+            return;
+        }
+
+        if let Some(types) = keywords.get("type") {
+            let is_type_matched = match *types {
+                Value::String(ref type_name) => is_of_type(value, type_name),
+                Value::Array(ref type_names) =>
+                    type_names.iter().any(|type_name| type_name.as_str().map_or(false, |type_name| is_of_type(value, type_name))),
+                _ => true,
+            };
+
+            if !is_type_matched {
+                errors.push(format!("{}: expected type {}", path, types));
+                return;
+            }
+        }
+
+        if let Some(allowed_values) = keywords.get("enum").and_then(|allowed_values| allowed_values.as_array()) {
+            if !allowed_values.contains(value) {
+                errors.push(format!("{}: not one of {}", path, Value::Array(allowed_values.clone())));
+            }
+        }
+
+        if let Some(constant) = keywords.get("const") {
+            if constant != value {
+                errors.push(format!("{}: expected {}", path, constant));
+            }
+        }
+
+        match *value {
+            Value::Object(ref fields) => self.validate_object(keywords, fields, path, errors, depth),
+            Value::Array(ref elements) => self.validate_array(keywords, elements, path, errors, depth),
+            Value::String(ref text) => self.validate_string(keywords, text, path, errors),
+            Value::Number(ref number) => validate_number(keywords, number.as_f64().unwrap_or(0.0), path, errors),
+            _ => {}
+        }
+
+        if let Some(sub_schemas) = keywords.get("allOf").and_then(|sub_schemas| sub_schemas.as_array()) {
+            for sub_schema in sub_schemas {
+                self.validate_value(sub_schema, value, path, errors, depth + 1);
+            }
+        }
+
+        if let Some(sub_schemas) = keywords.get("anyOf").and_then(|sub_schemas| sub_schemas.as_array()) {
+            if self.count_matching(sub_schemas, value, path, depth) == 0 {
+                errors.push(format!("{}: matches none of anyOf", path));
+            }
+        }
+
+        if let Some(sub_schemas) = keywords.get("oneOf").and_then(|sub_schemas| sub_schemas.as_array()) {
+            let matching_count = self.count_matching(sub_schemas, value, path, depth);
+            if matching_count != 1 {
+                errors.push(format!("{}: matches {} of oneOf instead of exactly one", path, matching_count));
+            }
+        }
+
+        if let Some(sub_schema) = keywords.get("not") {
+            let mut sub_errors = vec![];
+            self.validate_value(sub_schema, value, path, &mut sub_errors, depth + 1);
+            if sub_errors.is_empty() {
+                errors.push(format!("{}: matches the not schema", path));
+            }
+        }
+    }
+
+    fn count_matching(&self, sub_schemas: &Vec<Value>, value: &Value, path: &str, depth: usize) -> usize {
+        return sub_schemas.iter().filter(|sub_schema| {
+            let mut sub_errors = vec![];
+            self.validate_value(sub_schema, value, path, &mut sub_errors, depth + 1);
+            sub_errors.is_empty()
+        }).count();
+    }
+
+    fn validate_object(&self, keywords: &Map<String, Value>, fields: &Map<String, Value>,
+                       path: &str, errors: &mut Vec<String>, depth: usize) {
+        if let Some(required_fields) = keywords.get("required").and_then(|required_fields| required_fields.as_array()) {
+            for required_field in required_fields.iter().filter_map(|required_field| required_field.as_str()) {
+                if !fields.contains_key(required_field) {
+                    errors.push(format!("{}: missing required field {}", path, required_field));
+                }
+            }
+        }
+
+        let properties = keywords.get("properties").and_then(|properties| properties.as_object());
+        for (name, field_value) in fields {
+            let field_path = format!("{}.{}", path, name);
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => self.validate_value(property_schema, field_value, &field_path, errors, depth + 1),
+                None => {
+                    if let Some(additional_properties) = keywords.get("additionalProperties") {
+                        self.validate_value(additional_properties, field_value, &field_path, errors, depth + 1);
+                    }
+                }
+            }
+        }
+
+        validate_size(keywords, "minProperties", "maxProperties", fields.len(), "fields", path, errors);
+    }
+
+    fn validate_array(&self, keywords: &Map<String, Value>, elements: &Vec<Value>,
+                      path: &str, errors: &mut Vec<String>, depth: usize) {
+        match keywords.get("items") {
+            Some(&Value::Array(ref item_schemas)) => {
+                for (index, (item_schema, element)) in item_schemas.iter().zip(elements.iter()).enumerate() {
+                    self.validate_value(item_schema, element, &format!("{}[{}]", path, index), errors, depth + 1);
+                }
+            }
+            Some(item_schema) => {
+                for (index, element) in elements.iter().enumerate() {
+                    self.validate_value(item_schema, element, &format!("{}[{}]", path, index), errors, depth + 1);
+                }
+            }
+            None => {}
+        }
+
+        validate_size(keywords, "minItems", "maxItems", elements.len(), "items", path, errors);
+
+        if keywords.get("uniqueItems").and_then(|unique_items| unique_items.as_bool()).unwrap_or(false) {
+            let has_duplicates = elements.iter().enumerate()
+                .any(|(index, element)| elements[index + 1..].contains(element));
+            if has_duplicates {
+                errors.push(format!("{}: items aren't unique", path));
+            }
+        }
+    }
+
+    fn validate_string(&self, keywords: &Map<String, Value>, text: &String, path: &str, errors: &mut Vec<String>) {
+        validate_size(keywords, "minLength", "maxLength", text.chars().count(), "characters", path, errors);
+
+        if let Some(pattern) = keywords.get("pattern").and_then(|pattern| pattern.as_str()) {
+            let is_matched = self.patterns.get(pattern).map_or(true, |regex| regex.is_match(text));
+            if !is_matched {
+                errors.push(format!("{}: doesn't match {}", path, pattern));
+            }
+        }
+    }
+}
+
+fn is_of_type(value: &Value, type_name: &str) -> bool {
+    match type_name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => false,
+    }
+}
+
+fn validate_number(keywords: &Map<String, Value>, number: f64, path: &str, errors: &mut Vec<String>) {
+    let bound = |keyword: &str| keywords.get(keyword).and_then(|bound| bound.as_f64());
+
+    if bound("minimum").map_or(false, |minimum| number < minimum) {
+        errors.push(format!("{}: {} is below the minimum {}", path, number, bound("minimum").unwrap()));
+    }
+
+    if bound("maximum").map_or(false, |maximum| number > maximum) {
+        errors.push(format!("{}: {} is above the maximum {}", path, number, bound("maximum").unwrap()));
+    }
+
+    if bound("exclusiveMinimum").map_or(false, |minimum| number <= minimum) {
+        errors.push(format!("{}: {} isn't above {}", path, number, bound("exclusiveMinimum").unwrap()));
+    }
+
+    if bound("exclusiveMaximum").map_or(false, |maximum| number >= maximum) {
+        errors.push(format!("{}: {} isn't below {}", path, number, bound("exclusiveMaximum").unwrap()));
+    }
+
+    if bound("multipleOf").map_or(false, |multiple| multiple > 0.0 && (number / multiple).fract() != 0.0) {
+        errors.push(format!("{}: {} isn't a multiple of {}", path, number, bound("multipleOf").unwrap()));
+    }
+}
+
+fn validate_size(keywords: &Map<String, Value>, minimum_keyword: &str, maximum_keyword: &str, size: usize,
+                 unit: &str, path: &str, errors: &mut Vec<String>) {
+    if let Some(minimum) = keywords.get(minimum_keyword).and_then(|minimum| minimum.as_u64()) {
+        if (size as u64) < minimum {
+            errors.push(format!("{}: {} {}, at least {} expected", path, size, unit, minimum));
+        }
+    }
+
+    if let Some(maximum) = keywords.get(maximum_keyword).and_then(|maximum| maximum.as_u64()) {
+        if (size as u64) > maximum {
+            errors.push(format!("{}: {} {}, at most {} expected", path, size, unit, maximum));
+        }
+    }
+}
+
+/// The validation counts of a schema, shared with the admin API.
+pub struct SchemaCounts {
+    name: String,
+    validated_count: AtomicUsize,
+    violation_count: AtomicUsize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaCountsReport {
+    pub name: String,
+    pub validated_records: usize,
+    pub violations: usize,
+}
+
+impl SchemaCounts {
+    pub fn get_report(&self) -> SchemaCountsReport {
+        SchemaCountsReport {
+            name: self.name.to_string(),
+            validated_records: self.validated_count.load(Ordering::Relaxed),
+            violations: self.violation_count.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Validates the records against a schema, counting the violations per schema.
+pub struct SchemaTransform {
+    name: String,
+    validator: JsonSchemaValidator,
+    field: Option<String>,
+    equals: Option<Value>,
+    invalid_record_action: InvalidRecordAction,
+    counts: Arc<SchemaCounts>,
+    last_reported: Mutex<Instant>,
+}
+
+impl SchemaTransform {
+    pub fn new(config: SchemaConfig) -> Option<SchemaTransform> {
+        let mut content = String::new();
+        let file = File::open(&config.schema_file);
+        if file.is_err() || file.unwrap().read_to_string(&mut content).is_err() {
+            eprintln!("Can't read the schema file {}.", config.schema_file);
+            return None;
+        }
+
+        let schema: Result<Value, _> = serde_json::from_str(&content);
+        if schema.is_err() {
+            eprintln!("Invalid schema file {}. - {}", config.schema_file, schema.unwrap_err());
+            return None;
+        }
+
+        let validator = JsonSchemaValidator::new(schema.unwrap());
+        if validator.is_none() {
+            return None;
+        }
+
+        let invalid_record_action = match config.on_invalid.as_ref().map(|action| action.as_str()) {
+            None | Some("dead_letter") => InvalidRecordAction::DeadLetter,
+            Some("drop") => InvalidRecordAction::Drop,
+            Some("keep") => InvalidRecordAction::Keep,
+            Some(action) => {
+                eprintln!("Invalid on_invalid {} for schema {}, expected dead_letter, drop or keep.", action, config.name);
+                return None;
+            }
+        };
+
+        let counts = SchemaCounts {
+            name: config.name.to_string(),
+            validated_count: AtomicUsize::new(0),
+            violation_count: AtomicUsize::new(0)
+        };
+
+        return Some(SchemaTransform {
+            name: config.name,
+            validator: validator.unwrap(),
+            field: config.field,
+            equals: config.equals,
+            invalid_record_action,
+            counts: Arc::new(counts),
+            last_reported: Mutex::new(Instant::now())
+        });
+    }
+
+    pub fn get_counts(&self) -> Arc<SchemaCounts> {
+        return self.counts.clone();
+    }
+
+    fn is_applicable(&self, document: &Value) -> bool {
+        if self.field.is_none() {
+            return true;
+        }
+
+        let field_value = document.as_object().and_then(|fields| get_path(fields, self.field.as_ref().unwrap()));
+        return match (field_value, self.equals.as_ref()) {
+            (Some(field_value), Some(equals)) => field_value == equals,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+    }
+
+    fn report_counts(&self) {
+        let mut last_reported = self.last_reported.lock().unwrap();
+        if last_reported.elapsed() >= Duration::from_secs(VIOLATION_REPORT_INTERVAL_SECONDS) {
+            let report = self.counts.get_report();
+            eprintln!("Schema {}: {} violations in {} validated records.",
+                     self.name, report.violations, report.validated_records);
+            *last_reported = Instant::now();
+        }
+    }
+
+    fn get_dead_letter_record(&self, record: Record, errors: Vec<String>) -> Record {
+        let mut dead_letter = Map::new();
+        dead_letter.insert("schema".to_string(), Value::String(self.name.to_string()));
+        dead_letter.insert("errors".to_string(), Value::Array(errors.into_iter().map(Value::String).collect()));
+        dead_letter.insert("payload_base64".to_string(), Value::String(record.data.to_base64(STANDARD)));
+
+        let mut document = Map::new();
+        document.insert("dead_letter".to_string(), Value::Object(dead_letter));
+
+        let mut dead_letter_record = record;
+        dead_letter_record.data = Value::Object(document).to_string().into_bytes();
+        return dead_letter_record;
+    }
+}
+
+impl RecordTransform for SchemaTransform {
+    fn transform(&self, _context: &RecordContext, record: Record) -> Result<Vec<Record>, String> {
+        let parsed: Result<Value, _> = serde_json::from_slice(&record.data);
+        let errors = match parsed {
+            Ok(ref document) if !self.is_applicable(document) => return Ok(vec![record]),
+            Ok(ref document) => self.validator.validate(document),
+            Err(_) if self.field.is_some() => return Ok(vec![record]),
+            Err(_) => vec!["$: the payload isn't JSON".to_string()],
+        };
+
+        self.counts.validated_count.fetch_add(1, Ordering::Relaxed);
+        self.report_counts();
+        if errors.is_empty() {
+            return Ok(vec![record]);
+        }
+
+        self.counts.violation_count.fetch_add(1, Ordering::Relaxed);
+        eprintln!("Record {} violates schema {}. - {}", record.sequence_number, self.name, errors.join(", "));
+
+        return Ok(match self.invalid_record_action {
+            InvalidRecordAction::DeadLetter => vec![self.get_dead_letter_record(record, errors)],
+            InvalidRecordAction::Drop => vec![],
+            InvalidRecordAction::Keep => vec![record],
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_validator(schema: &str) -> JsonSchemaValidator {
+        return JsonSchemaValidator::new(serde_json::from_str(schema).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn accepts_only_integral_numbers_as_integers() {
+        let validator = get_validator(r#"{"type": "integer"}"#);
+
+        assert!(validator.validate(&serde_json::from_str("1").unwrap()).is_empty());
+        assert!(validator.validate(&serde_json::from_str("-1").unwrap()).is_empty());
+        assert!(!validator.validate(&serde_json::from_str("1.0").unwrap()).is_empty());
+        assert!(!validator.validate(&serde_json::from_str("1.5").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_dead_letter_payload_intact() {
+        let schema_file = ::std::env::temp_dir().join("kcl_schema_transform_test.schema.json");
+        ::std::fs::write(&schema_file, r#"{"type": "object"}"#).unwrap();
+        let schema_transform = SchemaTransform::new(SchemaConfig {
+            name: "orders".to_string(),
+            schema_file: schema_file.to_string_lossy().to_string(),
+            field: None,
+            equals: None,
+            on_invalid: None
+        }).unwrap();
+        let context = RecordContext { stream_name: "stream".to_string(), shard_id: "shard-1".to_string() };
+        let record = Record { data: vec![0xff, 0xfe, 0x00], sequence_number: "1".to_string(), ..Default::default() };
+
+        let records = schema_transform.transform(&context, record).unwrap();
+
+        let document: Value = serde_json::from_slice(&records[0].data).unwrap();
+        assert_eq!(document["dead_letter"]["payload_base64"], Value::String("//4A".to_string()));
+        let report = schema_transform.get_counts().get_report();
+        assert_eq!((report.validated_records, report.violations), (1, 1));
+        let _ = ::std::fs::remove_file(&schema_file);
+    }
+}
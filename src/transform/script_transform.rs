@@ -0,0 +1,424 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use rusoto_kinesis::Record;
+use rlua::{Context, Function, HookTriggers, Lua, StdLib, Table, Value as LuaValue, Variadic};
+use serde_json::{Map, Number, Value};
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Globals removed from the sandbox, they'd let a script load code or touch the filesystem.
+const UNSAFE_GLOBALS: [&str; 5] = ["dofile", "loadfile", "load", "loadstring", "require"];
+const TIMEOUT_CHECK_INSTRUCTIONS: u32 = 1000;
+const DEFAULT_MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Lua has no null and no empty array, json.null and the tables marked with json.array stand for them,
+/// so `null` and `[]` come back out of the script unchanged.
+const JSON_PRELUDE: &str = r#"
+json = {null = setmetatable({}, {__json = "null", __tostring = function() return "null" end})}
+local array_metatable = {__json = "array"}
+function json.array(values) return setmetatable(values or {}, array_metatable) end
+"#;
+
+/// A Lua script transform, e.g.
+/// {"script": {"file": "/etc/kcl/transform.lua", "timeout_millis": 50, "max_memory_bytes": 16777216, "on_error": "drop"}}
+/// The script defines transform(record, metadata), record being the JSON payload as a table and
+/// metadata holding stream_name, shard_id, partition_key and sequence_number. It returns the
+/// modified record, a list of records to split it, or nil to drop it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScriptConfig {
+    pub file: Option<String>,
+    pub source: Option<String>,
+    pub timeout_millis: Option<u64>,
+    pub max_memory_bytes: Option<usize>,
+    /// drop (default) drops the record when the script fails or times out, keep passes it through
+    /// unchanged. A scrubbing script shouldn't let the unscrubbed record through by default.
+    pub on_error: Option<String>,
+}
+
+/// A sandboxed Lua state with the script compiled, and the deadline of the record it runs.
+struct ScriptState {
+    lua: Lua,
+    deadline: Arc<Mutex<Instant>>,
+}
+
+/// Runs in a sandbox with only the base, table, string and math libraries, and is stopped
+/// once it runs over its timeout or its memory limit. The states are reused, one per thread
+/// running the transform, but every record runs the script in a new environment, so nothing
+/// a script keeps in its globals leaks from one record or shard to the next.
+pub struct ScriptTransform {
+    source: String,
+    timeout: Duration,
+    max_memory_bytes: usize,
+    is_drop_on_error: bool,
+    states: Mutex<Vec<ScriptState>>,
+}
+
+impl ScriptTransform {
+    pub fn new(config: ScriptConfig) -> Option<ScriptTransform> {
+        let mut source = config.source.clone().unwrap_or(String::new());
+        if config.file.is_some() {
+            let file = File::open(config.file.clone().unwrap());
+            if file.is_err() || file.unwrap().read_to_string(&mut source).is_err() {
+                eprintln!("Can't read the script {}.", config.file.unwrap());
+                return None;
+            }
+        }
+
+        let script_transform = ScriptTransform {
+            source,
+            timeout: Duration::from_millis(config.timeout_millis.unwrap_or(50)),
+            max_memory_bytes: config.max_memory_bytes.unwrap_or(DEFAULT_MAX_MEMORY_BYTES),
+            is_drop_on_error: config.on_error.unwrap_or("drop".to_string()) != "keep",
+            states: Mutex::new(vec![])
+        };
+
+        let loaded = script_transform.load_state().and_then(|state| {
+            *state.deadline.lock().unwrap() = Instant::now() + script_transform.timeout;
+            state.lua.context(|context| ScriptTransform::load_transform(context).map(|_| ()))?;
+            Ok(state)
+        });
+        if loaded.is_err() {
+            eprintln!("Invalid transform script, it should define transform(record, metadata). - {}",
+                     loaded.unwrap_err());
+            return None;
+        }
+
+        script_transform.states.lock().unwrap().push(loaded.unwrap());
+        return Some(script_transform);
+    }
+
+    /// A sandboxed state with the script compiled, stopped past the deadline of its current record.
+    fn load_state(&self) -> ::rlua::Result<ScriptState> {
+        let lua = Lua::new_with(StdLib::BASE | StdLib::TABLE | StdLib::STRING | StdLib::MATH);
+        lua.set_memory_limit(Some(self.max_memory_bytes));
+        let deadline = Arc::new(Mutex::new(Instant::now() + self.timeout));
+        let hook_deadline = deadline.clone();
+        lua.set_hook(
+            HookTriggers { every_nth_instruction: Some(TIMEOUT_CHECK_INSTRUCTIONS), ..Default::default() },
+            move |_context, _debug| {
+                if Instant::now() > *hook_deadline.lock().unwrap() {
+                    return Err(::rlua::Error::RuntimeError("the script timed out".to_string()));
+                }
+
+                Ok(())
+            }
+        );
+
+        lua.context(|context| {
+            for unsafe_global in UNSAFE_GLOBALS.iter() {
+                context.globals().set(*unsafe_global, LuaValue::Nil)?;
+            }
+
+            let string_library: Table = context.globals().get("string")?;
+            string_library.set("dump", LuaValue::Nil)?;
+
+            // stdout carries the records of kcl tail and the stdout sink.
+            let print = context.create_function(|context, values: Variadic<LuaValue>| {
+                let tostring: Function = context.globals().get("tostring")?;
+                let mut texts: Vec<String> = vec![];
+                for value in values {
+                    texts.push(tostring.call(value)?);
+                }
+
+                eprintln!("{}", texts.join("\t"));
+                Ok(())
+            })?;
+            context.globals().set("print", print)?;
+
+            context.load(JSON_PRELUDE).set_name("json")?.exec()?;
+            // The conversions don't depend on the script leaving the json global alone.
+            let json: Table = context.globals().get("json")?;
+            context.set_named_registry_value("json", json)?;
+
+            // Compiled once, the script runs with the environment it's given. The wrapper keeps
+            // the script's line numbers.
+            let script: Function = context.load(&format!("return function(_ENV) {}\nend", self.source))
+                .set_name("transform")?
+                .eval()?;
+            context.set_named_registry_value("script", script)?;
+            Ok(())
+        })?;
+
+        return Ok(ScriptState { lua, deadline });
+    }
+
+    /// Runs the script's top-level code in a new environment, falling back to the sandbox's
+    /// globals, and returns the transform function it defines.
+    fn load_transform<'lua>(context: Context<'lua>) -> ::rlua::Result<Function<'lua>> {
+        let environment = context.create_table()?;
+        let environment_metatable = context.create_table()?;
+        environment_metatable.set("__index", context.globals())?;
+        environment.set_metatable(Some(environment_metatable));
+
+        let script: Function = context.named_registry_value("script")?;
+        script.call::<_, ()>(environment.clone())?;
+        return environment.raw_get("transform");
+    }
+
+    fn run_script(&self, context: &RecordContext, record: &Record, document: Value)
+                  -> ::rlua::Result<Vec<Value>> {
+        let state = self.states.lock().unwrap().pop();
+        let state = match state {
+            Some(state) => state,
+            None => self.load_state()?,
+        };
+
+        // The timeout covers the script's top-level code, it runs again for every record.
+        *state.deadline.lock().unwrap() = Instant::now() + self.timeout;
+        let documents = state.lua.context(|lua_context| {
+            let metadata = lua_context.create_table()?;
+            metadata.set("stream_name", context.stream_name.to_string())?;
+            metadata.set("shard_id", context.shard_id.to_string())?;
+            metadata.set("partition_key", record.partition_key.to_string())?;
+            metadata.set("sequence_number", record.sequence_number.to_string())?;
+
+            let json: Table = lua_context.named_registry_value("json")?;
+            let transform = ScriptTransform::load_transform(lua_context)?;
+            let lua_record = to_lua_value(lua_context, &json, &document)?;
+            let returned: LuaValue = transform.call((lua_record, metadata))?;
+
+            match returned {
+                LuaValue::Nil => Ok(vec![]),
+                LuaValue::Table(table) => {
+                    // A list of records splits the record, a table with named fields replaces it.
+                    if get_json_type(&table)? == JsonType::Array {
+                        let mut documents = vec![];
+                        for element in table.sequence_values::<LuaValue>() {
+                            documents.push(from_lua_value(element?)?);
+                        }
+
+                        Ok(documents)
+                    } else {
+                        Ok(vec![from_lua_value(LuaValue::Table(table))?])
+                    }
+                }
+                _ => Err(::rlua::Error::RuntimeError("transform should return a table or nil".to_string())),
+            }
+        });
+
+        // A state the script failed in, e.g. out of memory, isn't trusted with the next record.
+        if documents.is_ok() {
+            self.states.lock().unwrap().push(state);
+        }
+
+        return documents;
+    }
+}
+
+impl RecordTransform for ScriptTransform {
+    fn transform(&self, context: &RecordContext, record: Record) -> Result<Vec<Record>, String> {
+        let parsed: Result<Value, _> = serde_json::from_slice(&record.data);
+        let document = match parsed {
+            Ok(document @ Value::Object(_)) => document,
+            _ => return Ok(vec![record]),
+        };
+
+        let documents = self.run_script(context, &record, document);
+        if documents.is_err() {
+            eprintln!("Transform script failed for record {}. - {}", record.sequence_number, documents.unwrap_err());
+            if self.is_drop_on_error {
+                return Ok(vec![]);
+            }
+
+            return Ok(vec![record]);
+        }
+
+        return Ok(documents.unwrap().into_iter().map(|document| {
+            let mut transformed_record = record.clone();
+            transformed_record.data = document.to_string().into_bytes();
+            transformed_record
+        }).collect());
+    }
+}
+
+fn to_lua_value<'lua>(context: Context<'lua>, json: &Table<'lua>, value: &Value) -> ::rlua::Result<LuaValue<'lua>> {
+    match *value {
+        Value::Null => json.get("null"),
+        Value::Bool(boolean) => Ok(LuaValue::Boolean(boolean)),
+        Value::Number(ref number) if number.is_i64() => Ok(LuaValue::Integer(number.as_i64().unwrap())),
+        Value::Number(ref number) => Ok(LuaValue::Number(number.as_f64().unwrap_or(0.0))),
+        Value::String(ref text) => Ok(LuaValue::String(context.create_string(text)?)),
+        Value::Array(ref values) => {
+            let table = context.create_table()?;
+            for (index, element) in values.iter().enumerate() {
+                table.set(index + 1, to_lua_value(context, json, element)?)?;
+            }
+
+            let array: Function = json.get("array")?;
+            array.call(table)
+        }
+        Value::Object(ref fields) => {
+            let table = context.create_table()?;
+            for (name, element) in fields {
+                table.set(name.to_string(), to_lua_value(context, json, element)?)?;
+            }
+
+            Ok(LuaValue::Table(table))
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum JsonType {
+    Null,
+    Array,
+    Object,
+}
+
+/// The marked tables, then the non-empty sequences, are arrays, any other table is an object.
+fn get_json_type(table: &Table) -> ::rlua::Result<JsonType> {
+    let marker: Option<String> = match table.get_metatable() {
+        Some(metatable) => metatable.raw_get("__json")?,
+        None => None,
+    };
+
+    match marker.as_ref().map(|marker| marker.as_str()) {
+        Some("null") => Ok(JsonType::Null),
+        Some("array") => Ok(JsonType::Array),
+        _ if table.raw_len() > 0 => Ok(JsonType::Array),
+        _ => Ok(JsonType::Object),
+    }
+}
+
+fn from_lua_value(value: LuaValue) -> ::rlua::Result<Value> {
+    match value {
+        LuaValue::Nil => Ok(Value::Null),
+        LuaValue::Boolean(boolean) => Ok(Value::Bool(boolean)),
+        LuaValue::Integer(integer) => Ok(Value::Number(Number::from(integer))),
+        LuaValue::Number(number) => Ok(Number::from_f64(number).map(Value::Number).unwrap_or(Value::Null)),
+        LuaValue::String(text) => Ok(Value::String(text.to_str()?.to_string())),
+        LuaValue::Table(table) => {
+            match get_json_type(&table)? {
+                JsonType::Null => Ok(Value::Null),
+                JsonType::Array => {
+                    let mut values = vec![];
+                    for element in table.sequence_values::<LuaValue>() {
+                        values.push(from_lua_value(element?)?);
+                    }
+
+                    Ok(Value::Array(values))
+                }
+                JsonType::Object => {
+                    let mut fields = Map::new();
+                    for pair in table.pairs::<String, LuaValue>() {
+                        let (name, element) = pair?;
+                        fields.insert(name, from_lua_value(element)?);
+                    }
+
+                    Ok(Value::Object(fields))
+                }
+            }
+        }
+        _ => Err(::rlua::Error::RuntimeError("records can't hold functions or userdata".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_script_transform(source: &str) -> ScriptTransform {
+        return ScriptTransform::new(ScriptConfig {
+            file: None,
+            source: Some(source.to_string()),
+            timeout_millis: None,
+            max_memory_bytes: None,
+            on_error: Some("drop".to_string())
+        }).unwrap();
+    }
+
+    fn transform(script_transform: &ScriptTransform, data: &str) -> Vec<String> {
+        let context = RecordContext { stream_name: "stream".to_string(), shard_id: "shard-1".to_string() };
+        let record = Record {
+            data: data.as_bytes().to_vec(),
+            partition_key: "key".to_string(),
+            sequence_number: "1".to_string(),
+            ..Default::default()
+        };
+
+        return script_transform.transform(&context, record).unwrap().into_iter()
+            .map(|record| String::from_utf8(record.data).unwrap())
+            .collect();
+    }
+
+    #[test]
+    fn keeps_empty_arrays_and_nulls() {
+        let script_transform = get_script_transform("function transform(record) record.added = json.array() return record end");
+
+        let documents = transform(&script_transform, r#"{"items":[],"missing":null,"values":[1,null,2]}"#);
+
+        let document: Value = serde_json::from_str(&documents[0]).unwrap();
+        let expected: Value =
+            serde_json::from_str(r#"{"items":[],"missing":null,"values":[1,null,2],"added":[]}"#).unwrap();
+        assert_eq!(document, expected);
+    }
+
+    #[test]
+    fn does_not_keep_globals_between_records() {
+        let script_transform = get_script_transform(
+            "function transform(record) count = (count or 0) + 1 record.count = count return record end");
+
+        transform(&script_transform, r#"{"id":1}"#);
+        let documents = transform(&script_transform, r#"{"id":2}"#);
+
+        assert_eq!(documents, vec![r#"{"count":1,"id":2}"#.to_string()]);
+    }
+
+    #[test]
+    fn reuses_the_lua_state_across_records() {
+        let script_transform = get_script_transform(
+            "local prefix = 'id-' function transform(record) record.id = prefix .. record.id return record end");
+
+        let first_documents = transform(&script_transform, r#"{"id":1}"#);
+        let second_documents = transform(&script_transform, r#"{"id":2}"#);
+
+        assert_eq!(first_documents, vec![r#"{"id":"id-1"}"#.to_string()]);
+        assert_eq!(second_documents, vec![r#"{"id":"id-2"}"#.to_string()]);
+        assert_eq!(script_transform.states.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stops_a_script_over_its_timeout() {
+        let script_transform = ScriptTransform::new(ScriptConfig {
+            file: None,
+            source: Some("function transform(record) if record.id == 1 then while true do end end return record end".to_string()),
+            timeout_millis: Some(50),
+            max_memory_bytes: None,
+            on_error: None
+        }).unwrap();
+
+        let started_at = Instant::now();
+        assert!(transform(&script_transform, r#"{"id":1}"#).is_empty());
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert_eq!(transform(&script_transform, r#"{"id":2}"#), vec![r#"{"id":2}"#.to_string()]);
+    }
+
+    #[test]
+    fn drops_the_record_by_default_and_keeps_it_on_request() {
+        let config = ScriptConfig {
+            file: None,
+            source: Some("function transform(record) print('scrubbing', record.id) error('boom') end".to_string()),
+            timeout_millis: None,
+            max_memory_bytes: None,
+            on_error: None
+        };
+        let keeping_config = ScriptConfig { on_error: Some("keep".to_string()), ..config.clone() };
+
+        assert!(transform(&ScriptTransform::new(config).unwrap(), r#"{"id":1}"#).is_empty());
+        assert_eq!(transform(&ScriptTransform::new(keeping_config).unwrap(), r#"{"id":1}"#), vec![r#"{"id":1}"#.to_string()]);
+    }
+
+    #[test]
+    fn stops_a_script_over_its_memory_limit() {
+        let script_transform = ScriptTransform::new(ScriptConfig {
+            file: None,
+            source: Some("function transform(record) record.big = string.rep('x', 1048576) return record end".to_string()),
+            timeout_millis: None,
+            max_memory_bytes: Some(512 * 1024),
+            on_error: Some("drop".to_string())
+        }).unwrap();
+
+        assert!(transform(&script_transform, r#"{"id":1}"#).is_empty());
+    }
+}
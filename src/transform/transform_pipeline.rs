@@ -0,0 +1,149 @@
+use transform::record_transform::{RecordContext, RecordTransform};
+use transform::json_transform::{JsonOperation, JsonTransform};
+use transform::script_transform::{ScriptConfig, ScriptTransform};
+use transform::grok_transform::{GrokConfig, GrokTransform};
+use transform::schema_transform::{SchemaConfig, SchemaCounts, SchemaTransform};
+use transform::decode_transform::{DecodeConfig, DecodeTransform};
+use rusoto_kinesis::Record;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+/// The transforms file, e.g.
+/// {"transforms": [
+///   {"decode": {"format": "avro", "registry_url": "http://localhost:8081"}},
+///   {"add_metadata": ["stream_name", "shard_id", "arrival_timestamp"]},
+///   {"remove_fields": ["user.email", "user.phone"]},
+///   {"rename_fields": {"msg": "message"}},
+///   {"parse_timestamp": {"field": "time", "format": "%d/%b/%Y:%H:%M:%S %z", "target": "@timestamp"}},
+///   {"script": {"file": "/etc/kcl/transform.lua", "timeout_millis": 50}},
+///   {"grok": {"patterns": ["%{COMBINEDAPACHELOG}"], "fallback_field": "message"}},
+///   {"validate": {"name": "orders", "schema_file": "/etc/kcl/orders.schema.json"}}
+/// ]}
+#[derive(Deserialize, Debug)]
+struct TransformsFile {
+    transforms: Vec<TransformDefinition>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TransformDefinition {
+    Json(JsonOperation),
+    Script { script: ScriptConfig },
+    Grok { grok: GrokConfig },
+    Validate { validate: SchemaConfig },
+    Decode { decode: DecodeConfig },
+}
+
+/// The transforms applied, in order, between reading a shard and pushing to the sinks.
+pub struct TransformPipeline {
+    transforms: Vec<Box<RecordTransform>>,
+    schema_counts: Vec<Arc<SchemaCounts>>,
+}
+
+impl TransformPipeline {
+    pub fn new() -> TransformPipeline {
+        TransformPipeline { transforms: vec![], schema_counts: vec![] }
+    }
+
+    pub fn from_file(transforms_file_path: &String) -> Option<TransformPipeline> {
+        let mut content = String::new();
+        let file = File::open(transforms_file_path);
+        if file.is_err() || file.unwrap().read_to_string(&mut content).is_err() {
+            eprintln!("Can't read the transforms file {}.", transforms_file_path);
+            return None;
+        }
+
+        let transforms_file: Result<TransformsFile, _> = serde_json::from_str(&content);
+        if transforms_file.is_err() {
+            eprintln!("Invalid transforms file {}. - {}", transforms_file_path, transforms_file.unwrap_err());
+            return None;
+        }
+
+        let mut transform_pipeline = TransformPipeline::new();
+        let mut json_operations = vec![];
+        for transform_definition in transforms_file.unwrap().transforms {
+            match transform_definition {
+                TransformDefinition::Json(json_operation) => json_operations.push(json_operation),
+                TransformDefinition::Script { script } => {
+                    transform_pipeline.add_json_operations(&mut json_operations);
+                    let script_transform = ScriptTransform::new(script);
+                    if script_transform.is_none() {
+                        return None;
+                    }
+
+                    transform_pipeline.add_transform(Box::new(script_transform.unwrap()));
+                }
+                TransformDefinition::Grok { grok } => {
+                    transform_pipeline.add_json_operations(&mut json_operations);
+                    let grok_transform = GrokTransform::new(grok);
+                    if grok_transform.is_none() {
+                        return None;
+                    }
+
+                    transform_pipeline.add_transform(Box::new(grok_transform.unwrap()));
+                }
+                TransformDefinition::Validate { validate } => {
+                    transform_pipeline.add_json_operations(&mut json_operations);
+                    let schema_transform = SchemaTransform::new(validate);
+                    if schema_transform.is_none() {
+                        return None;
+                    }
+
+                    let schema_transform = schema_transform.unwrap();
+                    transform_pipeline.schema_counts.push(schema_transform.get_counts());
+                    transform_pipeline.add_transform(Box::new(schema_transform));
+                }
+                TransformDefinition::Decode { decode } => {
+                    transform_pipeline.add_json_operations(&mut json_operations);
+                    let decode_transform = DecodeTransform::new(decode);
+                    if decode_transform.is_none() {
+                        return None;
+                    }
+
+                    transform_pipeline.add_transform(Box::new(decode_transform.unwrap()));
+                }
+            }
+        }
+
+        transform_pipeline.add_json_operations(&mut json_operations);
+
+        return Some(transform_pipeline);
+    }
+
+    /// Consecutive JSON operations share one parse of the record.
+    fn add_json_operations(&mut self, json_operations: &mut Vec<JsonOperation>) {
+        if !json_operations.is_empty() {
+            let operations = json_operations.drain(..).collect();
+            self.add_transform(Box::new(JsonTransform::new(operations)));
+        }
+    }
+
+    pub fn add_transform(&mut self, transform: Box<RecordTransform>) {
+        self.transforms.push(transform);
+    }
+
+    /// The validation counts of the validate steps, for the admin API.
+    pub fn get_schema_counts(&self) -> Vec<Arc<SchemaCounts>> {
+        return self.schema_counts.clone();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.transforms.is_empty();
+    }
+
+    /// Fails on the first transform error, none of the records may be delivered then.
+    pub fn apply(&self, context: &RecordContext, records: Vec<Record>) -> Result<Vec<Record>, String> {
+        let mut transformed_records = records;
+        for transform in &self.transforms {
+            let mut next_records = vec![];
+            for record in transformed_records {
+                next_records.append(&mut transform.transform(context, record)?);
+            }
+
+            transformed_records = next_records;
+        }
+
+        return Ok(transformed_records);
+    }
+}